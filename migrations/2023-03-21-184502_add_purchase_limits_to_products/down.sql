@@ -0,0 +1,3 @@
+ALTER TABLE products
+DROP COLUMN purchasable,
+DROP COLUMN max_order_qty;
\ No newline at end of file
@@ -0,0 +1,3 @@
+ALTER TABLE products
+ADD COLUMN purchasable BOOLEAN NOT NULL DEFAULT TRUE,
+ADD COLUMN max_order_qty INT NOT NULL DEFAULT 10;
\ No newline at end of file
@@ -0,0 +1,5 @@
+DROP TABLE discount_tiers;
+DROP TABLE discount_rules;
+
+ALTER TABLE products
+DROP COLUMN category;
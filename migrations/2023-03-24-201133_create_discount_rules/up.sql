@@ -0,0 +1,27 @@
+ALTER TABLE products
+ADD COLUMN category VARCHAR;
+
+-- kind is one of 'percentage', 'fixed', 'buy_x_get_y' or 'tiered'. A rule
+-- with neither product_id nor category set applies to the whole order.
+CREATE TABLE discount_rules (
+  id INTEGER GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  name VARCHAR NOT NULL,
+  kind VARCHAR NOT NULL,
+  value DECIMAL(10,4) NOT NULL DEFAULT 0.00,
+  product_id INTEGER REFERENCES products (id),
+  category VARCHAR,
+  buy_qty INTEGER,
+  get_qty INTEGER,
+  min_subtotal DECIMAL(10,2),
+  stackable BOOLEAN NOT NULL DEFAULT TRUE,
+  exclusive_group VARCHAR,
+  automatic BOOLEAN NOT NULL DEFAULT TRUE,
+  active BOOLEAN NOT NULL DEFAULT TRUE
+);
+
+CREATE TABLE discount_tiers (
+  id INTEGER GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  rule_id INTEGER NOT NULL REFERENCES discount_rules (id) ON DELETE CASCADE,
+  min_subtotal DECIMAL(10,2) NOT NULL,
+  value DECIMAL(10,4) NOT NULL
+);
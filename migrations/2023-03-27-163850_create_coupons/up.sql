@@ -0,0 +1,22 @@
+CREATE TABLE coupons (
+  id INTEGER GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  code VARCHAR NOT NULL UNIQUE,
+  discount_rule_id INTEGER NOT NULL REFERENCES discount_rules (id),
+  starts_at TIMESTAMPTZ,
+  ends_at TIMESTAMPTZ,
+  max_redemptions INTEGER,
+  max_redemptions_per_customer INTEGER,
+  min_subtotal DECIMAL(10,2),
+  redemptions INTEGER NOT NULL DEFAULT 0,
+  active BOOLEAN NOT NULL DEFAULT TRUE
+);
+
+CREATE TABLE coupon_redemptions (
+  id INTEGER GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  coupon_id INTEGER NOT NULL REFERENCES coupons (id),
+  customer_email VARCHAR NOT NULL,
+  order_id INTEGER NOT NULL,
+  redeemed_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX coupon_redemptions_customer_idx ON coupon_redemptions (coupon_id, customer_email);
@@ -0,0 +1,4 @@
+DROP TABLE tax_rates;
+
+ALTER TABLE products
+DROP COLUMN tax_category;
@@ -0,0 +1,18 @@
+ALTER TABLE products
+ADD COLUMN tax_category VARCHAR NOT NULL DEFAULT 'standard';
+
+-- The most specific matching row wins: a zip_prefix match beats a state
+-- match, which beats a country-wide rate. A category without a matching row
+-- is not taxed in that jurisdiction. Countries and states are stored
+-- upper-case, e.g. 'US' and 'NY'.
+CREATE TABLE tax_rates (
+  id INTEGER GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  country VARCHAR NOT NULL,
+  state VARCHAR,
+  zip_prefix VARCHAR,
+  tax_category VARCHAR NOT NULL DEFAULT 'standard',
+  rate DECIMAL(8,6) NOT NULL,
+  shipping_taxable BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE INDEX tax_rates_country_idx ON tax_rates (country);
@@ -0,0 +1,11 @@
+-- Exemption certificates submitted by B2B customers. A certificate without a
+-- state covers the whole country.
+CREATE TABLE tax_exemptions (
+  id INTEGER GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  certificate_id VARCHAR NOT NULL UNIQUE,
+  customer_email VARCHAR NOT NULL,
+  country VARCHAR NOT NULL,
+  state VARCHAR,
+  expires_at TIMESTAMPTZ,
+  active BOOLEAN NOT NULL DEFAULT TRUE
+);
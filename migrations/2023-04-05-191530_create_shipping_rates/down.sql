@@ -0,0 +1,8 @@
+DROP TABLE shipping_rates;
+DROP TABLE shipping_zones;
+
+ALTER TABLE products
+DROP COLUMN weight_grams,
+DROP COLUMN length_mm,
+DROP COLUMN width_mm,
+DROP COLUMN height_mm;
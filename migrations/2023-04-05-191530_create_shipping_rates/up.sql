@@ -0,0 +1,26 @@
+ALTER TABLE products
+ADD COLUMN weight_grams INT NOT NULL DEFAULT 0,
+ADD COLUMN length_mm INT NOT NULL DEFAULT 0,
+ADD COLUMN width_mm INT NOT NULL DEFAULT 0,
+ADD COLUMN height_mm INT NOT NULL DEFAULT 0;
+
+-- A zone without a state covers the rest of the country. Countries and
+-- states are stored upper-case, e.g. 'US' and 'NY'.
+CREATE TABLE shipping_zones (
+  id INTEGER GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  name VARCHAR NOT NULL,
+  country VARCHAR NOT NULL,
+  state VARCHAR
+);
+
+-- method is one of 'standard', 'express' or 'pickup'. The fee for a parcel
+-- is base_fee plus per_kg_fee for every kilogram of billable weight.
+CREATE TABLE shipping_rates (
+  id INTEGER GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  zone_id INTEGER NOT NULL REFERENCES shipping_zones (id) ON DELETE CASCADE,
+  method VARCHAR NOT NULL,
+  min_weight_grams INT NOT NULL DEFAULT 0,
+  max_weight_grams INT,
+  base_fee DECIMAL(10,2) NOT NULL,
+  per_kg_fee DECIMAL(10,2) NOT NULL DEFAULT 0.00
+);
@@ -0,0 +1,6 @@
+DROP TRIGGER credit_notes_immutable ON credit_notes;
+DROP TRIGGER invoices_immutable ON invoices;
+DROP FUNCTION reject_issued_document_change();
+DROP TABLE credit_notes;
+DROP TABLE invoices;
+DROP TABLE document_counters;
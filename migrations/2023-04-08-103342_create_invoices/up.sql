@@ -0,0 +1,43 @@
+CREATE TABLE document_counters (
+  name VARCHAR PRIMARY KEY,
+  next_number INTEGER NOT NULL
+);
+
+INSERT INTO document_counters (name, next_number) VALUES ('invoice', 1), ('credit_note', 1);
+
+CREATE TABLE invoices (
+  id INTEGER GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  invoice_number VARCHAR NOT NULL UNIQUE,
+  order_id INTEGER NOT NULL,
+  customer_email VARCHAR NOT NULL,
+  currency VARCHAR NOT NULL,
+  total DECIMAL(10,2) NOT NULL,
+  document TEXT NOT NULL,
+  issued_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE credit_notes (
+  id INTEGER GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  credit_note_number VARCHAR NOT NULL UNIQUE,
+  invoice_id INTEGER NOT NULL REFERENCES invoices (id),
+  reason VARCHAR NOT NULL,
+  note VARCHAR,
+  amount DECIMAL(10,2) NOT NULL CHECK (amount > 0),
+  issued_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Issued documents are never changed or removed, corrections are made with
+-- credit notes instead
+CREATE FUNCTION reject_issued_document_change() RETURNS TRIGGER AS $$
+BEGIN
+  RAISE EXCEPTION '% rows are immutable once issued', TG_TABLE_NAME;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER invoices_immutable
+  BEFORE UPDATE OR DELETE ON invoices
+  FOR EACH ROW EXECUTE FUNCTION reject_issued_document_change();
+
+CREATE TRIGGER credit_notes_immutable
+  BEFORE UPDATE OR DELETE ON credit_notes
+  FOR EACH ROW EXECUTE FUNCTION reject_issued_document_change();
@@ -0,0 +1,4 @@
+ALTER TABLE invoices
+  DROP COLUMN billing_address,
+  DROP COLUMN shipping_address,
+  DROP COLUMN masked_card;
@@ -0,0 +1,4 @@
+ALTER TABLE invoices
+  ADD COLUMN billing_address TEXT,
+  ADD COLUMN shipping_address TEXT,
+  ADD COLUMN masked_card VARCHAR;
@@ -0,0 +1,6 @@
+-- Units of each currency that one unit of the store's base currency buys
+CREATE TABLE exchange_rates (
+  currency VARCHAR PRIMARY KEY,
+  rate DECIMAL(18,8) NOT NULL CHECK (rate > 0),
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
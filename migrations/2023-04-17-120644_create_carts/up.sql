@@ -0,0 +1,20 @@
+CREATE TABLE carts (
+  id VARCHAR PRIMARY KEY,
+  currency VARCHAR NOT NULL,
+  shipping_method VARCHAR NOT NULL DEFAULT 'standard',
+  coupon_code VARCHAR,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  checked_out_at TIMESTAMPTZ
+);
+
+CREATE TABLE cart_lines (
+  id INTEGER GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  cart_id VARCHAR NOT NULL REFERENCES carts (id) ON DELETE CASCADE,
+  product_id INTEGER NOT NULL REFERENCES products (id),
+  qty INTEGER NOT NULL CHECK (qty > 0),
+  reserved_until TIMESTAMPTZ,
+  UNIQUE (cart_id, product_id)
+);
+
+CREATE INDEX cart_lines_reservation_idx ON cart_lines (product_id, reserved_until);
@@ -0,0 +1,19 @@
+-- Order ids used to be picked at random, so new ones start above that range
+CREATE SEQUENCE order_ids START 10000;
+
+CREATE TABLE orders (
+  id INTEGER PRIMARY KEY,
+  customer_email VARCHAR NOT NULL,
+  currency VARCHAR NOT NULL,
+  total NUMERIC NOT NULL,
+  transaction_id VARCHAR,
+  payment_status VARCHAR NOT NULL,
+  capture_amount NUMERIC,
+  captured_amount NUMERIC NOT NULL DEFAULT 0,
+  shipped_lines TEXT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  shipped_at TIMESTAMPTZ,
+  captured_at TIMESTAMPTZ
+);
+
+CREATE INDEX orders_payment_status_idx ON orders (payment_status);
@@ -0,0 +1,16 @@
+ALTER TABLE orders ADD COLUMN refunded_amount NUMERIC NOT NULL DEFAULT 0;
+
+CREATE TABLE refunds (
+  id INTEGER GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  order_id INTEGER NOT NULL REFERENCES orders (id),
+  kind VARCHAR NOT NULL,
+  amount NUMERIC NOT NULL,
+  transaction_id VARCHAR,
+  credit_note_number VARCHAR,
+  lines TEXT NOT NULL,
+  restocked BOOLEAN NOT NULL DEFAULT FALSE,
+  note VARCHAR,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX refunds_order_id_idx ON refunds (order_id);
@@ -0,0 +1,23 @@
+-- Orders are only stored once paid, so attempts for declined orders refer
+-- to an order id that may not exist in orders
+CREATE TABLE payments (
+  id INTEGER GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  order_id INTEGER NOT NULL,
+  action VARCHAR NOT NULL,
+  amount NUMERIC,
+  currency VARCHAR,
+  outcome VARCHAR NOT NULL,
+  message TEXT,
+  transaction_id VARCHAR,
+  response_code VARCHAR,
+  result_code VARCHAR,
+  auth_code VARCHAR,
+  avs_result_code VARCHAR,
+  cvv_result_code VARCHAR,
+  account_type VARCHAR,
+  account_number VARCHAR,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX payments_order_id_idx ON payments (order_id);
+CREATE INDEX payments_transaction_id_idx ON payments (transaction_id);
@@ -0,0 +1,20 @@
+-- One profile per customer at the gateway, which holds their cards
+CREATE TABLE customer_profiles (
+  customer_email VARCHAR PRIMARY KEY,
+  profile_id VARCHAR NOT NULL UNIQUE,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Only what identifies a card to the customer is kept here, the card itself
+-- stays with the gateway
+CREATE TABLE payment_methods (
+  id INTEGER GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  customer_email VARCHAR NOT NULL REFERENCES customer_profiles (customer_email),
+  payment_profile_id VARCHAR NOT NULL UNIQUE,
+  card_type VARCHAR,
+  masked_card VARCHAR NOT NULL,
+  expiration_date VARCHAR,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX payment_methods_customer_email_idx ON payment_methods (customer_email);
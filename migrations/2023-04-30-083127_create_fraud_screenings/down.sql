@@ -0,0 +1,2 @@
+ALTER TABLE orders DROP COLUMN review_reasons;
+DROP TABLE fraud_screenings;
@@ -0,0 +1,22 @@
+-- Every checkout that went through fraud screening, kept to count recent
+-- orders and declined cards. Like payments, blocked and declined checkouts
+-- refer to an order id that never made it into orders.
+CREATE TABLE fraud_screenings (
+  id INTEGER GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  order_id INTEGER NOT NULL,
+  customer_email VARCHAR NOT NULL,
+  ip_address VARCHAR NOT NULL,
+  card_fingerprint VARCHAR,
+  decision VARCHAR NOT NULL,
+  reasons TEXT,
+  payment_outcome VARCHAR,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX fraud_screenings_order_id_idx ON fraud_screenings (order_id);
+CREATE INDEX fraud_screenings_customer_email_idx ON fraud_screenings (customer_email, created_at);
+CREATE INDEX fraud_screenings_ip_address_idx ON fraud_screenings (ip_address, created_at);
+CREATE INDEX fraud_screenings_card_fingerprint_idx ON fraud_screenings (card_fingerprint, created_at);
+
+-- Why an order was held for manual review
+ALTER TABLE orders ADD COLUMN review_reasons TEXT;
@@ -0,0 +1 @@
+ALTER TABLE coupons DROP CONSTRAINT coupons_code_normalized;
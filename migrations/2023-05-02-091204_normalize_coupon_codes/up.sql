@@ -0,0 +1,5 @@
+-- Codes are looked up trimmed and uppercased, so they have to be stored
+-- that way too
+UPDATE coupons SET code = upper(btrim(code));
+
+ALTER TABLE coupons ADD CONSTRAINT coupons_code_normalized CHECK (code = upper(btrim(code)));
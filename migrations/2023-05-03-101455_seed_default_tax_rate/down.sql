@@ -0,0 +1 @@
+DELETE FROM tax_rates WHERE country = '*';
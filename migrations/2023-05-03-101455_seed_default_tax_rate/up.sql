@@ -0,0 +1,4 @@
+-- Country '*' matches any address that no other rate covers. This keeps the
+-- flat 7.15% on goods that was charged before rates were per jurisdiction.
+INSERT INTO tax_rates (country, tax_category, rate, shipping_taxable)
+VALUES ('*', 'standard', 0.0715, FALSE);
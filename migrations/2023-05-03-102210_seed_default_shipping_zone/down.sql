@@ -0,0 +1,2 @@
+-- Rates go with their zone
+DELETE FROM shipping_zones WHERE country = '*';
@@ -0,0 +1,9 @@
+-- Country '*' matches any address that no other zone covers. This keeps the
+-- flat $5 standard shipping charged before zones existed.
+WITH zone AS (
+  INSERT INTO shipping_zones (name, country)
+  VALUES ('Everywhere else', '*')
+  RETURNING id
+)
+INSERT INTO shipping_rates (zone_id, method, base_fee)
+SELECT id, 'standard', 5.00 FROM zone;
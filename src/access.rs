@@ -0,0 +1,71 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use sha2::Sha256;
+
+use crate::{
+    payment_methods::{is_customer, CUSTOMER_TOKEN_SECRET},
+    settings::required_secret,
+};
+
+lazy_static! {
+    /// Credential staff send to ship, refund and review orders, from
+    /// `ADMIN_TOKEN`
+    pub static ref ADMIN_TOKEN: Vec<u8> = required_secret("ADMIN_TOKEN");
+}
+
+/// Whether `token` is the admin credential
+pub fn is_admin(token: Option<&str>) -> bool {
+    match token {
+        Some(token) => constant_time_eq(token.trim().as_bytes(), &ADMIN_TOKEN),
+        None => false,
+    }
+}
+
+/// The token handed out with a new order so whoever placed it can look it
+/// up again. Order ids are sequential, so the id alone proves nothing.
+pub fn sign_order(order_id: i32) -> String {
+    URL_SAFE_NO_PAD.encode(order_mac(order_id).finalize().into_bytes())
+}
+
+/// Whether `token` may see order `order_id`: its order token, the customer
+/// token of `customer_email`, or the admin credential
+pub fn may_view_order(order_id: i32, customer_email: Option<&str>, token: Option<&str>) -> bool {
+    if is_admin(token) {
+        return true;
+    }
+
+    let order_token_matches = match token.map(|token| URL_SAFE_NO_PAD.decode(token.trim())) {
+        Some(Ok(signature)) => order_mac(order_id).verify_slice(&signature).is_ok(),
+        _ => false,
+    };
+
+    order_token_matches || customer_email.is_some_and(|email| is_customer(email, token))
+}
+
+fn order_mac(order_id: i32) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&CUSTOMER_TOKEN_SECRET)
+        .expect("HMAC accepts keys of any length");
+    mac.update(b"order:");
+    mac.update(order_id.to_string().as_bytes());
+    mac
+}
+
+/// Compares without stopping at the first difference, so response times
+/// give nothing away about the admin credential
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_time_eq_compares_whole_values() {
+        assert!(constant_time_eq(b"same secret", b"same secret"));
+        assert!(!constant_time_eq(b"same secret", b"same secreT"));
+        assert!(!constant_time_eq(b"same secret", b"same secret "));
+        assert!(!constant_time_eq(b"", b"x"));
+    }
+}
@@ -1,17 +1,26 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use dotenvy::dotenv;
 use rand::Rng;
 use reqwest::header::CONTENT_TYPE;
-use serde::{Deserialize, Serialize};
-use std::env;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{env, fmt, time::Duration};
+use zeroize::Zeroize;
 
 use crate::{
     ecommerce::{Customer, Invoice},
+    gateway::{
+        retry_delay, FoundTransaction, GatewayError, GatewayMessage, GatewayResponse,
+        GatewayResult, PaymentGateway, SavedProfile, TransactionStatus,
+    },
     inventory::Order,
+    money::Money,
+    settings::SETTINGS,
 };
 
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
-pub struct ChargeCreditCardRequest {
+struct CreateTransactionEnvelope {
     create_transaction_request: CreateTransactionRequest,
 }
 
@@ -23,34 +32,96 @@ struct CreateTransactionRequest {
     transaction_request: TransactionRequest,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 struct MerchantAuthentication {
     name: String,
     transaction_key: String,
 }
 
-#[derive(Deserialize, Serialize)]
+/// Authorize.NET checks the order of these fields, so new ones have to go
+/// where its schema puts them. Follow-up transactions only send a few.
+#[derive(Deserialize, Serialize, Default)]
 #[serde(rename_all = "camelCase")]
 struct TransactionRequest {
     transaction_type: String,
-    amount: String,
-    payment: Payment,
-    line_items: Vec<()>,
-    tax: AuthorizeNetFee,
-    duty: AuthorizeNetFee,
-    shipping: AuthorizeNetFee,
-    po_number: String,
-    customer: AuthorizeNetCustomer,
-    bill_to: Address,
-    ship_to: Address,
-    #[serde(rename(serialize = "customerIP"))]
-    customer_ip: String,
-    transaction_settings: TransactionSettings,
-    user_fields: UserFields,
-    processing_options: ProcessingOptions,
-    subsequent_auth_information: SubsequentAuthInformation,
-    authorization_indicator_type: AuthorizationIndicatorType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    amount: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payment: Option<PaymentDetails>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<ProfilePayment>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ref_trans_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    order: Option<OrderDescription>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line_items: Option<LineItems>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tax: Option<AuthorizeNetFee>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duty: Option<AuthorizeNetFee>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shipping: Option<AuthorizeNetFee>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tax_exempt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    po_number: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    customer: Option<AuthorizeNetCustomer>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bill_to: Option<Address>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ship_to: Option<Address>,
+    #[serde(
+        rename(serialize = "customerIP"),
+        skip_serializing_if = "Option::is_none"
+    )]
+    customer_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transaction_settings: Option<TransactionSettings>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_fields: Option<UserFields>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    processing_options: Option<ProcessingOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subsequent_auth_information: Option<SubsequentAuthInformation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    authorization_indicator_type: Option<AuthorizationIndicatorType>,
+}
+
+/// Carries the store's order id, which transaction lists report back, so a
+/// transaction whose reply was lost can still be found
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OrderDescription {
+    invoice_number: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LineItems {
+    line_item: Vec<AuthorizeNetLineItem>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthorizeNetLineItem {
+    pub item_id: String,
+    pub name: String,
+    pub description: String,
+    pub quantity: String,
+    pub unit_price: String,
+    pub taxable: String,
+}
+
+impl AuthorizeNetLineItem {
+    /// Authorize.NET rejects line item names longer than this
+    pub const MAX_NAME_LENGTH: usize = 31;
+    /// Authorize.NET accepts at most this many line items per transaction
+    pub const MAX_LINE_ITEMS: usize = 30;
 }
 
 #[derive(Deserialize, Serialize)]
@@ -86,13 +157,83 @@ pub struct AuthorizeNetFee {
     pub description: String,
 }
 
-#[derive(Deserialize, Serialize)]
+/// How a customer pays. Opaque data is a token from Authorize.NET's hosted
+/// payment form, so the card itself never reaches this server. Neither kind
+/// can be serialized, and both are wiped from memory once dropped.
+#[derive(Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
-struct Payment {
-    credit_card: CreditCard,
+pub enum Payment {
+    CreditCard(CreditCard),
+    OpaqueData(OpaqueData),
+    /// One of the customer's saved payment methods, by its id in our records
+    #[serde(rename = "paymentMethodId")]
+    SavedMethod(i32),
+    /// A saved payment method once it has been looked up. Never read from a
+    /// request, so nobody can name another customer's profile.
+    #[serde(skip_deserializing)]
+    Profile(PaymentProfile),
 }
 
-#[derive(Deserialize, Serialize, Clone)]
+impl Payment {
+    /// Card number with everything but the last four digits hidden, when it
+    /// is known before the gateway replies
+    pub fn masked(&self) -> Option<String> {
+        match self {
+            Payment::CreditCard(card) => Some(card.masked()),
+            Payment::Profile(profile) => Some(profile.masked_card.clone()),
+            Payment::OpaqueData(_) | Payment::SavedMethod(_) => None,
+        }
+    }
+
+    /// Whether the payment can still be sent once it has waited `waited` for
+    /// the gateway to come back. Card details are never kept for later, and
+    /// tokens only work until they expire.
+    pub fn can_wait(&self, waited: Duration) -> bool {
+        match self {
+            Payment::Profile(_) => true,
+            Payment::OpaqueData(_) => waited < OPAQUE_DATA_TTL,
+            Payment::CreditCard(_) | Payment::SavedMethod(_) => false,
+        }
+    }
+
+    fn details(&self) -> Option<PaymentDetails> {
+        match self {
+            Payment::CreditCard(card) => Some(PaymentDetails::CreditCard(CardDetails {
+                card_number: card.card_number.clone(),
+                expiration_date: card.expiration_date.clone(),
+                card_code: card.card_code.clone(),
+            })),
+            Payment::OpaqueData(data) => Some(PaymentDetails::OpaqueData(OpaqueDetails {
+                data_descriptor: data.data_descriptor.clone(),
+                data_value: data.data_value.clone(),
+            })),
+            Payment::SavedMethod(_) | Payment::Profile(_) => None,
+        }
+    }
+
+    fn profile(&self) -> Option<ProfilePayment> {
+        match self {
+            Payment::Profile(profile) => Some(ProfilePayment {
+                customer_profile_id: profile.profile_id.clone(),
+                payment_profile: PaymentProfileReference {
+                    payment_profile_id: profile.payment_profile_id.clone(),
+                },
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A payment method kept at the gateway, charged without sending any card
+/// details
+#[derive(Clone, Debug)]
+pub struct PaymentProfile {
+    pub profile_id: String,
+    pub payment_profile_id: String,
+    pub masked_card: String,
+}
+
+#[derive(Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct CreditCard {
     pub card_number: String,
@@ -100,6 +241,120 @@ pub struct CreditCard {
     pub card_code: String,
 }
 
+impl CreditCard {
+    /// Card number with everything but the last four digits hidden, the same
+    /// form Authorize.NET reports it in
+    pub fn masked(&self) -> String {
+        let digits: Vec<char> = self
+            .card_number
+            .chars()
+            .filter(|c| c.is_ascii_digit())
+            .collect();
+        let last_four: String = digits[digits.len().saturating_sub(4)..].iter().collect();
+
+        format!("XXXX{}", last_four)
+    }
+}
+
+impl Drop for CreditCard {
+    fn drop(&mut self) {
+        self.card_number.zeroize();
+        self.expiration_date.zeroize();
+        self.card_code.zeroize();
+    }
+}
+
+impl fmt::Debug for CreditCard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CreditCard")
+            .field("card_number", &self.masked())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A single use token from the hosted payment form. It expires about
+/// fifteen minutes after it is issued.
+pub const OPAQUE_DATA_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// A single use token from the hosted payment form, valid for
+/// `OPAQUE_DATA_TTL`
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OpaqueData {
+    pub data_descriptor: String,
+    pub data_value: String,
+}
+
+impl Drop for OpaqueData {
+    fn drop(&mut self) {
+        self.data_descriptor.zeroize();
+        self.data_value.zeroize();
+    }
+}
+
+impl fmt::Debug for OpaqueData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpaqueData")
+            .field("data_descriptor", &self.data_descriptor)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The `payment` element of a request, which is the only place card details
+/// are ever serialized
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum PaymentDetails {
+    CreditCard(CardDetails),
+    OpaqueData(OpaqueDetails),
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CardDetails {
+    card_number: String,
+    expiration_date: String,
+    /// Left out of refunds, which only carry the last four digits
+    #[serde(skip_serializing_if = "String::is_empty")]
+    card_code: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OpaqueDetails {
+    data_descriptor: String,
+    data_value: String,
+}
+
+impl Drop for CardDetails {
+    fn drop(&mut self) {
+        self.card_number.zeroize();
+        self.expiration_date.zeroize();
+        self.card_code.zeroize();
+    }
+}
+
+impl Drop for OpaqueDetails {
+    fn drop(&mut self) {
+        self.data_descriptor.zeroize();
+        self.data_value.zeroize();
+    }
+}
+
+/// Charges a saved payment profile in place of `payment`
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ProfilePayment {
+    customer_profile_id: String,
+    payment_profile: PaymentProfileReference,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PaymentProfileReference {
+    payment_profile_id: String,
+}
+
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct ProcessingOptions {
@@ -148,34 +403,40 @@ struct UserField {
 
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
-pub struct ChargeCreditCardResponse {
-    transaction_response: TransactionResponse,
-    pub ref_id: String,
+struct CreateTransactionResponse {
+    /// Missing when the request is rejected before a transaction is made
+    transaction_response: Option<TransactionResponse>,
+    #[serde(default)]
+    ref_id: String,
     messages: TransactionResponseResultMessages,
 }
 
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct TransactionResponse {
-    response_code: String,
-    auth_code: String,
-    avs_result_code: String,
-    cvv_result_code: String,
-    trans_id: String,
+    response_code: Option<String>,
+    auth_code: Option<String>,
+    avs_result_code: Option<String>,
+    cvv_result_code: Option<String>,
+    trans_id: Option<String>,
     #[serde(rename(deserialize = "refTransID"))]
-    ref_trans_id: String,
-    trans_hash: String,
-    test_request: String,
-    account_number: String,
-    account_type: String,
+    ref_trans_id: Option<String>,
+    trans_hash: Option<String>,
+    test_request: Option<String>,
+    account_number: Option<String>,
+    account_type: Option<String>,
+    /// Sent when the transaction went through, whatever its outcome
+    #[serde(default)]
     messages: Vec<TransactionResponseMessage>,
+    /// Sent instead of `messages` when the transaction failed
+    #[serde(default)]
+    errors: Vec<TransactionResponseError>,
     #[serde(default = "UserFields::get_default")]
     user_fields: UserFields,
-    trans_hash_sha2: String,
+    trans_hash_sha2: Option<String>,
     #[serde(rename(deserialize = "SupplementalDataQualificationIndicator"))]
-    supplemental_data_qualification_indicator: usize,
-    #[serde(default)]
-    network_trans_id: String,
+    supplemental_data_qualification_indicator: Option<usize>,
+    network_trans_id: Option<String>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -199,91 +460,707 @@ struct TransactionResponseMessage {
     description: String,
 }
 
-impl ChargeCreditCardRequest {
-    pub async fn create(
-        order: &Order,
-        invoice: Invoice,
-        customer: Customer,
-    ) -> Result<ChargeCreditCardResponse, Box<dyn std::error::Error>> {
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TransactionResponseError {
+    error_code: String,
+    error_text: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GetTransactionDetailsEnvelope {
+    get_transaction_details_request: GetTransactionDetailsRequest,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GetTransactionDetailsRequest {
+    merchant_authentication: MerchantAuthentication,
+    trans_id: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GetTransactionDetailsResponse {
+    transaction: Option<TransactionDetails>,
+    messages: TransactionResponseResultMessages,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TransactionDetails {
+    trans_id: String,
+    transaction_status: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GetUnsettledTransactionListEnvelope {
+    get_unsettled_transaction_list_request: GetUnsettledTransactionListRequest,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GetUnsettledTransactionListRequest {
+    merchant_authentication: MerchantAuthentication,
+    sorting: TransactionListSorting,
+    paging: TransactionListPaging,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TransactionListSorting {
+    order_by: String,
+    order_descending: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TransactionListPaging {
+    limit: usize,
+    offset: usize,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GetUnsettledTransactionListResponse {
+    #[serde(default)]
+    transactions: Vec<TransactionSummary>,
+    messages: TransactionResponseResultMessages,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GetSettledBatchListEnvelope {
+    get_settled_batch_list_request: GetSettledBatchListRequest,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GetSettledBatchListRequest {
+    merchant_authentication: MerchantAuthentication,
+    first_settlement_date: String,
+    last_settlement_date: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GetSettledBatchListResponse {
+    #[serde(default)]
+    batch_list: Vec<SettledBatch>,
+    messages: TransactionResponseResultMessages,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SettledBatch {
+    batch_id: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GetTransactionListEnvelope {
+    get_transaction_list_request: GetTransactionListRequest,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GetTransactionListRequest {
+    merchant_authentication: MerchantAuthentication,
+    batch_id: String,
+    sorting: TransactionListSorting,
+    paging: TransactionListPaging,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GetTransactionListResponse {
+    #[serde(default)]
+    transactions: Vec<TransactionSummary>,
+    messages: TransactionResponseResultMessages,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TransactionSummary {
+    trans_id: String,
+    transaction_status: String,
+    invoice_number: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateProfileFromTransactionEnvelope {
+    create_customer_profile_from_transaction_request: CreateProfileFromTransactionRequest,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateProfileFromTransactionRequest {
+    merchant_authentication: MerchantAuthentication,
+    trans_id: String,
+    /// Only sent when a new profile is made
+    #[serde(skip_serializing_if = "Option::is_none")]
+    customer: Option<ProfileCustomer>,
+    /// Adds the payment method to this profile instead of making a new one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    customer_profile_id: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ProfileCustomer {
+    email: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateProfileResponse {
+    customer_profile_id: Option<String>,
+    #[serde(default)]
+    customer_payment_profile_id_list: Vec<String>,
+    messages: TransactionResponseResultMessages,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeletePaymentProfileEnvelope {
+    delete_customer_payment_profile_request: DeletePaymentProfileRequest,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeletePaymentProfileRequest {
+    merchant_authentication: MerchantAuthentication,
+    customer_profile_id: String,
+    customer_payment_profile_id: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeletePaymentProfileResponse {
+    messages: TransactionResponseResultMessages,
+}
+
+impl TransactionResponseResultMessages {
+    /// Turns a reply the gateway marked as failed into an error
+    fn check(&self) -> GatewayResult<()> {
+        if self.result_code == "Ok" {
+            Ok(())
+        } else {
+            Err(GatewayError::Rejected(
+                self.message
+                    .iter()
+                    .map(|message| message.text.as_str())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ))
+        }
+    }
+}
+
+/// How many transactions are fetched per page when looking for a lost reply
+const TRANSACTION_LOOKUP_LIMIT: usize = 100;
+
+/// Longest span of settlement dates Authorize.NET lists batches for
+const SETTLED_LOOKUP_DAYS: i64 = 31;
+
+/// Authorize.NET's error code for a transaction id it does not know
+const UNKNOWN_TRANSACTION_CODE: &str = "E00040";
+
+fn check_messages(messages: TransactionResponseResultMessages) -> GatewayResult<()> {
+    if messages.result_code == "Ok" {
+        return Ok(());
+    }
+
+    Err(GatewayError::InvalidResponse(
+        messages
+            .message
+            .into_iter()
+            .map(|message| message.text)
+            .collect::<Vec<_>>()
+            .join(" "),
+    ))
+}
+
+/// The transaction in `transactions` that was sent with `ref_id`
+fn find_in(transactions: Vec<TransactionSummary>, ref_id: &str) -> Option<FoundTransaction> {
+    transactions
+        .into_iter()
+        .find(|transaction| transaction.invoice_number.as_deref() == Some(ref_id))
+        .map(|transaction| FoundTransaction {
+            transaction_id: transaction.trans_id,
+            status: TransactionStatus::from_code(&transaction.transaction_status),
+        })
+}
+
+fn settlement_date(date: DateTime<Utc>) -> String {
+    date.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+/// Client for the Authorize.NET JSON API
+pub struct AuthorizeNetGateway {
+    merchant_id: String,
+    transaction_key: String,
+    endpoint: String,
+    client: reqwest::Client,
+}
+
+impl AuthorizeNetGateway {
+    const SANDBOX_ENDPOINT: &'static str = "https://apitest.authorize.net/xml/v1/request.api";
+
+    pub fn from_env() -> Self {
         dotenv().ok();
-        let client = reqwest::Client::new();
 
-        let merchant_id = env::var("MERCHANT_ID").expect("Could not get MERCHANT_ID from .env");
-        let transaction_key =
-            env::var("TRANSACTION_KEY").expect("Could not get TRANSACTION_KEY from .env");
+        AuthorizeNetGateway {
+            merchant_id: env::var("MERCHANT_ID").expect("Could not get MERCHANT_ID from .env"),
+            transaction_key: env::var("TRANSACTION_KEY")
+                .expect("Could not get TRANSACTION_KEY from .env"),
+            endpoint: String::from(Self::SANDBOX_ENDPOINT),
+            client: reqwest::Client::builder()
+                .connect_timeout(Duration::from_secs(SETTINGS.gateway_connect_timeout_secs))
+                .timeout(Duration::from_secs(SETTINGS.gateway_timeout_secs))
+                .build()
+                .expect("Unable to build payment gateway client"),
+        }
+    }
+
+    fn authentication(&self) -> MerchantAuthentication {
+        MerchantAuthentication {
+            name: self.merchant_id.clone(),
+            transaction_key: self.transaction_key.clone(),
+        }
+    }
+
+    /// Sends a request, trying again only while it is certain the gateway
+    /// never received it
+    async fn send<Request, Response>(&self, request: &Request) -> GatewayResult<Response>
+    where
+        Request: Serialize,
+        Response: DeserializeOwned,
+    {
+        let mut attempt = 1;
+        loop {
+            match self.send_once(request).await {
+                Err(GatewayError::Unreachable(_)) if attempt <= SETTINGS.gateway_retries => {
+                    tokio::time::sleep(retry_delay(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn send_once<Request, Response>(&self, request: &Request) -> GatewayResult<Response>
+    where
+        Request: Serialize,
+        Response: DeserializeOwned,
+    {
+        let response = self
+            .client
+            .post(&self.endpoint)
+            .header(CONTENT_TYPE, "application/json")
+            .json(request)
+            .send()
+            .await
+            .map_err(|error| {
+                // Only a failed connection proves nothing was sent
+                if error.is_connect() || error.is_builder() {
+                    GatewayError::Unreachable(error.to_string())
+                } else {
+                    GatewayError::InDoubt(error.to_string())
+                }
+            })?
+            .text()
+            .await
+            .map_err(|error| GatewayError::InDoubt(error.to_string()))?;
 
-        let ref_id = String::from(order.id.to_string());
-        let transaction_type = String::from("authCaptureTransaction");
-        let transaction_total = invoice.total.to_string();
+        // Authorize.NET returns a ZWSP at the start of the JSON response
+        let response = str::replace(&response, "\u{feff}", "");
 
-        let taxes = invoice.get_taxes();
-        let duties = invoice.get_duty();
-        let shipping_fees = invoice.get_shipping();
+        serde_json::from_str(&response)
+            .map_err(|error| GatewayError::InvalidResponse(error.to_string()))
+    }
 
+    async fn create_transaction(
+        &self,
+        ref_id: String,
+        transaction_request: TransactionRequest,
+    ) -> GatewayResult<GatewayResponse> {
+        let envelope = CreateTransactionEnvelope {
+            create_transaction_request: CreateTransactionRequest {
+                merchant_authentication: self.authentication(),
+                ref_id,
+                transaction_request,
+            },
+        };
+
+        let response: CreateTransactionResponse = self.send(&envelope).await?;
+        Ok(response.into())
+    }
+
+    /// Full request for a new authorization, with or without capture
+    fn card_transaction(
+        transaction_type: &str,
+        order: &Order,
+        invoice: &Invoice,
+        customer: &Customer,
+    ) -> TransactionRequest {
         let po_number = rand::thread_rng().gen_range(0..100000).to_string();
         let customer_id = String::from("99999456654");
 
-        let charge_request = ChargeCreditCardRequest {
-            create_transaction_request: CreateTransactionRequest {
-                merchant_authentication: MerchantAuthentication {
-                    name: merchant_id,
-                    transaction_key,
+        TransactionRequest {
+            transaction_type: transaction_type.to_string(),
+            amount: Some(invoice.total.to_string()),
+            currency_code: Some(invoice.total.currency.code().to_string()),
+            payment: customer.payment.details(),
+            profile: customer.payment.profile(),
+            ref_trans_id: None,
+            order: Some(OrderDescription {
+                invoice_number: order.id.to_string(),
+            }),
+            line_items: Some(LineItems {
+                line_item: invoice.get_line_items(),
+            }),
+            tax: Some(invoice.get_taxes()),
+            duty: Some(invoice.get_duty()),
+            shipping: Some(invoice.get_shipping()),
+            tax_exempt: Some(invoice.tax_exemption_id.is_some().to_string()),
+            po_number: Some(po_number),
+            customer: Some(AuthorizeNetCustomer { id: customer_id }),
+            // A saved profile carries its own billing address
+            bill_to: match customer.payment {
+                Payment::Profile(_) => None,
+                _ => Some(customer.billing_address.clone()),
+            },
+            ship_to: Some(customer.shipping_address.clone()),
+            customer_ip: Some(customer.ip_address.clone()),
+            transaction_settings: Some(TransactionSettings {
+                setting: TransactionSetting {
+                    setting_name: "testRequest".to_string(),
+                    setting_value: "false".to_string(),
                 },
-                ref_id,
-                transaction_request: TransactionRequest {
-                    transaction_type,
-                    amount: transaction_total,
-                    payment: Payment {
-                        credit_card: CreditCard {
-                            card_code: customer.credit_card.card_code,
-                            card_number: customer.credit_card.card_number,
-                            expiration_date: customer.credit_card.expiration_date,
-                        },
-                    },
-                    line_items: vec![],
-                    tax: taxes,
-                    duty: duties,
-                    shipping: shipping_fees,
-                    po_number,
-                    customer: AuthorizeNetCustomer { id: customer_id },
-                    bill_to: customer.billing_address,
-                    ship_to: customer.shipping_address,
-                    customer_ip: customer.ip_address,
-                    transaction_settings: TransactionSettings {
-                        setting: TransactionSetting {
-                            setting_name: "testRequest".to_string(),
-                            setting_value: "false".to_string(),
-                        },
-                    },
-                    user_fields: UserFields { user_field: vec![] },
-                    processing_options: ProcessingOptions {
-                        is_subsequent_auth: "true".to_string(),
+            }),
+            user_fields: Some(UserFields { user_field: vec![] }),
+            processing_options: Some(ProcessingOptions {
+                is_subsequent_auth: "true".to_string(),
+            }),
+            subsequent_auth_information: Some(SubsequentAuthInformation {
+                original_auth_amount: "45".to_string(),
+                original_network_trans_id: "123456789NNNH".to_string(),
+                reason: "resubmission".to_string(),
+            }),
+            authorization_indicator_type: Some(AuthorizationIndicatorType {
+                authorization_indicator: "final".to_string(),
+            }),
+        }
+    }
+}
+
+impl From<CreateTransactionResponse> for GatewayResponse {
+    fn from(response: CreateTransactionResponse) -> Self {
+        let result_code = response.messages.result_code;
+        let result_messages = response
+            .messages
+            .message
+            .into_iter()
+            .map(|message| GatewayMessage {
+                code: message.code,
+                text: message.text,
+            });
+
+        let transaction = match response.transaction_response {
+            Some(transaction) => transaction,
+            None => {
+                return GatewayResponse {
+                    result_code,
+                    messages: result_messages.collect(),
+                    ..Default::default()
+                }
+            }
+        };
+
+        // Transaction level messages explain the outcome best, the overall
+        // result messages only say whether the call succeeded
+        let mut messages: Vec<GatewayMessage> = transaction
+            .messages
+            .into_iter()
+            .map(|message| GatewayMessage {
+                code: message.code,
+                text: message.description,
+            })
+            .chain(transaction.errors.into_iter().map(|error| GatewayMessage {
+                code: error.error_code,
+                text: error.error_text,
+            }))
+            .collect();
+        if messages.is_empty() {
+            messages.extend(result_messages);
+        }
+
+        GatewayResponse {
+            transaction_id: transaction.trans_id.unwrap_or_default(),
+            response_code: transaction.response_code.unwrap_or_default(),
+            result_code,
+            auth_code: transaction.auth_code.unwrap_or_default(),
+            avs_result_code: transaction.avs_result_code.unwrap_or_default(),
+            cvv_result_code: transaction.cvv_result_code.unwrap_or_default(),
+            account_number: transaction.account_number.unwrap_or_default(),
+            account_type: transaction.account_type.unwrap_or_default(),
+            messages,
+        }
+    }
+}
+
+#[async_trait]
+impl PaymentGateway for AuthorizeNetGateway {
+    async fn charge(
+        &self,
+        order: &Order,
+        invoice: &Invoice,
+        customer: &Customer,
+    ) -> GatewayResult<GatewayResponse> {
+        self.create_transaction(
+            order.id.to_string(),
+            Self::card_transaction("authCaptureTransaction", order, invoice, customer),
+        )
+        .await
+    }
+
+    async fn authorize(
+        &self,
+        order: &Order,
+        invoice: &Invoice,
+        customer: &Customer,
+    ) -> GatewayResult<GatewayResponse> {
+        self.create_transaction(
+            order.id.to_string(),
+            Self::card_transaction("authOnlyTransaction", order, invoice, customer),
+        )
+        .await
+    }
+
+    async fn capture(
+        &self,
+        transaction_id: &str,
+        amount: &Money,
+    ) -> GatewayResult<GatewayResponse> {
+        self.create_transaction(
+            transaction_id.to_string(),
+            TransactionRequest {
+                transaction_type: String::from("priorAuthCaptureTransaction"),
+                amount: Some(amount.to_string()),
+                ref_trans_id: Some(transaction_id.to_string()),
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    async fn void(&self, transaction_id: &str) -> GatewayResult<GatewayResponse> {
+        self.create_transaction(
+            transaction_id.to_string(),
+            TransactionRequest {
+                transaction_type: String::from("voidTransaction"),
+                ref_trans_id: Some(transaction_id.to_string()),
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    async fn refund(
+        &self,
+        transaction_id: &str,
+        amount: &Money,
+        masked_card: &str,
+    ) -> GatewayResult<GatewayResponse> {
+        let last_four: String = masked_card.chars().filter(|c| c.is_ascii_digit()).collect();
+
+        self.create_transaction(
+            transaction_id.to_string(),
+            TransactionRequest {
+                transaction_type: String::from("refundTransaction"),
+                amount: Some(amount.to_string()),
+                payment: Some(PaymentDetails::CreditCard(CardDetails {
+                    card_number: last_four,
+                    expiration_date: String::from("XXXX"),
+                    card_code: String::from(""),
+                })),
+                ref_trans_id: Some(transaction_id.to_string()),
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    /// Searches every page of unsettled transactions, then the batches
+    /// settled since `since`, as a transaction may have settled by the time
+    /// it is looked for
+    async fn find_transaction(
+        &self,
+        ref_id: &str,
+        since: DateTime<Utc>,
+    ) -> GatewayResult<Option<FoundTransaction>> {
+        for page in 1.. {
+            let envelope = GetUnsettledTransactionListEnvelope {
+                get_unsettled_transaction_list_request: GetUnsettledTransactionListRequest {
+                    merchant_authentication: self.authentication(),
+                    sorting: TransactionListSorting {
+                        order_by: String::from("submitTimeUTC"),
+                        order_descending: true,
                     },
-                    subsequent_auth_information: SubsequentAuthInformation {
-                        original_auth_amount: "45".to_string(),
-                        original_network_trans_id: "123456789NNNH".to_string(),
-                        reason: "resubmission".to_string(),
+                    paging: TransactionListPaging {
+                        limit: TRANSACTION_LOOKUP_LIMIT,
+                        offset: page,
                     },
-                    authorization_indicator_type: AuthorizationIndicatorType {
-                        authorization_indicator: "final".to_string(),
+                },
+            };
+
+            let response: GetUnsettledTransactionListResponse = self.send(&envelope).await?;
+            check_messages(response.messages)?;
+            let last_page = response.transactions.len() < TRANSACTION_LOOKUP_LIMIT;
+            if let Some(found) = find_in(response.transactions, ref_id) {
+                return Ok(Some(found));
+            }
+            if last_page {
+                break;
+            }
+        }
+
+        let now = Utc::now();
+        let envelope = GetSettledBatchListEnvelope {
+            get_settled_batch_list_request: GetSettledBatchListRequest {
+                merchant_authentication: self.authentication(),
+                first_settlement_date: settlement_date(
+                    since.max(now - chrono::Duration::days(SETTLED_LOOKUP_DAYS)),
+                ),
+                last_settlement_date: settlement_date(now),
+            },
+        };
+
+        let response: GetSettledBatchListResponse = self.send(&envelope).await?;
+        check_messages(response.messages)?;
+
+        for batch in response.batch_list {
+            for page in 1.. {
+                let envelope = GetTransactionListEnvelope {
+                    get_transaction_list_request: GetTransactionListRequest {
+                        merchant_authentication: self.authentication(),
+                        batch_id: batch.batch_id.clone(),
+                        sorting: TransactionListSorting {
+                            order_by: String::from("submitTimeUTC"),
+                            order_descending: true,
+                        },
+                        paging: TransactionListPaging {
+                            limit: TRANSACTION_LOOKUP_LIMIT,
+                            offset: page,
+                        },
                     },
+                };
+
+                let response: GetTransactionListResponse = self.send(&envelope).await?;
+                check_messages(response.messages)?;
+                let last_page = response.transactions.len() < TRANSACTION_LOOKUP_LIMIT;
+                if let Some(found) = find_in(response.transactions, ref_id) {
+                    return Ok(Some(found));
+                }
+                if last_page {
+                    break;
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn transaction_status(&self, transaction_id: &str) -> GatewayResult<TransactionStatus> {
+        let envelope = GetTransactionDetailsEnvelope {
+            get_transaction_details_request: GetTransactionDetailsRequest {
+                merchant_authentication: self.authentication(),
+                trans_id: transaction_id.to_string(),
+            },
+        };
+
+        let response: GetTransactionDetailsResponse = self.send(&envelope).await?;
+        match response.transaction {
+            Some(transaction) => Ok(TransactionStatus::from_code(
+                &transaction.transaction_status,
+            )),
+            None if response
+                .messages
+                .message
+                .iter()
+                .any(|message| message.code == UNKNOWN_TRANSACTION_CODE) =>
+            {
+                Err(GatewayError::UnknownTransaction(transaction_id.to_string()))
+            }
+            None => Err(GatewayError::InvalidResponse(
+                response
+                    .messages
+                    .message
+                    .into_iter()
+                    .map(|message| message.text)
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            )),
+        }
+    }
+
+    async fn save_payment_method(
+        &self,
+        transaction_id: &str,
+        customer: &Customer,
+        profile_id: Option<&str>,
+    ) -> GatewayResult<SavedProfile> {
+        let envelope = CreateProfileFromTransactionEnvelope {
+            create_customer_profile_from_transaction_request: CreateProfileFromTransactionRequest {
+                merchant_authentication: self.authentication(),
+                trans_id: transaction_id.to_string(),
+                customer: match profile_id {
+                    Some(_) => None,
+                    None => Some(ProfileCustomer {
+                        email: customer.email.trim().to_string(),
+                    }),
                 },
+                customer_profile_id: profile_id.map(str::to_string),
             },
         };
 
-        let response = client
-            .post("https://apitest.authorize.net/xml/v1/request.api")
-            .header(CONTENT_TYPE, "application/json")
-            .json(&charge_request)
-            .send()
-            .await?
-            .text()
-            .await?;
+        let response: CreateProfileResponse = self.send(&envelope).await?;
+        response.messages.check()?;
+        let profile_id = response
+            .customer_profile_id
+            .or_else(|| profile_id.map(str::to_string));
+        match (
+            profile_id,
+            response.customer_payment_profile_id_list.into_iter().next(),
+        ) {
+            (Some(profile_id), Some(payment_profile_id)) => Ok(SavedProfile {
+                profile_id,
+                payment_profile_id,
+            }),
+            _ => Err(GatewayError::InvalidResponse(String::from(
+                "No payment profile id came back",
+            ))),
+        }
+    }
 
-        // Authorize.NET returns a ZWSP at the start of the JSON response
-        let response = str::replace(&response, "\u{feff}", "");
+    async fn delete_payment_method(
+        &self,
+        profile_id: &str,
+        payment_profile_id: &str,
+    ) -> GatewayResult<()> {
+        let envelope = DeletePaymentProfileEnvelope {
+            delete_customer_payment_profile_request: DeletePaymentProfileRequest {
+                merchant_authentication: self.authentication(),
+                customer_profile_id: profile_id.to_string(),
+                customer_payment_profile_id: payment_profile_id.to_string(),
+            },
+        };
 
-        let response: ChargeCreditCardResponse = serde_json::from_str(&response)?;
-        Ok(response)
+        let response: DeletePaymentProfileResponse = self.send(&envelope).await?;
+        response.messages.check()
     }
 }
@@ -1,6 +1,5 @@
-use bigdecimal::BigDecimal;
-use std::{io::stdin, str::FromStr};
-use traffic_jam::*;
+use std::io::stdin;
+use traffic_jam::{money::*, *};
 
 fn main() {
     let pool = create_pool();
@@ -26,7 +25,7 @@ fn main() {
     stdin()
         .read_line(&mut price)
         .expect("Unable to read price input");
-    let price: BigDecimal = BigDecimal::from_str(price.trim()).expect("Unable to parse price");
+    let price = Money::parse(&price, Currency::base()).expect("Unable to parse price");
 
     let product = create_product(conn, title, &stock, &price);
     println!(
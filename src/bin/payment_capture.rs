@@ -1,61 +1,53 @@
-use bigdecimal::{BigDecimal, FromPrimitive};
-
 use traffic_jam::{
-    authorize_net::{Address, ChargeCreditCardRequest, CreditCard},
-    ecommerce::{Customer, Discount, Invoice},
-    inventory::Order,
+    gateway::configured_gateway,
+    orders::{capture_order, in_doubt_orders, pending_captures, resolve_order, OrderError},
 };
 
+/// Captures every shipped order whose payment is still only authorized.
+/// Meant to run on a schedule, it picks up captures that failed when the
+/// order shipped as well as orders marked shipped without capturing.
+/// Orders whose payment reply was lost are settled first.
 #[tokio::main]
 async fn main() {
-    let order = Order {
-        id: 123,
-        items: vec![],
-    };
+    let gateway = configured_gateway();
 
-    let customer = Customer {
-        first_name: String::from(""),
-        last_name: String::from(""),
-        email: String::from(""),
-        phone_number: String::from(""),
-        ip_address: String::from(""),
-        billing_address: Address {
-            first_name: String::from(""),
-            last_name: String::from(""),
-            company: String::from(""),
-            address: String::from(""),
-            city: String::from(""),
-            state: String::from(""),
-            zip: String::from(""),
-            country: String::from(""),
-        },
-        shipping_address: Address {
-            first_name: String::from(""),
-            last_name: String::from(""),
-            company: String::from(""),
-            address: String::from(""),
-            city: String::from(""),
-            state: String::from(""),
-            zip: String::from(""),
-            country: String::from(""),
-        },
-        credit_card: CreditCard {
-            card_code: String::from(""),
-            card_number: String::from(""),
-            expiration_date: String::from(""),
-        },
-    };
+    let in_doubt = in_doubt_orders();
+    println!("{} order(s) with an unconfirmed payment", in_doubt.len());
 
-    let discounts: Vec<Discount> = vec![];
+    for order in in_doubt {
+        match resolve_order(gateway.as_ref(), order.id).await {
+            Ok(resolved) => println!(
+                "Payment for order #{} is {}",
+                resolved.id, resolved.payment_status
+            ),
+            Err(OrderError::StillInDoubt(reason)) => {
+                eprintln!("Order #{} is still unconfirmed: {}", order.id, reason)
+            }
+            Err(order_error) => eprintln!(
+                "Unable to resolve order #{}: {}",
+                order.id,
+                order_error.describe()
+            ),
+        }
+    }
 
-    let invoice = Invoice::create(
-        &order,
-        discounts,
-        BigDecimal::from_f32(5.0).unwrap(),
-        BigDecimal::from_f32(0.0715).unwrap(),
-    );
+    let orders = pending_captures();
+    println!("{} order(s) awaiting capture", orders.len());
 
-    ChargeCreditCardRequest::create(&order, invoice, customer)
-        .await
-        .expect("Unable to make payment capture");
+    for order in orders {
+        match capture_order(gateway.as_ref(), order.id).await {
+            Ok(captured) => println!(
+                "Captured {} {} for order #{}",
+                captured.captured_amount, captured.currency, captured.id
+            ),
+            Err(OrderError::CaptureDeferred(reason)) => {
+                eprintln!("Order #{} will be retried: {}", order.id, reason)
+            }
+            Err(order_error) => eprintln!(
+                "Unable to capture order #{}: {}",
+                order.id,
+                order_error.describe()
+            ),
+        }
+    }
 }
@@ -0,0 +1,443 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    ecommerce::{Customer, Invoice},
+    gateway::{
+        FoundTransaction, GatewayError, GatewayResponse, GatewayResult, PaymentGateway,
+        SavedProfile, TransactionStatus,
+    },
+    inventory::Order,
+    money::Money,
+    settings::SETTINGS,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Requests go through as normal
+    Closed,
+    /// The gateway keeps failing, so requests are refused without being sent
+    Open,
+    /// The cool down is over and one request is let through to test the
+    /// gateway
+    HalfOpen,
+}
+
+impl BreakerState {
+    pub fn code(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half_open",
+        }
+    }
+}
+
+/// A snapshot of the breaker for health checks and listeners
+#[derive(Clone, Debug, Serialize)]
+pub struct BreakerStatus {
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub opened_at: Option<DateTime<Utc>>,
+    /// When the next request will be let through to test the gateway
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+struct BreakerInner {
+    state: BreakerState,
+    consecutive_failures: u32,
+    opened: Option<(Instant, DateTime<Utc>)>,
+    last_error: Option<String>,
+    /// Whether the test request of a half open breaker is still out
+    probing: bool,
+}
+
+type Listener = Box<dyn Fn(&BreakerStatus) + Send + Sync>;
+
+/// Wraps a gateway and stops sending it requests once too many in a row
+/// have failed to get through. While open every request fails straight
+/// away with `GatewayError::Unavailable`; after the cool down a single
+/// request is let through and its result decides whether to close again.
+/// Declines and other replies count as the gateway working.
+pub struct CircuitBreaker {
+    gateway: Arc<dyn PaymentGateway>,
+    threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<BreakerInner>,
+    listener: Option<Listener>,
+}
+
+impl CircuitBreaker {
+    /// Uses `GATEWAY_BREAKER_THRESHOLD` and `GATEWAY_BREAKER_COOLDOWN_SECS`
+    pub fn new(gateway: Arc<dyn PaymentGateway>) -> Self {
+        Self::with_limits(
+            gateway,
+            SETTINGS.gateway_breaker_threshold,
+            Duration::from_secs(SETTINGS.gateway_breaker_cooldown_secs),
+        )
+    }
+
+    /// Opens after `threshold` failures in a row and tests the gateway again
+    /// once `cooldown` has passed
+    pub fn with_limits(
+        gateway: Arc<dyn PaymentGateway>,
+        threshold: u32,
+        cooldown: Duration,
+    ) -> Self {
+        CircuitBreaker {
+            gateway,
+            threshold: threshold.max(1),
+            cooldown,
+            inner: Mutex::new(BreakerInner {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                opened: None,
+                last_error: None,
+                probing: false,
+            }),
+            listener: None,
+        }
+    }
+
+    /// Called with the new status every time the breaker changes state
+    pub fn with_listener(
+        mut self,
+        listener: impl Fn(&BreakerStatus) + Send + Sync + 'static,
+    ) -> Self {
+        self.listener = Some(Box::new(listener));
+        self
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        let inner = self.inner.lock().unwrap();
+        self.snapshot(&inner)
+    }
+
+    /// Whether a request sent now would be passed on to the gateway
+    pub fn allows_requests(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::Open => self.cooled_down(&inner),
+            BreakerState::HalfOpen => !inner.probing,
+        }
+    }
+
+    fn snapshot(&self, inner: &BreakerInner) -> BreakerStatus {
+        let cooldown = chrono::Duration::seconds(self.cooldown.as_secs() as i64);
+
+        BreakerStatus {
+            state: inner.state,
+            consecutive_failures: inner.consecutive_failures,
+            opened_at: inner.opened.map(|(_, opened_at)| opened_at),
+            retry_at: match inner.state {
+                BreakerState::Open => inner.opened.map(|(_, opened_at)| opened_at + cooldown),
+                _ => None,
+            },
+            last_error: inner.last_error.clone(),
+        }
+    }
+
+    fn cooled_down(&self, inner: &BreakerInner) -> bool {
+        inner
+            .opened
+            .is_none_or(|(opened, _)| opened.elapsed() >= self.cooldown)
+    }
+
+    /// Moves to `state` and tells the listener. The lock is let go first so
+    /// a listener may look at the breaker.
+    fn change_state(&self, mut inner: std::sync::MutexGuard<BreakerInner>, state: BreakerState) {
+        if inner.state == state {
+            return;
+        }
+
+        inner.state = state;
+        match state {
+            BreakerState::Open => inner.opened = Some((Instant::now(), Utc::now())),
+            BreakerState::Closed => inner.opened = None,
+            BreakerState::HalfOpen => {}
+        }
+        let status = self.snapshot(&inner);
+        drop(inner);
+
+        if let Some(listener) = &self.listener {
+            listener(&status);
+        }
+    }
+
+    fn before_request(&self) -> GatewayResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::Closed => Ok(()),
+            BreakerState::HalfOpen if !inner.probing => {
+                inner.probing = true;
+                Ok(())
+            }
+            BreakerState::Open if self.cooled_down(&inner) => {
+                inner.probing = true;
+                self.change_state(inner, BreakerState::HalfOpen);
+                Ok(())
+            }
+            _ => Err(GatewayError::Unavailable(
+                inner
+                    .last_error
+                    .clone()
+                    .unwrap_or_else(|| String::from("too many failed requests")),
+            )),
+        }
+    }
+
+    fn after_request<T>(&self, result: &GatewayResult<T>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.probing = false;
+
+        match result {
+            Err(gateway_error) if gateway_error.is_outage() => {
+                inner.consecutive_failures += 1;
+                inner.last_error = Some(gateway_error.describe());
+                if inner.state == BreakerState::HalfOpen
+                    || inner.consecutive_failures >= self.threshold
+                {
+                    self.change_state(inner, BreakerState::Open);
+                }
+            }
+            _ => {
+                inner.consecutive_failures = 0;
+                inner.last_error = None;
+                self.change_state(inner, BreakerState::Closed);
+            }
+        }
+    }
+
+    async fn guard<T>(
+        &self,
+        request: impl Future<Output = GatewayResult<T>> + Send,
+    ) -> GatewayResult<T> {
+        self.before_request()?;
+        let mut pending = PendingRequest {
+            breaker: self,
+            finished: false,
+        };
+        let result = request.await;
+        pending.finished = true;
+        self.after_request(&result);
+        result
+    }
+}
+
+/// A request the breaker let through. If it is dropped or panics before its
+/// result comes back, the probe it may have been is given up on so another
+/// request can test the gateway.
+struct PendingRequest<'a> {
+    breaker: &'a CircuitBreaker,
+    finished: bool,
+}
+
+impl Drop for PendingRequest<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Ok(mut inner) = self.breaker.inner.lock() {
+                inner.probing = false;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl PaymentGateway for CircuitBreaker {
+    async fn charge(
+        &self,
+        order: &Order,
+        invoice: &Invoice,
+        customer: &Customer,
+    ) -> GatewayResult<GatewayResponse> {
+        self.guard(self.gateway.charge(order, invoice, customer))
+            .await
+    }
+
+    async fn authorize(
+        &self,
+        order: &Order,
+        invoice: &Invoice,
+        customer: &Customer,
+    ) -> GatewayResult<GatewayResponse> {
+        self.guard(self.gateway.authorize(order, invoice, customer))
+            .await
+    }
+
+    async fn capture(
+        &self,
+        transaction_id: &str,
+        amount: &Money,
+    ) -> GatewayResult<GatewayResponse> {
+        self.guard(self.gateway.capture(transaction_id, amount))
+            .await
+    }
+
+    async fn void(&self, transaction_id: &str) -> GatewayResult<GatewayResponse> {
+        self.guard(self.gateway.void(transaction_id)).await
+    }
+
+    async fn refund(
+        &self,
+        transaction_id: &str,
+        amount: &Money,
+        masked_card: &str,
+    ) -> GatewayResult<GatewayResponse> {
+        self.guard(self.gateway.refund(transaction_id, amount, masked_card))
+            .await
+    }
+
+    async fn transaction_status(&self, transaction_id: &str) -> GatewayResult<TransactionStatus> {
+        self.guard(self.gateway.transaction_status(transaction_id))
+            .await
+    }
+
+    async fn find_transaction(
+        &self,
+        ref_id: &str,
+        since: DateTime<Utc>,
+    ) -> GatewayResult<Option<FoundTransaction>> {
+        self.guard(self.gateway.find_transaction(ref_id, since))
+            .await
+    }
+
+    async fn save_payment_method(
+        &self,
+        transaction_id: &str,
+        customer: &Customer,
+        profile_id: Option<&str>,
+    ) -> GatewayResult<SavedProfile> {
+        self.guard(
+            self.gateway
+                .save_payment_method(transaction_id, customer, profile_id),
+        )
+        .await
+    }
+
+    async fn delete_payment_method(
+        &self,
+        profile_id: &str,
+        payment_profile_id: &str,
+    ) -> GatewayResult<()> {
+        self.guard(
+            self.gateway
+                .delete_payment_method(profile_id, payment_profile_id),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gateway::MockGateway;
+
+    fn breaker(threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker::with_limits(Arc::new(MockGateway::new()), threshold, cooldown)
+    }
+
+    /// Sends a request that succeeds, or fails, as `outcome` says
+    async fn send(breaker: &CircuitBreaker, outcome: &str) -> GatewayResult<()> {
+        breaker
+            .guard(async {
+                match outcome {
+                    "down" => Err(GatewayError::Unreachable(String::from(
+                        "Connection refused",
+                    ))),
+                    "refused" => Err(GatewayError::Rejected(String::from("Declined"))),
+                    _ => Ok(()),
+                }
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn opens_after_the_threshold_of_failures_in_a_row() {
+        let breaker = breaker(2, Duration::from_secs(60));
+
+        assert!(send(&breaker, "down").await.is_err());
+        assert_eq!(breaker.status().state, BreakerState::Closed);
+        assert!(send(&breaker, "down").await.is_err());
+        assert_eq!(breaker.status().state, BreakerState::Open);
+
+        assert!(matches!(
+            send(&breaker, "up").await,
+            Err(GatewayError::Unavailable(_))
+        ));
+        assert!(!breaker.allows_requests());
+    }
+
+    #[tokio::test]
+    async fn replies_from_the_gateway_reset_the_failure_count() {
+        let breaker = breaker(2, Duration::from_secs(60));
+
+        assert!(send(&breaker, "down").await.is_err());
+        assert!(send(&breaker, "refused").await.is_err());
+        assert!(send(&breaker, "down").await.is_err());
+
+        let status = breaker.status();
+        assert_eq!(status.state, BreakerState::Closed);
+        assert_eq!(status.consecutive_failures, 1);
+    }
+
+    #[tokio::test]
+    async fn a_probe_decides_whether_to_close_again() {
+        let breaker = breaker(1, Duration::ZERO);
+
+        assert!(send(&breaker, "down").await.is_err());
+        assert_eq!(breaker.status().state, BreakerState::Open);
+        assert!(send(&breaker, "down").await.is_err());
+        assert_eq!(breaker.status().state, BreakerState::Open);
+
+        assert!(send(&breaker, "up").await.is_ok());
+        assert_eq!(breaker.status().state, BreakerState::Closed);
+    }
+
+    #[tokio::test]
+    async fn tells_the_listener_about_state_changes() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let recorded = seen.clone();
+        let breaker = breaker(1, Duration::ZERO)
+            .with_listener(move |status| recorded.lock().unwrap().push(status.state));
+
+        assert!(send(&breaker, "down").await.is_err());
+        assert!(send(&breaker, "up").await.is_ok());
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                BreakerState::Open,
+                BreakerState::HalfOpen,
+                BreakerState::Closed
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_dropped_probe_lets_another_request_through() {
+        let breaker = breaker(1, Duration::ZERO);
+        assert!(send(&breaker, "down").await.is_err());
+
+        let probe = breaker.guard(std::future::pending::<GatewayResult<()>>());
+        assert!(tokio::time::timeout(Duration::from_millis(10), probe)
+            .await
+            .is_err());
+
+        assert_eq!(breaker.status().state, BreakerState::HalfOpen);
+        assert!(breaker.allows_requests());
+        assert!(send(&breaker, "up").await.is_ok());
+        assert_eq!(breaker.status().state, BreakerState::Closed);
+    }
+}
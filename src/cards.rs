@@ -0,0 +1,378 @@
+use chrono::{Datelike, NaiveDate};
+use serde::Serialize;
+
+use crate::{
+    authorize_net::{CreditCard, OpaqueData},
+    validation::ValidationError,
+};
+
+/// What the hosted payment form labels its tokens with
+const OPAQUE_DATA_DESCRIPTOR: &str = "COMMON.ACCEPT.INAPP.PAYMENT";
+
+/// Card networks the gateway accepts, told apart by the leading digits of
+/// the card number
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CardBrand {
+    Visa,
+    Mastercard,
+    Amex,
+    Discover,
+    DinersClub,
+    Jcb,
+    UnionPay,
+}
+
+impl CardBrand {
+    /// Works out the brand from the issuer identification number
+    pub fn detect(card_number: &str) -> Option<Self> {
+        let prefix = |digits: usize| -> u32 {
+            card_number
+                .get(..digits)
+                .and_then(|prefix| prefix.parse().ok())
+                .unwrap_or(0)
+        };
+
+        // Discover's share of the UnionPay range has to be checked first
+        if (622126..=622925).contains(&prefix(6))
+            || prefix(4) == 6011
+            || (644..=649).contains(&prefix(3))
+            || prefix(2) == 65
+        {
+            Some(CardBrand::Discover)
+        } else if prefix(2) == 62 {
+            Some(CardBrand::UnionPay)
+        } else if prefix(2) == 34 || prefix(2) == 37 {
+            Some(CardBrand::Amex)
+        } else if (3528..=3589).contains(&prefix(4)) {
+            Some(CardBrand::Jcb)
+        } else if (300..=305).contains(&prefix(3)) || matches!(prefix(2), 36 | 38 | 39) {
+            Some(CardBrand::DinersClub)
+        } else if (51..=55).contains(&prefix(2)) || (2221..=2720).contains(&prefix(4)) {
+            Some(CardBrand::Mastercard)
+        } else if prefix(1) == 4 {
+            Some(CardBrand::Visa)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CardBrand::Visa => "Visa",
+            CardBrand::Mastercard => "Mastercard",
+            CardBrand::Amex => "American Express",
+            CardBrand::Discover => "Discover",
+            CardBrand::DinersClub => "Diners Club",
+            CardBrand::Jcb => "JCB",
+            CardBrand::UnionPay => "UnionPay",
+        }
+    }
+
+    /// How many digits a card number of this brand may have
+    pub fn lengths(&self) -> &'static [usize] {
+        match self {
+            CardBrand::Visa => &[13, 16, 19],
+            CardBrand::Mastercard => &[16],
+            CardBrand::Amex => &[15],
+            CardBrand::Discover | CardBrand::Jcb | CardBrand::UnionPay => &[16, 17, 18, 19],
+            CardBrand::DinersClub => &[14, 15, 16, 17, 18, 19],
+        }
+    }
+
+    /// Length of the security code printed on the card
+    pub fn code_length(&self) -> usize {
+        match self {
+            CardBrand::Amex => 4,
+            _ => 3,
+        }
+    }
+
+    /// Some UnionPay cards are issued without a Luhn check digit
+    pub fn uses_luhn(&self) -> bool {
+        !matches!(self, CardBrand::UnionPay)
+    }
+}
+
+/// Whether the number's last digit is the Luhn check digit of the rest
+pub fn luhn_valid(card_number: &str) -> bool {
+    let mut sum = 0;
+    for (index, digit) in card_number.chars().rev().enumerate() {
+        let mut digit = match digit.to_digit(10) {
+            Some(digit) => digit,
+            None => return false,
+        };
+        if index % 2 == 1 {
+            digit *= 2;
+            if digit > 9 {
+                digit -= 9;
+            }
+        }
+        sum += digit;
+    }
+
+    sum % 10 == 0
+}
+
+/// Reads an expiration date given as `YYYY-MM`, `MM/YY`, `MM/YYYY` or
+/// `MMYY` into its year and month
+pub fn parse_expiration(expiration_date: &str) -> Option<(i32, u32)> {
+    let value = expiration_date.trim();
+    if !value
+        .chars()
+        .all(|c| c.is_ascii_digit() || c == '/' || c == '-')
+    {
+        return None;
+    }
+
+    let (year, month) = match (value.len(), value.find(['/', '-'])) {
+        (7, Some(4)) => (&value[..4], &value[5..]),
+        (5, Some(2)) | (7, Some(2)) => (&value[3..], &value[..2]),
+        (4, None) => (&value[2..], &value[..2]),
+        _ => return None,
+    };
+    let year: i32 = year.parse().ok()?;
+    let month: u32 = month.parse().ok()?;
+    let year = if year < 100 { 2000 + year } else { year };
+
+    if (1..=12).contains(&month) {
+        Some((year, month))
+    } else {
+        None
+    }
+}
+
+/// Checks a card before any stock is held for it or the gateway is asked.
+/// Spaces and dashes are taken out of the number, spaces out of the security
+/// code, and a valid expiration date is rewritten as `YYYY-MM`, the form the
+/// gateway expects.
+pub fn validate_card(
+    card: &mut CreditCard,
+    prefix: &str,
+    today: NaiveDate,
+) -> Vec<ValidationError> {
+    let mut errors: Vec<ValidationError> = vec![];
+    let number_field = format!("{}.cardNumber", prefix);
+    let code_field = format!("{}.cardCode", prefix);
+    let expiration_field = format!("{}.expirationDate", prefix);
+
+    card.card_number.retain(|c| c != ' ' && c != '-');
+    let card_number = &card.card_number;
+
+    let brand = if card_number.is_empty() {
+        errors.push(ValidationError::new(
+            &number_field,
+            "This field is required",
+        ));
+        None
+    } else if !card_number.chars().all(|c| c.is_ascii_digit()) {
+        errors.push(ValidationError::new(
+            &number_field,
+            "Card number may only contain digits",
+        ));
+        None
+    } else {
+        match CardBrand::detect(card_number) {
+            Some(brand) => {
+                if !brand.lengths().contains(&card_number.len()) {
+                    errors.push(ValidationError::new(
+                        &number_field,
+                        &format!(
+                            "{} card numbers cannot be {} digits long",
+                            brand.name(),
+                            card_number.len()
+                        ),
+                    ));
+                } else if brand.uses_luhn() && !luhn_valid(card_number) {
+                    errors.push(ValidationError::new(
+                        &number_field,
+                        "Card number is not valid, please check it for typos",
+                    ));
+                }
+                Some(brand)
+            }
+            None => {
+                errors.push(ValidationError::new(
+                    &number_field,
+                    "This card brand is not accepted",
+                ));
+                None
+            }
+        }
+    };
+
+    card.card_code.retain(|c| !c.is_whitespace());
+    let card_code = &card.card_code;
+    if card_code.is_empty() {
+        errors.push(ValidationError::new(&code_field, "This field is required"));
+    } else if !card_code.chars().all(|c| c.is_ascii_digit()) {
+        errors.push(ValidationError::new(
+            &code_field,
+            "Security code may only contain digits",
+        ));
+    } else if let Some(brand) = brand {
+        if card_code.len() != brand.code_length() {
+            errors.push(ValidationError::new(
+                &code_field,
+                &format!(
+                    "{} security codes are {} digits long",
+                    brand.name(),
+                    brand.code_length()
+                ),
+            ));
+        }
+    }
+
+    match parse_expiration(&card.expiration_date) {
+        // Cards stay valid until the end of the month they expire in
+        Some((year, month)) if (year, month) < (today.year(), today.month()) => errors.push(
+            ValidationError::new(&expiration_field, "This card has expired"),
+        ),
+        Some((year, month)) => card.expiration_date = format!("{:04}-{:02}", year, month),
+        None => errors.push(ValidationError::new(
+            &expiration_field,
+            "Expiration date must be given as MM/YY or YYYY-MM",
+        )),
+    }
+
+    errors
+}
+
+/// Checks a token from the hosted payment form. Its contents can only be
+/// checked by the gateway, so this only makes sure one was sent.
+pub fn validate_opaque_data(data: &OpaqueData, prefix: &str) -> Vec<ValidationError> {
+    let mut errors: Vec<ValidationError> = vec![];
+
+    if data.data_descriptor.trim() != OPAQUE_DATA_DESCRIPTOR {
+        errors.push(ValidationError::new(
+            &format!("{}.dataDescriptor", prefix),
+            &format!("Payment tokens must be {}", OPAQUE_DATA_DESCRIPTOR),
+        ));
+    }
+    if data.data_value.trim().is_empty() {
+        errors.push(ValidationError::new(
+            &format!("{}.dataValue", prefix),
+            "This field is required",
+        ));
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn card(card_number: &str, expiration_date: &str, card_code: &str) -> CreditCard {
+        CreditCard {
+            card_number: card_number.to_string(),
+            expiration_date: expiration_date.to_string(),
+            card_code: card_code.to_string(),
+        }
+    }
+
+    fn today() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2026, 5, 14).unwrap()
+    }
+
+    fn fields(errors: &[ValidationError]) -> Vec<&str> {
+        errors.iter().map(|error| error.field.as_str()).collect()
+    }
+
+    #[test]
+    fn luhn_accepts_test_cards_and_catches_typos() {
+        assert!(luhn_valid("4111111111111111"));
+        assert!(luhn_valid("378282246310005"));
+        assert!(luhn_valid("5555555555554444"));
+        assert!(!luhn_valid("4111111111111112"));
+        assert!(!luhn_valid("4111 1111 1111 1111"));
+    }
+
+    #[test]
+    fn detect_tells_brands_apart_by_prefix() {
+        assert_eq!(CardBrand::detect("4111111111111111"), Some(CardBrand::Visa));
+        assert_eq!(
+            CardBrand::detect("5555555555554444"),
+            Some(CardBrand::Mastercard)
+        );
+        assert_eq!(
+            CardBrand::detect("2221000000000009"),
+            Some(CardBrand::Mastercard)
+        );
+        assert_eq!(CardBrand::detect("378282246310005"), Some(CardBrand::Amex));
+        assert_eq!(
+            CardBrand::detect("6011111111111117"),
+            Some(CardBrand::Discover)
+        );
+        assert_eq!(
+            CardBrand::detect("6221260000000000"),
+            Some(CardBrand::Discover)
+        );
+        assert_eq!(
+            CardBrand::detect("6200000000000005"),
+            Some(CardBrand::UnionPay)
+        );
+        assert_eq!(CardBrand::detect("3530111333300000"), Some(CardBrand::Jcb));
+        assert_eq!(
+            CardBrand::detect("30569309025904"),
+            Some(CardBrand::DinersClub)
+        );
+        assert_eq!(CardBrand::detect("9111111111111111"), None);
+        assert_eq!(CardBrand::detect(""), None);
+    }
+
+    #[test]
+    fn parse_expiration_reads_every_accepted_form() {
+        assert_eq!(parse_expiration("2027-03"), Some((2027, 3)));
+        assert_eq!(parse_expiration("03/27"), Some((2027, 3)));
+        assert_eq!(parse_expiration("03/2027"), Some((2027, 3)));
+        assert_eq!(parse_expiration("0327"), Some((2027, 3)));
+        assert_eq!(parse_expiration(" 12/30 "), Some((2030, 12)));
+    }
+
+    #[test]
+    fn parse_expiration_refuses_malformed_dates() {
+        assert_eq!(parse_expiration("13/27"), None);
+        assert_eq!(parse_expiration("00/27"), None);
+        assert_eq!(parse_expiration("3/27"), None);
+        assert_eq!(parse_expiration("March 2027"), None);
+        assert_eq!(parse_expiration(""), None);
+    }
+
+    #[test]
+    fn validate_card_cleans_up_a_valid_card() {
+        let mut valid = card("4111 1111-1111 1111", "05/26", " 123 ");
+
+        assert!(validate_card(&mut valid, "card", today()).is_empty());
+        assert_eq!(valid.card_number, "4111111111111111");
+        assert_eq!(valid.card_code, "123");
+        assert_eq!(valid.expiration_date, "2026-05");
+    }
+
+    #[test]
+    fn validate_card_reports_every_problem() {
+        let mut invalid = card("4111111111111112", "04/26", "12a");
+
+        assert_eq!(
+            fields(&validate_card(&mut invalid, "card", today())),
+            vec!["card.cardNumber", "card.cardCode", "card.expirationDate"]
+        );
+    }
+
+    #[test]
+    fn validate_card_checks_lengths_for_the_brand() {
+        let mut amex = card("378282246310005", "2030-01", "123");
+        let errors = validate_card(&mut amex, "card", today());
+        assert_eq!(fields(&errors), vec!["card.cardCode"]);
+        assert_eq!(
+            errors[0].message,
+            "American Express security codes are 4 digits long"
+        );
+
+        let mut short_visa = card("41111111111", "2030-01", "123");
+        assert_eq!(
+            fields(&validate_card(&mut short_visa, "card", today())),
+            vec!["card.cardNumber"]
+        );
+    }
+}
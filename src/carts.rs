@@ -0,0 +1,287 @@
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use rand::{distributions::Alphanumeric, Rng};
+
+use crate::{
+    db::{DatabaseError, TransactionError, POOL},
+    inventory::Item,
+    models::*,
+    money::Currency,
+    pricing::apply_catalog_prices,
+    settings::SETTINGS,
+    shipping::ShippingMethod,
+};
+
+const CART_ID_LENGTH: usize = 24;
+
+pub enum CartError {
+    NotFound,
+    CheckedOut,
+    ProductNotFound,
+    InsufficientStock(i32),
+    Database(DatabaseError),
+}
+
+impl From<DatabaseError> for CartError {
+    fn from(error: DatabaseError) -> Self {
+        CartError::Database(error)
+    }
+}
+
+impl CartError {
+    pub fn describe(&self) -> String {
+        match self {
+            CartError::NotFound => "This cart does not exist".to_string(),
+            CartError::CheckedOut => "This cart has already been checked out".to_string(),
+            CartError::ProductNotFound => "This product does not exist".to_string(),
+            CartError::InsufficientStock(available) => format!(
+                "Only {} of this product can currently be reserved",
+                available
+            ),
+            CartError::Database(error) => error.describe(),
+        }
+    }
+}
+
+/// Cart ids are random so carts cannot be found by guessing
+pub fn create_cart(
+    currency: Currency,
+    shipping_method: ShippingMethod,
+    coupon_code: Option<&str>,
+) -> Cart {
+    use crate::schema::carts;
+
+    let conn = &mut POOL.get().unwrap();
+    let cart_id: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(CART_ID_LENGTH)
+        .map(char::from)
+        .collect();
+
+    diesel::insert_into(carts::table)
+        .values(&NewCart {
+            id: &cart_id,
+            currency: currency.code(),
+            shipping_method: shipping_method.code(),
+            coupon_code,
+        })
+        .get_result(conn)
+        .expect("Unable to create cart")
+}
+
+pub fn find_cart(cart_id: &str) -> Result<Cart, CartError> {
+    use crate::schema::carts;
+
+    let conn = &mut POOL.get().unwrap();
+    let cart: Cart = carts::table
+        .find(cart_id)
+        .first(conn)
+        .optional()
+        .expect("Unable to look up cart")
+        .ok_or(CartError::NotFound)?;
+
+    if cart.checked_out_at.is_some() {
+        return Err(CartError::CheckedOut);
+    }
+
+    Ok(cart)
+}
+
+pub fn cart_lines(cart_id: &str) -> Vec<CartLine> {
+    use crate::schema::cart_lines;
+
+    let conn = &mut POOL.get().unwrap();
+
+    cart_lines::table
+        .filter(cart_lines::cart_id.eq(cart_id))
+        .order(cart_lines::id)
+        .load::<CartLine>(conn)
+        .expect("Unable to load cart lines")
+}
+
+/// The cart's lines as order items at current catalog prices, which is what
+/// a cart shows and what its checkout is charged
+pub fn cart_items(cart_id: &str) -> Vec<Item> {
+    let mut items = line_items(cart_lines(cart_id));
+    apply_catalog_prices(&mut items);
+    items
+}
+
+fn line_items(lines: Vec<CartLine>) -> Vec<Item> {
+    lines
+        .into_iter()
+        .map(|line| Item {
+            id: line.product_id,
+            qty: line.qty,
+            price: Default::default(),
+        })
+        .collect()
+}
+
+pub fn update_cart(
+    cart: &Cart,
+    currency: Currency,
+    shipping_method: ShippingMethod,
+    coupon_code: Option<&str>,
+) -> Cart {
+    use crate::schema::carts;
+
+    let conn = &mut POOL.get().unwrap();
+
+    diesel::update(carts::table.find(&cart.id))
+        .set((
+            carts::currency.eq(currency.code()),
+            carts::shipping_method.eq(shipping_method.code()),
+            carts::coupon_code.eq(coupon_code),
+            carts::updated_at.eq(Utc::now()),
+        ))
+        .get_result(conn)
+        .expect("Unable to update cart")
+}
+
+/// Sets the quantity of a product in the cart, adding the line if needed.
+///
+/// With `reserve` the line is set aside for `CART_RESERVATION_MINUTES`. The
+/// product row is locked while stock reserved by other carts is counted, so
+/// two carts can never reserve the same units.
+pub fn set_cart_line(
+    cart: &Cart,
+    line_product_id: i32,
+    line_qty: i32,
+    reserve: bool,
+) -> Result<CartLine, CartError> {
+    use crate::schema::{cart_lines, carts, products};
+
+    let conn = &mut POOL.get().unwrap();
+
+    conn.build_transaction()
+        .read_write()
+        .run::<CartLine, TransactionError<CartError>, _>(|conn| {
+            let stock: i32 = products::table
+                .find(line_product_id)
+                .select(products::stock)
+                .for_update()
+                .first(conn)
+                .optional()?
+                .ok_or(CartError::ProductNotFound)?;
+
+            let reserved_until = if reserve {
+                let available = stock - reserved_elsewhere(conn, line_product_id, Some(&cart.id))?;
+                if line_qty > available {
+                    return Err(CartError::InsufficientStock(available.max(0)).into());
+                }
+
+                Some(Utc::now() + Duration::minutes(SETTINGS.cart_reservation_minutes))
+            } else {
+                None
+            };
+
+            let line = diesel::insert_into(cart_lines::table)
+                .values(&NewCartLine {
+                    cart_id: &cart.id,
+                    product_id: &line_product_id,
+                    qty: &line_qty,
+                    reserved_until: reserved_until.as_ref(),
+                })
+                .on_conflict((cart_lines::cart_id, cart_lines::product_id))
+                .do_update()
+                .set((
+                    cart_lines::qty.eq(line_qty),
+                    cart_lines::reserved_until.eq(reserved_until),
+                ))
+                .get_result(conn)?;
+
+            diesel::update(carts::table.find(&cart.id))
+                .set(carts::updated_at.eq(Utc::now()))
+                .execute(conn)?;
+
+            Ok(line)
+        })
+        .map_err(TransactionError::into_domain)
+}
+
+pub fn remove_cart_line(cart: &Cart, line_product_id: i32) {
+    use crate::schema::{cart_lines, carts};
+
+    let conn = &mut POOL.get().unwrap();
+
+    diesel::delete(
+        cart_lines::table
+            .filter(cart_lines::cart_id.eq(&cart.id))
+            .filter(cart_lines::product_id.eq(line_product_id)),
+    )
+    .execute(conn)
+    .expect("Unable to remove cart line");
+
+    diesel::update(carts::table.find(&cart.id))
+        .set(carts::updated_at.eq(Utc::now()))
+        .execute(conn)
+        .expect("Unable to update cart");
+}
+
+/// Closes the cart once its order went through and gives back its
+/// reservations, since the order now holds the stock itself
+/// Marks the cart checked out before its order is placed, so that only one
+/// of several concurrent checkouts of the same cart goes ahead
+pub fn claim_cart(cart_id: &str) -> Result<Cart, CartError> {
+    use crate::schema::carts;
+
+    let conn = &mut POOL.get().unwrap();
+    let claimed: Option<Cart> = diesel::update(
+        carts::table
+            .find(cart_id)
+            .filter(carts::checked_out_at.is_null()),
+    )
+    .set(carts::checked_out_at.eq(Utc::now()))
+    .get_result(conn)
+    .optional()
+    .map_err(DatabaseError::from)?;
+
+    match claimed {
+        Some(cart) => Ok(cart),
+        None => find_cart(cart_id).and(Err(CartError::CheckedOut)),
+    }
+}
+
+/// Gives a claimed cart back when its order could not be placed
+pub fn release_cart(cart_id: &str) {
+    use crate::schema::carts;
+
+    let conn = &mut POOL.get().unwrap();
+
+    diesel::update(carts::table.find(cart_id))
+        .set(carts::checked_out_at.eq(None::<chrono::DateTime<Utc>>))
+        .execute(conn)
+        .expect("Unable to release cart");
+}
+
+/// Lets go of the stock a claimed cart had reserved once its order holds it
+pub fn mark_checked_out(cart_id: &str) {
+    use crate::schema::cart_lines;
+
+    let conn = &mut POOL.get().unwrap();
+
+    diesel::update(cart_lines::table.filter(cart_lines::cart_id.eq(cart_id)))
+        .set(cart_lines::reserved_until.eq(None::<chrono::DateTime<Utc>>))
+        .execute(conn)
+        .expect("Unable to check out cart");
+}
+
+/// Units of a product set aside by unexpired reservations in every cart
+/// other than `cart_id`
+pub fn reserved_elsewhere(
+    conn: &mut PgConnection,
+    reserved_product_id: i32,
+    excluded_cart_id: Option<&str>,
+) -> QueryResult<i32> {
+    use crate::schema::cart_lines::dsl::*;
+
+    let reserved: Option<i64> = cart_lines
+        .filter(product_id.eq(reserved_product_id))
+        .filter(reserved_until.gt(Utc::now()))
+        .filter(cart_id.ne(excluded_cart_id.unwrap_or("")))
+        .select(diesel::dsl::sum(qty))
+        .first(conn)?;
+
+    Ok(reserved.unwrap_or(0) as i32)
+}
@@ -0,0 +1,238 @@
+use chrono::Utc;
+use diesel::prelude::*;
+
+use crate::{
+    db::{DatabaseError, TransactionError, POOL},
+    inventory::Item,
+    models::*,
+    money::{Currency, Money},
+};
+
+pub enum CouponError {
+    NotFound,
+    NotStarted,
+    Expired,
+    Exhausted,
+    CustomerLimitReached,
+    MinimumNotMet(Money),
+    Database(DatabaseError),
+}
+
+impl From<DatabaseError> for CouponError {
+    fn from(error: DatabaseError) -> Self {
+        CouponError::Database(error)
+    }
+}
+
+impl CouponError {
+    pub fn describe(&self) -> String {
+        match self {
+            CouponError::NotFound => "This coupon code does not exist".to_string(),
+            CouponError::NotStarted => "This coupon code is not valid yet".to_string(),
+            CouponError::Expired => "This coupon code has expired".to_string(),
+            CouponError::Exhausted => {
+                "This coupon code has reached its redemption limit".to_string()
+            }
+            CouponError::CustomerLimitReached => {
+                "You have already redeemed this coupon code the maximum number of times".to_string()
+            }
+            CouponError::MinimumNotMet(minimum) => {
+                format!(
+                    "This coupon code requires a subtotal of at least {}",
+                    minimum
+                )
+            }
+            CouponError::Database(error) => error.describe(),
+        }
+    }
+}
+
+/// Looks up a coupon by code and checks that it can currently be used.
+///
+/// The per-customer limit is only checked when `customer_email` is given, so
+/// quotes can show a coupon's discount before the customer is known. Limits
+/// are checked again when the coupon is actually redeemed.
+pub fn find_valid_coupon(
+    coupon_code: &str,
+    customer_email: Option<&str>,
+    items: &[Item],
+) -> Result<Coupon, CouponError> {
+    use crate::schema::coupons::dsl::*;
+
+    let conn = &mut POOL.get().unwrap();
+
+    let coupon: Coupon = coupons
+        .filter(code.eq(normalize_code(coupon_code)))
+        .filter(active.eq(true))
+        .first(conn)
+        .optional()
+        .expect("Unable to look up coupon")
+        .ok_or(CouponError::NotFound)?;
+
+    check_window(&coupon)?;
+    check_global_limit(&coupon)?;
+
+    if let Some(minimum) = &coupon.min_subtotal {
+        let subtotal = items
+            .iter()
+            .fold(Money::zero(Currency::base()), |total, item| {
+                total + item.price.times(item.qty)
+            });
+        if subtotal.amount < *minimum {
+            return Err(CouponError::MinimumNotMet(Money::from(minimum.clone())));
+        }
+    }
+
+    if let Some(email) = customer_email {
+        check_customer_limit(conn, &coupon, email).map_err(TransactionError::into_domain)?;
+    }
+
+    Ok(coupon)
+}
+
+/// Atomically counts a redemption against the coupon. The coupon row is
+/// locked while its limits are re-checked, so concurrent orders can never
+/// redeem a code more times than allowed.
+pub fn redeem_coupon(
+    coupon: &Coupon,
+    customer_email: &str,
+    redeeming_order_id: i32,
+) -> Result<(), CouponError> {
+    use crate::schema::coupon_redemptions;
+    use crate::schema::coupons::dsl::*;
+
+    let conn = &mut POOL.get().unwrap();
+
+    conn.build_transaction()
+        .read_write()
+        .run::<(), TransactionError<CouponError>, _>(|conn| {
+            let locked: Coupon = coupons.find(coupon.id).for_update().first(conn)?;
+
+            check_window(&locked)?;
+            check_global_limit(&locked)?;
+            check_customer_limit(conn, &locked, customer_email)?;
+
+            diesel::update(coupons.find(locked.id))
+                .set(redemptions.eq(redemptions + 1))
+                .execute(conn)?;
+
+            diesel::insert_into(coupon_redemptions::table)
+                .values(&NewCouponRedemption {
+                    coupon_id: &locked.id,
+                    customer_email: &normalize_email(customer_email),
+                    order_id: &redeeming_order_id,
+                })
+                .execute(conn)?;
+
+            Ok(())
+        })
+        .map_err(TransactionError::into_domain)
+}
+
+/// Gives back a redemption when payment for the order did not go through
+pub fn release_coupon(coupon: &Coupon, redeeming_order_id: i32) {
+    use crate::schema::coupon_redemptions;
+    use crate::schema::coupons::dsl::*;
+
+    let conn = &mut POOL.get().unwrap();
+
+    conn.build_transaction()
+        .read_write()
+        .run::<(), diesel::result::Error, _>(|conn| {
+            let removed = diesel::delete(
+                coupon_redemptions::table
+                    .filter(coupon_redemptions::coupon_id.eq(coupon.id))
+                    .filter(coupon_redemptions::order_id.eq(redeeming_order_id)),
+            )
+            .execute(conn)?;
+
+            if removed > 0 {
+                diesel::update(coupons.find(coupon.id))
+                    .set(redemptions.eq(redemptions - removed as i32))
+                    .execute(conn)?;
+            }
+
+            Ok(())
+        })
+        .expect("Unable to release coupon redemption");
+}
+
+fn check_window(coupon: &Coupon) -> Result<(), CouponError> {
+    let now = Utc::now();
+
+    match (&coupon.starts_at, &coupon.ends_at) {
+        (Some(starts_at), _) if now < *starts_at => Err(CouponError::NotStarted),
+        (_, Some(ends_at)) if now >= *ends_at => Err(CouponError::Expired),
+        _ => Ok(()),
+    }
+}
+
+fn check_global_limit(coupon: &Coupon) -> Result<(), CouponError> {
+    match coupon.max_redemptions {
+        Some(limit) if coupon.redemptions >= limit => Err(CouponError::Exhausted),
+        _ => Ok(()),
+    }
+}
+
+fn check_customer_limit(
+    conn: &mut PgConnection,
+    coupon: &Coupon,
+    email: &str,
+) -> Result<(), TransactionError<CouponError>> {
+    use crate::schema::coupon_redemptions::dsl::*;
+
+    let limit = match coupon.max_redemptions_per_customer {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+
+    let redeemed: i64 = coupon_redemptions
+        .filter(coupon_id.eq(coupon.id))
+        .filter(customer_email.eq(normalize_email(email)))
+        .count()
+        .get_result(conn)?;
+
+    if redeemed >= limit as i64 {
+        Err(CouponError::CustomerLimitReached.into())
+    } else {
+        Ok(())
+    }
+}
+
+/// The form codes are stored in, which the database enforces
+fn normalize_code(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
+fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Gives back every coupon redemption made by an order that ended up never
+/// being paid
+pub fn release_order_coupons(redeeming_order_id: i32) {
+    use crate::schema::coupon_redemptions;
+    use crate::schema::coupons::dsl::*;
+
+    let conn = &mut POOL.get().unwrap();
+
+    conn.build_transaction()
+        .read_write()
+        .run::<(), diesel::result::Error, _>(|conn| {
+            let released_coupon_ids: Vec<i32> = diesel::delete(
+                coupon_redemptions::table
+                    .filter(coupon_redemptions::order_id.eq(redeeming_order_id)),
+            )
+            .returning(coupon_redemptions::coupon_id)
+            .get_results(conn)?;
+
+            for released_coupon_id in released_coupon_ids {
+                diesel::update(coupons.find(released_coupon_id))
+                    .set(redemptions.eq(redemptions - 1))
+                    .execute(conn)?;
+            }
+
+            Ok(())
+        })
+        .expect("Unable to release coupon redemptions");
+}
@@ -11,3 +11,50 @@ type PgPool = Pool<ConnectionManager<PgConnection>>;
 lazy_static! {
     pub static ref POOL: PgPool = create_pool();
 }
+
+/// A query that failed. It is logged when it happens and reported to the
+/// client as a 500, so a request never takes its task down with it.
+#[derive(Debug)]
+pub struct DatabaseError;
+
+impl DatabaseError {
+    pub fn describe(&self) -> String {
+        "The request could not be completed, please try again".to_string()
+    }
+}
+
+impl From<diesel::result::Error> for DatabaseError {
+    fn from(error: diesel::result::Error) -> Self {
+        eprintln!("Database error: {}", error);
+        DatabaseError
+    }
+}
+
+/// Why a transaction was rolled back: either the domain refused the change
+/// or the database failed. Both convert with `?` inside the transaction.
+pub enum TransactionError<E> {
+    Domain(E),
+    Database(DatabaseError),
+}
+
+impl<E: From<DatabaseError>> TransactionError<E> {
+    /// Folds a database failure into the domain error
+    pub fn into_domain(self) -> E {
+        match self {
+            TransactionError::Domain(error) => error,
+            TransactionError::Database(error) => E::from(error),
+        }
+    }
+}
+
+impl<E: From<DatabaseError>> From<E> for TransactionError<E> {
+    fn from(error: E) -> Self {
+        TransactionError::Domain(error)
+    }
+}
+
+impl<E> From<diesel::result::Error> for TransactionError<E> {
+    fn from(error: diesel::result::Error) -> Self {
+        TransactionError::Database(error.into())
+    }
+}
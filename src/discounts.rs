@@ -0,0 +1,231 @@
+use bigdecimal::BigDecimal;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{
+    db::POOL,
+    inventory::Item,
+    models::*,
+    money::{Currency, Money, Rounding},
+};
+
+/// A discount that was applied to an invoice, with the amount it took off
+#[derive(Deserialize, Serialize, Clone)]
+pub struct AppliedDiscount {
+    pub rule_id: i32,
+    pub name: String,
+    pub amount: Money,
+}
+
+#[derive(Default)]
+pub struct DiscountRules {
+    rules: Vec<DiscountRule>,
+    tiers: Vec<DiscountTier>,
+}
+
+impl DiscountRules {
+    /// Every active rule that applies without needing a coupon, plus the rule
+    /// linked to `coupon` when one was given
+    pub fn for_order(coupon: Option<&Coupon>) -> Self {
+        use crate::schema::discount_rules::dsl::*;
+
+        let conn = &mut POOL.get().unwrap();
+        let coupon_rule_id = coupon.map(|coupon| coupon.discount_rule_id).unwrap_or(-1);
+        let rules = discount_rules
+            .filter(active.eq(true))
+            .filter(automatic.eq(true).or(id.eq(coupon_rule_id)))
+            .load::<DiscountRule>(conn)
+            .expect("Unable to load discount rules");
+
+        Self::with_tiers(rules)
+    }
+
+    pub fn with_tiers(rules: Vec<DiscountRule>) -> Self {
+        use crate::schema::discount_tiers::dsl::*;
+
+        let conn = &mut POOL.get().unwrap();
+        let loaded_rule_ids: Vec<i32> = rules.iter().map(|rule| rule.id).collect();
+        let tiers = discount_tiers
+            .filter(rule_id.eq_any(loaded_rule_ids))
+            .load::<DiscountTier>(conn)
+            .expect("Unable to load discount tiers");
+
+        DiscountRules { rules, tiers }
+    }
+
+    /// Works out which rules apply to `items` and how much each takes off.
+    ///
+    /// Only the largest discount from each exclusive group is considered. If a
+    /// single non-stackable discount beats every stackable one combined it is
+    /// applied on its own, otherwise all stackable discounts are applied. The
+    /// combined discount never exceeds the value of the goods.
+    pub fn apply(&self, items: &[Item], currency: Currency) -> Vec<AppliedDiscount> {
+        let categories = product_categories(items);
+        let order_total = lines_total(items.iter(), currency);
+
+        let mut best_by_group: HashMap<String, (&DiscountRule, Money)> = HashMap::new();
+        let mut candidates: Vec<(&DiscountRule, Money)> = vec![];
+
+        for rule in &self.rules {
+            if let Some(min_subtotal) = &rule.min_subtotal {
+                if order_total.amount < *min_subtotal {
+                    continue;
+                }
+            }
+
+            let eligible: Vec<&Item> = items
+                .iter()
+                .filter(|item| match (&rule.product_id, &rule.category) {
+                    (Some(product_id), _) => item.id == *product_id,
+                    (None, Some(category)) => {
+                        categories.get(&item.id).and_then(|c| c.as_ref()) == Some(category)
+                    }
+                    (None, None) => true,
+                })
+                .collect();
+            if eligible.is_empty() {
+                continue;
+            }
+
+            let amount = self.rule_amount(rule, &eligible, currency);
+            if amount.is_zero() {
+                continue;
+            }
+
+            match &rule.exclusive_group {
+                Some(group) => match best_by_group.get(group) {
+                    Some((_, best)) if *best >= amount => {}
+                    _ => {
+                        best_by_group.insert(group.clone(), (rule, amount));
+                    }
+                },
+                None => candidates.push((rule, amount)),
+            }
+        }
+        candidates.extend(best_by_group.into_values());
+        candidates.sort_by_key(|(rule, _)| rule.id);
+
+        let stacked_total = candidates
+            .iter()
+            .filter(|(rule, _)| rule.stackable)
+            .fold(Money::zero(currency), |total, (_, amount)| {
+                total + amount.clone()
+            });
+        let best_exclusive = candidates
+            .iter()
+            .filter(|(rule, _)| !rule.stackable)
+            .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap());
+
+        let chosen: Vec<(&DiscountRule, Money)> = match best_exclusive {
+            Some((rule, amount)) if *amount > stacked_total => vec![(rule, amount.clone())],
+            _ => candidates
+                .into_iter()
+                .filter(|(rule, _)| rule.stackable)
+                .collect(),
+        };
+
+        let mut remaining = order_total;
+        let mut applied: Vec<AppliedDiscount> = vec![];
+        for (rule, amount) in chosen {
+            let amount = amount.min(remaining.clone());
+            if amount.is_zero() {
+                break;
+            }
+            remaining -= amount.clone();
+
+            applied.push(AppliedDiscount {
+                rule_id: rule.id,
+                name: rule.name.clone(),
+                amount,
+            });
+        }
+
+        applied
+    }
+
+    fn rule_amount(&self, rule: &DiscountRule, eligible: &[&Item], currency: Currency) -> Money {
+        let eligible_total = lines_total(eligible.iter().copied(), currency);
+        let is_scoped = rule.product_id.is_some() || rule.category.is_some();
+
+        let amount = match rule.kind.as_str() {
+            "percentage" => eligible_total.apply_rate(&percent(&rule.value), Rounding::HalfUp),
+            "fixed" => {
+                let fixed = Money::new(rule.value.clone(), currency);
+                if is_scoped {
+                    // Scoped fixed discounts come off every eligible unit
+                    let units: i32 = eligible.iter().map(|item| item.qty).sum();
+                    fixed.times(units)
+                } else {
+                    fixed
+                }
+            }
+            "buy_x_get_y" => {
+                // value is the percentage off each "get" unit, 100 for free
+                let buy = rule.buy_qty.unwrap_or(1).max(1);
+                let get = rule.get_qty.unwrap_or(1).max(1);
+
+                eligible.iter().fold(Money::zero(currency), |total, item| {
+                    let free_units = (item.qty / (buy + get)) * get;
+                    total
+                        + item
+                            .price
+                            .times(free_units)
+                            .apply_rate(&percent(&rule.value), Rounding::HalfUp)
+                })
+            }
+            "tiered" => {
+                let tier = self
+                    .tiers
+                    .iter()
+                    .filter(|tier| tier.rule_id == rule.id)
+                    .filter(|tier| eligible_total.amount >= tier.min_subtotal)
+                    .max_by(|a, b| a.min_subtotal.cmp(&b.min_subtotal));
+
+                match tier {
+                    Some(tier) => {
+                        eligible_total.apply_rate(&percent(&tier.value), Rounding::HalfUp)
+                    }
+                    None => Money::zero(currency),
+                }
+            }
+            _ => Money::zero(currency),
+        };
+
+        amount.floor_at_zero().min(eligible_total)
+    }
+}
+
+fn percent(value: &BigDecimal) -> BigDecimal {
+    value / BigDecimal::from(100)
+}
+
+fn lines_total<'a>(items: impl Iterator<Item = &'a Item>, currency: Currency) -> Money {
+    items.fold(Money::zero(currency), |total, item| {
+        total + item.price.times(item.qty)
+    })
+}
+
+fn product_categories(items: &[Item]) -> HashMap<i32, Option<String>> {
+    use crate::schema::products::dsl::*;
+
+    let conn = &mut POOL.get().unwrap();
+    let item_ids: Vec<i32> = items.iter().map(|item| item.id).collect();
+
+    products
+        .filter(id.eq_any(item_ids))
+        .select((id, category))
+        .load::<(i32, Option<String>)>(conn)
+        .expect("Unable to load product categories")
+        .into_iter()
+        .collect()
+}
+
+/// Sums every applied discount, used where only the total matters
+pub fn discount_total(discounts: &[AppliedDiscount], currency: Currency) -> Money {
+    discounts
+        .iter()
+        .fold(Money::zero(currency), |total, discount| {
+            total + discount.amount.clone()
+        })
+}
@@ -1,15 +1,20 @@
-use bigdecimal::{BigDecimal, FromPrimitive};
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 use crate::{
-    authorize_net::{Address, AuthorizeNetFee, CreditCard},
+    authorize_net::{Address, AuthorizeNetFee, AuthorizeNetLineItem, Payment},
     db::POOL,
-    inventory::Order,
-    models::*,
+    discounts::{discount_total, AppliedDiscount},
+    exchange::Conversion,
+    inventory::{Item, Order},
+    money::{Currency, Money},
+    shipping::{ShippingMethod, ShippingQuote},
+    tax::{LineTax, TaxRates},
 };
 
-#[derive(Deserialize, Serialize, Clone)]
+/// Only ever read from a request, since it carries payment details
+#[derive(Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Customer {
     pub first_name: String,
@@ -19,74 +24,265 @@ pub struct Customer {
     pub ip_address: String,
     pub billing_address: Address,
     pub shipping_address: Address,
-    pub credit_card: CreditCard,
+    /// Given as either `creditCard` or `opaqueData`
+    #[serde(flatten)]
+    pub payment: Payment,
+    /// Certificate id of a tax exemption the customer has on file
+    #[serde(default)]
+    pub tax_exemption_id: Option<String>,
 }
 
-pub struct Discount {
-    amount: usize,
+/// A single product on an invoice. `discount` is this line's share of the
+/// order's discounts and `tax` is worked out on what is left after it.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct InvoiceLine {
+    pub product_id: i32,
+    pub title: String,
+    pub qty: i32,
+    pub unit_price: Money,
+    pub line_total: Money,
+    pub discount: Money,
+    pub tax: LineTax,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct Invoice {
-    pub subtotal: BigDecimal,
-    pub shipping: BigDecimal,
-    pub taxes: BigDecimal,
-    pub total: BigDecimal,
+    pub lines: Vec<InvoiceLine>,
+    /// Value of the goods before any discounts
+    pub subtotal: Money,
+    pub discounts: Vec<AppliedDiscount>,
+    pub discount_total: Money,
+    pub shipping_method: ShippingMethod,
+    pub shipping: Money,
+    pub shipping_tax: Money,
+    /// Tax on every line plus any tax on shipping
+    pub taxes: Money,
+    /// Tax that was not charged because of `tax_exemption_id`
+    pub exempted_tax: Money,
+    pub tax_exemption_id: Option<String>,
+    /// When set, the subtotal and shipping already include `taxes`
+    pub prices_include_tax: bool,
+    pub total: Money,
+    /// Rate used to turn base currency prices into the currency charged,
+    /// left out when the customer pays in the base currency
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversion: Option<Conversion>,
 }
 
 impl Invoice {
+    /// Builds the invoice in the conversion's target currency. Unit prices,
+    /// discounts and shipping are converted and rounded one by one before
+    /// anything is added up, so every figure shown adds up exactly.
     pub fn create(
         order: &Order,
-        discounts: Vec<Discount>,
-        shipping_fee: BigDecimal,
-        tax_rate: BigDecimal,
+        discounts: Vec<AppliedDiscount>,
+        shipping: ShippingQuote,
+        tax_rates: &TaxRates,
+        conversion: &Conversion,
     ) -> Self {
-        let subtotal = Self::calc_subtotal(&order, discounts);
-        let taxes = Self::calc_taxes(&subtotal, &tax_rate);
+        let order = &Order {
+            items: order
+                .items
+                .iter()
+                .map(|item| Item {
+                    price: conversion.convert(&item.price),
+                    ..item.clone()
+                })
+                .collect(),
+            ..order.clone()
+        };
+        let discounts: Vec<AppliedDiscount> = discounts
+            .into_iter()
+            .map(|discount| AppliedDiscount {
+                amount: conversion.convert(&discount.amount),
+                ..discount
+            })
+            .collect();
+        let shipping_fee = conversion.convert(&shipping.fee);
+        let currency = conversion.to;
+        let subtotal = Self::calc_subtotal(order, currency);
+        let discount_total = discount_total(&discounts, currency).min(subtotal.clone());
+        let discounted = (subtotal.clone() - discount_total.clone()).floor_at_zero();
+
+        let lines = Self::calc_lines(order, &subtotal, &discount_total, tax_rates);
+        let (shipping_tax, shipping_exempted) = tax_rates.shipping_tax(&shipping_fee);
+        let taxes = lines.iter().fold(shipping_tax.clone(), |total, line| {
+            total + line.tax.amount.clone()
+        });
+        let exempted_tax = lines.iter().fold(shipping_exempted, |total, line| {
+            total + line.tax.exempted.clone()
+        });
+
+        // Every component is already rounded to the cent, so the total is
+        // exactly the sum of what is shown on the invoice. Tax-inclusive
+        // prices already contain the tax, so only an exemption changes them.
+        let total = if tax_rates.prices_include_tax {
+            discounted + shipping_fee.clone() - exempted_tax.clone()
+        } else {
+            discounted + shipping_fee.clone() + taxes.clone()
+        };
 
         Invoice {
-            subtotal: subtotal.clone(),
-            shipping: shipping_fee.clone(),
-            taxes: taxes.clone(),
-            total: subtotal.clone() + shipping_fee + taxes.clone(),
+            lines,
+            subtotal,
+            discounts,
+            discount_total,
+            shipping_method: shipping.method,
+            shipping: shipping_fee,
+            shipping_tax,
+            taxes,
+            exempted_tax,
+            tax_exemption_id: tax_rates.exemption_id.clone(),
+            prices_include_tax: tax_rates.prices_include_tax,
+            total,
+            conversion: if conversion.is_identity() {
+                None
+            } else {
+                Some(conversion.clone())
+            },
         }
     }
 
-    fn calc_subtotal(order: &Order, discounts: Vec<Discount>) -> BigDecimal {
-        use crate::schema::products::dsl::*;
-        let conn = &mut POOL.get().unwrap();
-
-        let mut subtotal = BigDecimal::from_f32(0.0).unwrap();
+    /// Item prices are expected to have already been set by the server, see
+    /// `pricing::apply_server_prices`
+    fn calc_subtotal(order: &Order, currency: Currency) -> Money {
+        let mut subtotal = Money::zero(currency);
 
         for item in &order.items {
-            let db_item: Option<Product> = products.find(item.id).first(conn).optional().unwrap();
-            subtotal = subtotal + BigDecimal::from_i32(item.qty).unwrap() * db_item.unwrap().price;
+            subtotal += item.price.times(item.qty);
         }
 
-        for discount in discounts {
-            subtotal = subtotal - BigDecimal::from_usize(discount.amount).unwrap();
+        subtotal
+    }
+
+    /// Spreads the order's discounts over its lines in proportion to each
+    /// line's value. The last line takes whatever is left so the shares always
+    /// add up to exactly `discount_total`.
+    fn allocate_discount(order: &Order, subtotal: &Money, discount_total: &Money) -> Vec<Money> {
+        let mut remaining = discount_total.clone();
+        let mut shares: Vec<Money> = vec![];
+
+        for (index, item) in order.items.iter().enumerate() {
+            let line_total = item.price.times(item.qty);
+            let share = if index == order.items.len() - 1 {
+                remaining.clone()
+            } else if subtotal.is_zero() {
+                Money::zero(subtotal.currency)
+            } else {
+                Money::new(
+                    &discount_total.amount * &line_total.amount / &subtotal.amount,
+                    subtotal.currency,
+                )
+            };
+            let share = share.min(line_total).min(remaining.clone());
+
+            remaining -= share.clone();
+            shares.push(share);
         }
 
-        subtotal
+        shares
+    }
+
+    fn calc_lines(
+        order: &Order,
+        subtotal: &Money,
+        discount_total: &Money,
+        tax_rates: &TaxRates,
+    ) -> Vec<InvoiceLine> {
+        let discount_shares = Self::allocate_discount(order, subtotal, discount_total);
+        let titles = product_titles(order);
+
+        order
+            .items
+            .iter()
+            .zip(discount_shares)
+            .map(|(item, discount)| {
+                let line_total = item.price.times(item.qty);
+                let taxable_amount = (line_total.clone() - discount.clone()).floor_at_zero();
+
+                InvoiceLine {
+                    product_id: item.id,
+                    title: titles.get(&item.id).cloned().unwrap_or_default(),
+                    qty: item.qty,
+                    unit_price: item.price.clone(),
+                    line_total,
+                    discount,
+                    tax: tax_rates.line_tax(item.id, taxable_amount),
+                }
+            })
+            .collect()
     }
 
-    fn calc_taxes(subtotal: &BigDecimal, tax_rate: &BigDecimal) -> BigDecimal {
-        subtotal * tax_rate
+    /// Itemization sent to the gateway. Lines past what the gateway accepts
+    /// are rolled into one summary line, so the items still add up to the
+    /// amount charged.
+    pub fn get_line_items(&self) -> Vec<AuthorizeNetLineItem> {
+        let max_lines = AuthorizeNetLineItem::MAX_LINE_ITEMS;
+        let (listed, rolled_up) = if self.lines.len() > max_lines {
+            self.lines.split_at(max_lines - 1)
+        } else {
+            (&self.lines[..], &[][..])
+        };
+
+        let mut line_items: Vec<AuthorizeNetLineItem> = listed
+            .iter()
+            .map(|line| AuthorizeNetLineItem {
+                item_id: line.product_id.to_string(),
+                name: truncate(&line.title, AuthorizeNetLineItem::MAX_NAME_LENGTH),
+                description: discount_description(&line.discount),
+                quantity: line.qty.to_string(),
+                unit_price: line.unit_price.to_string(),
+                taxable: (!line.tax.amount.is_zero()).to_string(),
+            })
+            .collect();
+
+        if !rolled_up.is_empty() {
+            let currency = self.subtotal.currency;
+            let (total, discount) = rolled_up.iter().fold(
+                (Money::zero(currency), Money::zero(currency)),
+                |(total, discount), line| {
+                    (
+                        total + line.line_total.clone(),
+                        discount + line.discount.clone(),
+                    )
+                },
+            );
+
+            line_items.push(AuthorizeNetLineItem {
+                item_id: String::from("more"),
+                name: format!("{} more items", rolled_up.len()),
+                description: discount_description(&discount),
+                quantity: String::from("1"),
+                unit_price: total.to_string(),
+                taxable: rolled_up
+                    .iter()
+                    .any(|line| !line.tax.amount.is_zero())
+                    .to_string(),
+            });
+        }
+
+        line_items
     }
 
     pub fn get_shipping(&self) -> AuthorizeNetFee {
         AuthorizeNetFee {
             name: String::from("Shipping"),
-            description: String::from("Flat rate shipping fee"),
-            amount: format!("{:.02}", self.shipping),
+            description: String::from(self.shipping_method.describe()),
+            amount: self.shipping.to_string(),
         }
     }
 
     pub fn get_taxes(&self) -> AuthorizeNetFee {
+        let description = match (&self.tax_exemption_id, self.prices_include_tax) {
+            (Some(exemption_id), _) => format!("Tax exempt, certificate {}", exemption_id),
+            (None, true) => String::from("Included in prices"),
+            (None, false) => String::from(""),
+        };
+
         AuthorizeNetFee {
             name: String::from("Taxes"),
-            description: String::from(""),
-            amount: format!("{:.02}", self.taxes),
+            description,
+            amount: self.taxes.to_string(),
         }
     }
 
@@ -98,3 +294,30 @@ impl Invoice {
         }
     }
 }
+
+fn product_titles(order: &Order) -> HashMap<i32, String> {
+    use crate::schema::products::dsl::*;
+
+    let conn = &mut POOL.get().unwrap();
+    let item_ids: Vec<i32> = order.items.iter().map(|item| item.id).collect();
+
+    products
+        .filter(id.eq_any(item_ids))
+        .select((id, title))
+        .load::<(i32, String)>(conn)
+        .expect("Unable to load product titles")
+        .into_iter()
+        .collect()
+}
+
+fn discount_description(discount: &Money) -> String {
+    if discount.is_zero() {
+        String::from("")
+    } else {
+        format!("Discount {}", discount)
+    }
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    text.chars().take(max_chars).collect()
+}
@@ -0,0 +1,124 @@
+use bigdecimal::{BigDecimal, One, Zero};
+use chrono::Utc;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::POOL,
+    models::*,
+    money::{Currency, Money, Rounding},
+};
+
+pub enum ExchangeError {
+    MissingRate(Currency),
+    InvalidRate,
+}
+
+impl ExchangeError {
+    pub fn describe(&self) -> String {
+        match self {
+            ExchangeError::MissingRate(currency) => {
+                format!("Prices are not available in {} yet", currency.code())
+            }
+            ExchangeError::InvalidRate => "Exchange rates must be above zero".to_string(),
+        }
+    }
+}
+
+/// Turns base currency amounts into the currency the customer pays in
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Conversion {
+    pub from: Currency,
+    pub to: Currency,
+    pub rate: BigDecimal,
+}
+
+impl Conversion {
+    /// Used when the customer pays in the base currency
+    pub fn identity(currency: Currency) -> Self {
+        Conversion {
+            from: currency,
+            to: currency,
+            rate: BigDecimal::one(),
+        }
+    }
+
+    /// Looks up the stored rate from the base currency into `presentment`
+    pub fn to_presentment(presentment: Currency) -> Result<Self, ExchangeError> {
+        use crate::schema::exchange_rates::dsl::*;
+
+        let base = Currency::base();
+        if presentment == base {
+            return Ok(Self::identity(base));
+        }
+
+        let conn = &mut POOL.get().unwrap();
+        let stored: ExchangeRate = exchange_rates
+            .find(presentment.code())
+            .first(conn)
+            .optional()
+            .expect("Unable to look up exchange rate")
+            .ok_or(ExchangeError::MissingRate(presentment))?;
+
+        Ok(Conversion {
+            from: base,
+            to: presentment,
+            rate: stored.rate,
+        })
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.from == self.to
+    }
+
+    /// Converts and rounds to the target currency's minor units
+    pub fn convert(&self, amount: &Money) -> Money {
+        assert_eq!(
+            amount.currency,
+            self.from,
+            "Cannot convert {} with a {} rate",
+            amount.currency.code(),
+            self.from.code()
+        );
+
+        Money::rounded(&(&amount.amount * &self.rate), self.to, Rounding::HalfUp)
+    }
+}
+
+pub fn exchange_rates() -> Vec<ExchangeRate> {
+    use crate::schema::exchange_rates::dsl::*;
+
+    let conn = &mut POOL.get().unwrap();
+
+    exchange_rates
+        .order(currency)
+        .load::<ExchangeRate>(conn)
+        .expect("Unable to load exchange rates")
+}
+
+/// Sets how many units of `target` one unit of the base currency buys
+pub fn set_exchange_rate(
+    target: Currency,
+    new_rate: &BigDecimal,
+) -> Result<ExchangeRate, ExchangeError> {
+    use crate::schema::exchange_rates::dsl::*;
+
+    if *new_rate <= BigDecimal::zero() {
+        return Err(ExchangeError::InvalidRate);
+    }
+
+    let conn = &mut POOL.get().unwrap();
+    let values = NewExchangeRate {
+        currency: target.code(),
+        rate: new_rate,
+        updated_at: &Utc::now(),
+    };
+
+    Ok(diesel::insert_into(exchange_rates)
+        .values(&values)
+        .on_conflict(currency)
+        .do_update()
+        .set(&values)
+        .get_result(conn)
+        .expect("Unable to save exchange rate"))
+}
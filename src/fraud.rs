@@ -0,0 +1,342 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use diesel::{pg::Pg, prelude::*};
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::{
+    authorize_net::Payment,
+    db::POOL,
+    ecommerce::{Customer, Invoice},
+    exchange::Conversion,
+    gateway::GatewayResponse,
+    models::*,
+    money::Money,
+    schema::fraud_screenings,
+    settings::{required_secret, SETTINGS},
+};
+
+lazy_static! {
+    /// Key card numbers are fingerprinted with, from `CARD_FINGERPRINT_SECRET`.
+    /// Changing it starts the card velocity counts over.
+    pub static ref CARD_FINGERPRINT_SECRET: Vec<u8> = required_secret("CARD_FINGERPRINT_SECRET");
+}
+
+/// Address check results where neither the street nor the zip code matched
+const AVS_MISMATCH_CODES: [&str; 1] = ["N"];
+/// Address check results where only the street or only the zip code matched
+const AVS_PARTIAL_CODES: [&str; 3] = ["A", "W", "Z"];
+/// Security code check results where the code did not match, or the card
+/// has one and it was left out
+const CVV_FAILURE_CODES: [&str; 2] = ["N", "S"];
+
+/// What a fraud rule does to an order that breaks it. Ordered from least to
+/// most severe, so the worst of several can be picked with `max`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FraudAction {
+    #[default]
+    Allow,
+    /// Take the payment but hold the order until someone has looked at it
+    Review,
+    /// Turn the order away, voiding its payment if one was already taken
+    Reject,
+}
+
+impl FraudAction {
+    pub fn code(&self) -> &'static str {
+        match self {
+            FraudAction::Allow => "allow",
+            FraudAction::Review => "review",
+            FraudAction::Reject => "reject",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        match code.trim() {
+            "allow" => Some(FraudAction::Allow),
+            "review" => Some(FraudAction::Review),
+            "reject" => Some(FraudAction::Reject),
+            _ => None,
+        }
+    }
+}
+
+/// What the fraud rules made of an order, with a reason for every rule it
+/// broke. The reasons are for staff and are never shown to the customer.
+#[derive(Clone, Debug, Default)]
+pub struct Screening {
+    pub action: FraudAction,
+    pub reasons: Vec<String>,
+}
+
+impl Screening {
+    fn flag(&mut self, action: FraudAction, reason: String) {
+        if action == FraudAction::Allow {
+            return;
+        }
+
+        self.action = self.action.max(action);
+        self.reasons.push(reason);
+    }
+
+    pub fn merge(mut self, other: Screening) -> Self {
+        self.action = self.action.max(other.action);
+        self.reasons.extend(other.reasons);
+        self
+    }
+
+    pub fn rejected(&self) -> bool {
+        self.action == FraudAction::Reject
+    }
+
+    pub fn held(&self) -> bool {
+        self.action == FraudAction::Review
+    }
+}
+
+/// What a customer is recognized by when counting their recent orders
+enum Identity {
+    Email(String),
+    IpAddress(String),
+    Card(String),
+}
+
+impl Identity {
+    fn describe(&self) -> &'static str {
+        match self {
+            Identity::Email(_) => "email address",
+            Identity::IpAddress(_) => "IP address",
+            Identity::Card(_) => "card",
+        }
+    }
+
+    fn recent_screenings(&self, since: DateTime<Utc>) -> fraud_screenings::BoxedQuery<'_, Pg> {
+        let query = fraud_screenings::table
+            .filter(fraud_screenings::created_at.ge(since))
+            .into_boxed();
+
+        match self {
+            Identity::Email(email) => query.filter(fraud_screenings::customer_email.eq(email)),
+            Identity::IpAddress(ip_address) => {
+                query.filter(fraud_screenings::ip_address.eq(ip_address))
+            }
+            Identity::Card(fingerprint) => {
+                query.filter(fraud_screenings::card_fingerprint.eq(fingerprint))
+            }
+        }
+    }
+}
+
+/// Checks an order before it is paid for: its value, where it ships and how
+/// many orders and declined payments were seen lately from the same email
+/// address, IP address or card. The screening is stored so later orders
+/// can be counted against it.
+pub fn screen_order(order_id: i32, customer: &Customer, invoice: &Invoice) -> Screening {
+    let mut screening = Screening::default();
+    let total = &invoice.total;
+
+    // Limits are set in the base currency
+    let limit = |threshold: &Option<Money>| {
+        let threshold = threshold.as_ref()?;
+        let conversion = Conversion::to_presentment(total.currency).ok()?;
+        Some(conversion.convert(threshold))
+    };
+    if let Some(max_value) = limit(&SETTINGS.fraud_max_order_value) {
+        if *total > max_value {
+            screening.flag(
+                FraudAction::Reject,
+                format!(
+                    "Order total of {} {} is over the limit of {}",
+                    total,
+                    total.currency.code(),
+                    max_value
+                ),
+            );
+        }
+    }
+    if let Some(review_value) = limit(&SETTINGS.fraud_review_order_value) {
+        if *total > review_value && !screening.rejected() {
+            screening.flag(
+                FraudAction::Review,
+                format!(
+                    "Order total of {} {} is over the review limit of {}",
+                    total,
+                    total.currency.code(),
+                    review_value
+                ),
+            );
+        }
+    }
+
+    let billing_country = customer.billing_address.country.trim();
+    let shipping_country = customer.shipping_address.country.trim();
+    if !billing_country.is_empty()
+        && !shipping_country.is_empty()
+        && !billing_country.eq_ignore_ascii_case(shipping_country)
+    {
+        screening.flag(
+            SETTINGS.fraud_country_mismatch_action,
+            format!(
+                "Billed to {} but shipped to {}",
+                billing_country, shipping_country
+            ),
+        );
+    }
+
+    let email = customer.email.trim().to_lowercase();
+    let ip_address = customer.ip_address.trim().to_string();
+    let card_fingerprint = card_fingerprint(&customer.payment);
+    let mut identities = vec![Identity::Email(email.clone())];
+    if !ip_address.is_empty() {
+        identities.push(Identity::IpAddress(ip_address.clone()));
+    }
+    if let Some(fingerprint) = &card_fingerprint {
+        identities.push(Identity::Card(fingerprint.clone()));
+    }
+
+    let window = SETTINGS.fraud_velocity_window_minutes;
+    let since = Utc::now() - Duration::minutes(window);
+    let conn = &mut POOL.get().unwrap();
+
+    for identity in &identities {
+        if SETTINGS.fraud_max_orders > 0 {
+            let orders: i64 = identity
+                .recent_screenings(since)
+                .count()
+                .get_result(conn)
+                .expect("Unable to count recent orders");
+            if orders >= SETTINGS.fraud_max_orders {
+                screening.flag(
+                    SETTINGS.fraud_velocity_action,
+                    format!(
+                        "{} orders from this {} in the last {} minutes",
+                        orders,
+                        identity.describe(),
+                        window
+                    ),
+                );
+            }
+        }
+
+        if SETTINGS.fraud_max_declined_payments > 0 {
+            let declined: i64 = identity
+                .recent_screenings(since)
+                .filter(fraud_screenings::payment_outcome.eq("declined"))
+                .count()
+                .get_result(conn)
+                .expect("Unable to count recent declined payments");
+            if declined >= SETTINGS.fraud_max_declined_payments {
+                screening.flag(
+                    SETTINGS.fraud_velocity_action,
+                    format!(
+                        "{} declined payments from this {} in the last {} minutes",
+                        declined,
+                        identity.describe(),
+                        window
+                    ),
+                );
+            }
+        }
+    }
+
+    let reasons = encode_reasons(&screening.reasons);
+    let inserted = diesel::insert_into(fraud_screenings::table)
+        .values(&NewFraudScreening {
+            order_id: &order_id,
+            customer_email: &email,
+            ip_address: &ip_address,
+            card_fingerprint: card_fingerprint.as_deref(),
+            decision: screening.action.code(),
+            reasons: reasons.as_deref(),
+        })
+        .execute(conn);
+    if let Err(error) = inserted {
+        eprintln!(
+            "Unable to record fraud screening for order #{}: {}",
+            order_id, error
+        );
+    }
+
+    screening
+}
+
+/// Checks the gateway's address and security code results on a payment it
+/// approved
+pub fn screen_payment(response: &GatewayResponse) -> Screening {
+    let mut screening = Screening::default();
+
+    let avs = response.avs_result_code.trim();
+    if AVS_MISMATCH_CODES.contains(&avs) {
+        screening.flag(
+            SETTINGS.fraud_avs_action,
+            format!("Billing address does not match the card (AVS {})", avs),
+        );
+    } else if AVS_PARTIAL_CODES.contains(&avs) {
+        // A partial match is often just a typo, so it is never rejected
+        screening.flag(
+            SETTINGS.fraud_avs_action.min(FraudAction::Review),
+            format!("Billing address only partly matches the card (AVS {})", avs),
+        );
+    }
+
+    let cvv = response.cvv_result_code.trim();
+    if CVV_FAILURE_CODES.contains(&cvv) {
+        screening.flag(
+            SETTINGS.fraud_cvv_action,
+            format!("Security code does not match the card (CVV {})", cvv),
+        );
+    }
+
+    screening
+}
+
+/// Stores what became of a screened order's payment, so declined payments
+/// count toward the velocity limits, along with the full screening once the
+/// payment has been checked too
+pub fn finish_screening(order_id: i32, screening: &Screening, payment_outcome: &str) {
+    let conn = &mut POOL.get().unwrap();
+    let reasons = encode_reasons(&screening.reasons);
+
+    let updated =
+        diesel::update(fraud_screenings::table.filter(fraud_screenings::order_id.eq(order_id)))
+            .set((
+                fraud_screenings::decision.eq(screening.action.code()),
+                fraud_screenings::reasons.eq(reasons),
+                fraud_screenings::payment_outcome.eq(payment_outcome),
+            ))
+            .execute(conn);
+    if let Err(error) = updated {
+        eprintln!(
+            "Unable to update fraud screening for order #{}: {}",
+            order_id, error
+        );
+    }
+}
+
+/// How reasons are stored on screenings and orders
+pub fn encode_reasons(reasons: &[String]) -> Option<String> {
+    if reasons.is_empty() {
+        return None;
+    }
+
+    Some(serde_json::to_string(reasons).expect("Unable to serialize fraud reasons"))
+}
+
+/// Tells cards apart without storing their numbers. Saved payment methods
+/// are told apart by their gateway id, and payment tokens cannot be.
+fn card_fingerprint(payment: &Payment) -> Option<String> {
+    match payment {
+        Payment::CreditCard(card) => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(&CARD_FINGERPRINT_SECRET)
+                .expect("HMAC accepts keys of any length");
+            mac.update(b"card:");
+            mac.update(card.card_number.as_bytes());
+            Some(URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes()))
+        }
+        Payment::Profile(profile) => Some(format!("profile:{}", profile.payment_profile_id)),
+        Payment::OpaqueData(_) | Payment::SavedMethod(_) => None,
+    }
+}
@@ -0,0 +1,974 @@
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    authorize_net::{AuthorizeNetGateway, Payment, PaymentProfile},
+    ecommerce::{Customer, Invoice},
+    inventory::Order,
+    money::Money,
+    settings::SETTINGS,
+};
+
+/// A message the gateway attached to its reply
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GatewayMessage {
+    pub code: String,
+    pub text: String,
+}
+
+/// The gateway's reply to a transaction request, in the gateway's own terms.
+/// Authorize.NET response codes are used as the common vocabulary: 1 is
+/// approved, 2 declined, 3 error and 4 held for review.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct GatewayResponse {
+    pub transaction_id: String,
+    pub response_code: String,
+    /// Overall result of the API call, `Ok` or `Error`
+    pub result_code: String,
+    pub auth_code: String,
+    pub avs_result_code: String,
+    pub cvv_result_code: String,
+    pub account_number: String,
+    pub account_type: String,
+    pub messages: Vec<GatewayMessage>,
+}
+
+/// What became of a transaction request that reached the gateway
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", tag = "outcome", content = "reason")]
+pub enum PaymentOutcome {
+    Approved,
+    Declined(String),
+    Error(String),
+    /// Authorized, but the gateway's fraud filters want someone to look at it
+    HeldForReview(String),
+}
+
+impl PaymentOutcome {
+    pub fn code(&self) -> &'static str {
+        match self {
+            PaymentOutcome::Approved => "approved",
+            PaymentOutcome::Declined(_) => "declined",
+            PaymentOutcome::Error(_) => "error",
+            PaymentOutcome::HeldForReview(_) => "held_for_review",
+        }
+    }
+}
+
+impl GatewayResponse {
+    pub fn outcome(&self) -> PaymentOutcome {
+        let reason = self.reason();
+
+        match (self.response_code.as_str(), self.result_code.as_str()) {
+            ("1", "Ok") => PaymentOutcome::Approved,
+            ("2", _) => PaymentOutcome::Declined(reason),
+            ("4", _) => PaymentOutcome::HeldForReview(reason),
+            _ => PaymentOutcome::Error(reason),
+        }
+    }
+
+    /// The gateway's explanation of the outcome
+    pub fn reason(&self) -> String {
+        match self.messages.first() {
+            Some(message) => message.text.clone(),
+            None => String::from("No reason given"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionStatus {
+    AuthorizedPendingCapture,
+    CapturedPendingSettlement,
+    Settled,
+    Voided,
+    RefundPendingSettlement,
+    Refunded,
+    Declined,
+    Expired,
+    UnderReview,
+    Other(String),
+}
+
+impl TransactionStatus {
+    pub fn from_code(code: &str) -> Self {
+        match code {
+            "authorizedPendingCapture" => TransactionStatus::AuthorizedPendingCapture,
+            "capturedPendingSettlement" => TransactionStatus::CapturedPendingSettlement,
+            "settledSuccessfully" => TransactionStatus::Settled,
+            "voided" => TransactionStatus::Voided,
+            "refundPendingSettlement" => TransactionStatus::RefundPendingSettlement,
+            "refundSettledSuccessfully" => TransactionStatus::Refunded,
+            "declined" => TransactionStatus::Declined,
+            "expired" => TransactionStatus::Expired,
+            "underReview" | "FDSPendingReview" | "FDSAuthorizedPendingReview" => {
+                TransactionStatus::UnderReview
+            }
+            other => TransactionStatus::Other(other.to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum GatewayError {
+    /// The request never reached the gateway, so it is safe to send again
+    Unreachable(String),
+    /// The request was sent but no reply came back, so it may or may not
+    /// have gone through
+    InDoubt(String),
+    /// A reply came back but could not be understood
+    InvalidResponse(String),
+    UnknownTransaction(String),
+    /// Refused without being sent because the gateway has been failing
+    Unavailable(String),
+    /// The gateway answered but would not do what was asked
+    Rejected(String),
+}
+
+impl GatewayError {
+    pub fn describe(&self) -> String {
+        match self {
+            GatewayError::Unreachable(reason) => {
+                format!("Unable to reach the payment gateway: {}", reason)
+            }
+            GatewayError::InDoubt(reason) => {
+                format!("No reply came back from the payment gateway: {}", reason)
+            }
+            GatewayError::InvalidResponse(reason) => {
+                format!("The payment gateway sent an unexpected reply: {}", reason)
+            }
+            GatewayError::UnknownTransaction(transaction_id) => {
+                format!("The payment gateway has no transaction {}", transaction_id)
+            }
+            GatewayError::Unavailable(reason) => {
+                format!("The payment gateway is unavailable: {}", reason)
+            }
+            GatewayError::Rejected(reason) => {
+                format!("The payment gateway refused the request: {}", reason)
+            }
+        }
+    }
+
+    /// Whether the gateway may have acted on the request
+    pub fn is_in_doubt(&self) -> bool {
+        matches!(
+            self,
+            GatewayError::InDoubt(_) | GatewayError::InvalidResponse(_)
+        )
+    }
+
+    /// Whether the request was never sent, so it can be sent again later
+    pub fn was_not_sent(&self) -> bool {
+        matches!(
+            self,
+            GatewayError::Unreachable(_) | GatewayError::Unavailable(_)
+        )
+    }
+
+    /// Whether this points at the gateway itself being down or broken
+    pub fn is_outage(&self) -> bool {
+        matches!(
+            self,
+            GatewayError::Unreachable(_)
+                | GatewayError::InDoubt(_)
+                | GatewayError::InvalidResponse(_)
+        )
+    }
+}
+
+pub type GatewayResult<T> = Result<T, GatewayError>;
+
+/// Everything the store needs from a card processor
+#[async_trait]
+pub trait PaymentGateway: Send + Sync {
+    /// Authorizes the invoice total and captures it straight away
+    async fn charge(
+        &self,
+        order: &Order,
+        invoice: &Invoice,
+        customer: &Customer,
+    ) -> GatewayResult<GatewayResponse>;
+
+    /// Authorizes the invoice total without capturing it
+    async fn authorize(
+        &self,
+        order: &Order,
+        invoice: &Invoice,
+        customer: &Customer,
+    ) -> GatewayResult<GatewayResponse>;
+
+    /// Captures up to the authorized amount of an earlier authorization
+    async fn capture(&self, transaction_id: &str, amount: &Money)
+        -> GatewayResult<GatewayResponse>;
+
+    /// Cancels a transaction that has not settled yet
+    async fn void(&self, transaction_id: &str) -> GatewayResult<GatewayResponse>;
+
+    /// Returns money from a settled transaction to the card it came from
+    async fn refund(
+        &self,
+        transaction_id: &str,
+        amount: &Money,
+        masked_card: &str,
+    ) -> GatewayResult<GatewayResponse>;
+
+    async fn transaction_status(&self, transaction_id: &str) -> GatewayResult<TransactionStatus>;
+
+    /// Looks up the transaction sent with `ref_id` no earlier than `since`,
+    /// for requests whose reply never arrived
+    async fn find_transaction(
+        &self,
+        ref_id: &str,
+        since: DateTime<Utc>,
+    ) -> GatewayResult<Option<FoundTransaction>>;
+
+    /// Keeps the card or token used for an approved transaction, adding it
+    /// to the customer's profile or starting one when there is none
+    async fn save_payment_method(
+        &self,
+        transaction_id: &str,
+        customer: &Customer,
+        profile_id: Option<&str>,
+    ) -> GatewayResult<SavedProfile>;
+
+    async fn delete_payment_method(
+        &self,
+        profile_id: &str,
+        payment_profile_id: &str,
+    ) -> GatewayResult<()>;
+}
+
+/// Where the gateway keeps a saved payment method
+#[derive(Clone, Debug)]
+pub struct SavedProfile {
+    pub profile_id: String,
+    pub payment_profile_id: String,
+}
+
+/// A transaction found by the reference it was sent with
+#[derive(Clone, Debug)]
+pub struct FoundTransaction {
+    pub transaction_id: String,
+    pub status: TransactionStatus,
+}
+
+impl FoundTransaction {
+    /// Stands in for the reply that never arrived, so a resolved request can
+    /// be handled like any other
+    pub fn into_response(self) -> GatewayResponse {
+        let (response_code, text) = match &self.status {
+            TransactionStatus::AuthorizedPendingCapture
+            | TransactionStatus::CapturedPendingSettlement
+            | TransactionStatus::Settled => ("1", "Approved, found by lookup"),
+            TransactionStatus::Declined => ("2", "Declined, found by lookup"),
+            TransactionStatus::UnderReview => ("4", "Held for review, found by lookup"),
+            _ => ("3", "Not completed, found by lookup"),
+        };
+
+        GatewayResponse {
+            transaction_id: self.transaction_id,
+            response_code: response_code.to_string(),
+            result_code: String::from("Ok"),
+            messages: vec![GatewayMessage {
+                code: response_code.to_string(),
+                text: text.to_string(),
+            }],
+            ..Default::default()
+        }
+    }
+}
+
+/// Finds out what became of a request whose reply was lost. A transaction
+/// can take a moment to show up, so the lookup is repeated before deciding
+/// the request never arrived. `None` means it was never made.
+pub async fn resolve_in_doubt(
+    gateway: &dyn PaymentGateway,
+    ref_id: &str,
+    since: DateTime<Utc>,
+) -> GatewayResult<Option<GatewayResponse>> {
+    let attempts = SETTINGS.gateway_retries + 1;
+
+    for attempt in 1..=attempts {
+        if let Some(found) = gateway.find_transaction(ref_id, since).await? {
+            return Ok(Some(found.into_response()));
+        }
+        if attempt < attempts {
+            tokio::time::sleep(retry_delay(attempt)).await;
+        }
+    }
+
+    Ok(None)
+}
+
+/// Waits twice as long after each failed attempt
+pub fn retry_delay(attempt: u32) -> Duration {
+    Duration::from_millis(250 * 2_u64.pow(attempt.saturating_sub(1).min(6)))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GatewayKind {
+    AuthorizeNet,
+    Mock,
+}
+
+impl GatewayKind {
+    pub fn from_code(code: &str) -> Option<Self> {
+        match code.trim() {
+            "authorize_net" => Some(GatewayKind::AuthorizeNet),
+            "mock" => Some(GatewayKind::Mock),
+            _ => None,
+        }
+    }
+}
+
+/// The gateway picked by `PAYMENT_GATEWAY`
+pub fn configured_gateway() -> Arc<dyn PaymentGateway> {
+    match SETTINGS.payment_gateway {
+        GatewayKind::AuthorizeNet => Arc::new(AuthorizeNetGateway::from_env()),
+        GatewayKind::Mock => Arc::new(MockGateway::with_test_cards()),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum MockOutcome {
+    Approve,
+    Decline(String),
+    HoldForReview,
+    /// The gateway answers with a processing error
+    Error(String),
+    /// The request never reaches the gateway
+    Unreachable(String),
+    /// The gateway approves the request but the reply is lost
+    LostReply,
+}
+
+/// How the mock gateway answers for one card number
+#[derive(Clone, Debug)]
+pub struct MockBehavior {
+    pub outcome: MockOutcome,
+    pub latency: Duration,
+    pub avs_result_code: String,
+    pub cvv_result_code: String,
+}
+
+impl MockBehavior {
+    /// Address and security code checks pass unless set otherwise
+    pub fn new(outcome: MockOutcome) -> Self {
+        MockBehavior {
+            outcome,
+            latency: Duration::ZERO,
+            avs_result_code: String::from("Y"),
+            cvv_result_code: String::from("M"),
+        }
+    }
+
+    pub fn with_latency(self, latency: Duration) -> Self {
+        MockBehavior { latency, ..self }
+    }
+
+    pub fn with_result_codes(self, avs_result_code: &str, cvv_result_code: &str) -> Self {
+        MockBehavior {
+            avs_result_code: avs_result_code.to_string(),
+            cvv_result_code: cvv_result_code.to_string(),
+            ..self
+        }
+    }
+}
+
+/// All the mock keeps of a card: enough to follow its script and mask it in
+/// replies, never the number itself
+#[derive(Clone)]
+struct MockCard {
+    fingerprint: String,
+    last_four: String,
+}
+
+struct MockTransaction {
+    ref_id: String,
+    card: MockCard,
+    status: TransactionStatus,
+}
+
+/// In-process gateway for running the order flow offline. Each card number
+/// can be scripted to approve, decline, error or stall; unscripted cards
+/// are approved.
+pub struct MockGateway {
+    /// Scripts by card fingerprint
+    scripts: Mutex<HashMap<String, MockBehavior>>,
+    transactions: Mutex<HashMap<String, MockTransaction>>,
+    /// Card behind each saved payment profile
+    saved_cards: Mutex<HashMap<String, MockCard>>,
+    /// Random per gateway, so fingerprints mean nothing outside it
+    fingerprint_key: [u8; 32],
+}
+
+impl Default for MockGateway {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockGateway {
+    pub fn new() -> Self {
+        MockGateway {
+            scripts: Mutex::new(HashMap::new()),
+            transactions: Mutex::new(HashMap::new()),
+            saved_cards: Mutex::new(HashMap::new()),
+            fingerprint_key: rand::thread_rng().gen(),
+        }
+    }
+
+    /// Scripts the Authorize.NET sandbox test cards, plus cards that are
+    /// approved but fail the address or security code check
+    pub fn with_test_cards() -> Self {
+        let gateway = Self::new();
+        gateway.script(
+            "4000000000000002",
+            MockBehavior::new(MockOutcome::Decline(String::from(
+                "This transaction has been declined.",
+            ))),
+        );
+        gateway.script(
+            "4000000000000119",
+            MockBehavior::new(MockOutcome::Error(String::from(
+                "An error occurred during processing.",
+            ))),
+        );
+        gateway.script(
+            "4000000000000127",
+            MockBehavior::new(MockOutcome::HoldForReview),
+        );
+        gateway.script(
+            "4000000000000267",
+            MockBehavior::new(MockOutcome::LostReply),
+        );
+        gateway.script(
+            "4000000000000259",
+            MockBehavior::new(MockOutcome::Approve).with_latency(Duration::from_secs(5)),
+        );
+        gateway.script(
+            "4000000000000010",
+            MockBehavior::new(MockOutcome::Approve).with_result_codes("N", "M"),
+        );
+        gateway.script(
+            "4000000000000101",
+            MockBehavior::new(MockOutcome::Approve).with_result_codes("Y", "N"),
+        );
+
+        gateway
+    }
+
+    pub fn script(&self, card_number: &str, behavior: MockBehavior) {
+        self.scripts
+            .lock()
+            .unwrap()
+            .insert(self.card(card_number).fingerprint, behavior);
+    }
+
+    /// Settles a captured transaction, as the processor's nightly batch would
+    pub fn settle(&self, transaction_id: &str) {
+        if let Some(transaction) = self.transactions.lock().unwrap().get_mut(transaction_id) {
+            if transaction.status == TransactionStatus::CapturedPendingSettlement {
+                transaction.status = TransactionStatus::Settled;
+            }
+        }
+    }
+
+    fn behavior_for(&self, card: &MockCard) -> MockBehavior {
+        self.scripts
+            .lock()
+            .unwrap()
+            .get(&card.fingerprint)
+            .cloned()
+            .unwrap_or_else(|| MockBehavior::new(MockOutcome::Approve))
+    }
+
+    fn card(&self, card_number: &str) -> MockCard {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.fingerprint_key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(card_number.as_bytes());
+
+        MockCard {
+            fingerprint: URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes()),
+            last_four: card_number
+                .chars()
+                .skip(card_number.len().saturating_sub(4))
+                .collect(),
+        }
+    }
+
+    /// The card the scripts are looked up by. Opaque data is scripted by its
+    /// token and saved profiles by the card they were saved from.
+    fn payment_card(&self, payment: &Payment) -> GatewayResult<MockCard> {
+        match payment {
+            Payment::CreditCard(card) => Ok(self.card(&card.card_number)),
+            Payment::OpaqueData(data) => Ok(self.card(&data.data_value)),
+            Payment::Profile(PaymentProfile {
+                payment_profile_id, ..
+            }) => self
+                .saved_cards
+                .lock()
+                .unwrap()
+                .get(payment_profile_id)
+                .cloned()
+                .ok_or_else(|| {
+                    GatewayError::Rejected(format!("No payment profile {}", payment_profile_id))
+                }),
+            Payment::SavedMethod(_) => Err(GatewayError::Rejected(String::from(
+                "Saved payment methods must be looked up first",
+            ))),
+        }
+    }
+
+    fn card_for(&self, transaction_id: &str) -> GatewayResult<MockCard> {
+        self.transactions
+            .lock()
+            .unwrap()
+            .get(transaction_id)
+            .map(|transaction| transaction.card.clone())
+            .ok_or_else(|| GatewayError::UnknownTransaction(transaction_id.to_string()))
+    }
+
+    /// Answers as scripted for `card`, moving the transaction to `status`
+    /// when it is approved
+    async fn respond(
+        &self,
+        card: MockCard,
+        ref_id: &str,
+        transaction_id: Option<&str>,
+        status: TransactionStatus,
+    ) -> GatewayResult<GatewayResponse> {
+        let behavior = self.behavior_for(&card);
+        tokio::time::sleep(behavior.latency).await;
+
+        let (response_code, result_code, message) = match &behavior.outcome {
+            MockOutcome::Approve | MockOutcome::LostReply => {
+                ("1", "Ok", "This transaction has been approved.")
+            }
+            MockOutcome::Decline(reason) => ("2", "Error", reason.as_str()),
+            MockOutcome::Error(reason) => ("3", "Error", reason.as_str()),
+            MockOutcome::HoldForReview => ("4", "Ok", "This transaction is being held for review."),
+            MockOutcome::Unreachable(reason) => {
+                return Err(GatewayError::Unreachable(reason.clone()))
+            }
+        };
+
+        let transaction_id = transaction_id.map(str::to_string).unwrap_or_else(|| {
+            rand::thread_rng()
+                .gen_range(10_000_000..100_000_000)
+                .to_string()
+        });
+        let recorded_status = match response_code {
+            "1" => Some(status),
+            "2" => Some(TransactionStatus::Declined),
+            "4" => Some(TransactionStatus::UnderReview),
+            _ => None,
+        };
+        if let Some(recorded_status) = recorded_status {
+            let mut transactions = self.transactions.lock().unwrap();
+            match transactions.get_mut(&transaction_id) {
+                // Follow-up requests only change the status of the original
+                Some(transaction) if response_code == "1" => transaction.status = recorded_status,
+                Some(_) => {}
+                None => {
+                    transactions.insert(
+                        transaction_id.clone(),
+                        MockTransaction {
+                            ref_id: ref_id.to_string(),
+                            card: card.clone(),
+                            status: recorded_status,
+                        },
+                    );
+                }
+            }
+        }
+
+        if let MockOutcome::LostReply = behavior.outcome {
+            return Err(GatewayError::InDoubt(String::from(
+                "The connection closed before a reply arrived",
+            )));
+        }
+
+        Ok(GatewayResponse {
+            transaction_id,
+            response_code: response_code.to_string(),
+            result_code: result_code.to_string(),
+            auth_code: if response_code == "1" {
+                String::from("MOCK01")
+            } else {
+                String::from("")
+            },
+            avs_result_code: behavior.avs_result_code.clone(),
+            cvv_result_code: behavior.cvv_result_code.clone(),
+            account_number: format!("XXXX{}", card.last_four),
+            account_type: String::from("Visa"),
+            messages: vec![GatewayMessage {
+                code: response_code.to_string(),
+                text: message.to_string(),
+            }],
+        })
+    }
+}
+
+#[async_trait]
+impl PaymentGateway for MockGateway {
+    async fn charge(
+        &self,
+        order: &Order,
+        _invoice: &Invoice,
+        customer: &Customer,
+    ) -> GatewayResult<GatewayResponse> {
+        self.respond(
+            self.payment_card(&customer.payment)?,
+            &order.id.to_string(),
+            None,
+            TransactionStatus::CapturedPendingSettlement,
+        )
+        .await
+    }
+
+    async fn authorize(
+        &self,
+        order: &Order,
+        _invoice: &Invoice,
+        customer: &Customer,
+    ) -> GatewayResult<GatewayResponse> {
+        self.respond(
+            self.payment_card(&customer.payment)?,
+            &order.id.to_string(),
+            None,
+            TransactionStatus::AuthorizedPendingCapture,
+        )
+        .await
+    }
+
+    async fn capture(
+        &self,
+        transaction_id: &str,
+        _amount: &Money,
+    ) -> GatewayResult<GatewayResponse> {
+        let card = self.card_for(transaction_id)?;
+        self.respond(
+            card,
+            transaction_id,
+            Some(transaction_id),
+            TransactionStatus::CapturedPendingSettlement,
+        )
+        .await
+    }
+
+    async fn void(&self, transaction_id: &str) -> GatewayResult<GatewayResponse> {
+        let card = self.card_for(transaction_id)?;
+        self.respond(
+            card,
+            transaction_id,
+            Some(transaction_id),
+            TransactionStatus::Voided,
+        )
+        .await
+    }
+
+    async fn refund(
+        &self,
+        transaction_id: &str,
+        _amount: &Money,
+        _masked_card: &str,
+    ) -> GatewayResult<GatewayResponse> {
+        let card = self.card_for(transaction_id)?;
+        self.respond(
+            card,
+            transaction_id,
+            Some(transaction_id),
+            TransactionStatus::RefundPendingSettlement,
+        )
+        .await
+    }
+
+    async fn transaction_status(&self, transaction_id: &str) -> GatewayResult<TransactionStatus> {
+        self.transactions
+            .lock()
+            .unwrap()
+            .get(transaction_id)
+            .map(|transaction| transaction.status.clone())
+            .ok_or_else(|| GatewayError::UnknownTransaction(transaction_id.to_string()))
+    }
+
+    async fn find_transaction(
+        &self,
+        ref_id: &str,
+        _since: DateTime<Utc>,
+    ) -> GatewayResult<Option<FoundTransaction>> {
+        Ok(self
+            .transactions
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, transaction)| transaction.ref_id == ref_id)
+            .map(|(transaction_id, transaction)| FoundTransaction {
+                transaction_id: transaction_id.clone(),
+                status: transaction.status.clone(),
+            }))
+    }
+
+    async fn save_payment_method(
+        &self,
+        transaction_id: &str,
+        customer: &Customer,
+        profile_id: Option<&str>,
+    ) -> GatewayResult<SavedProfile> {
+        let card = self.card_for(transaction_id)?;
+        let profile_id = profile_id
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("mock-{}", customer.email.trim().to_lowercase()));
+        let payment_profile_id = rand::thread_rng()
+            .gen_range(10_000_000..100_000_000)
+            .to_string();
+        self.saved_cards
+            .lock()
+            .unwrap()
+            .insert(payment_profile_id.clone(), card);
+
+        Ok(SavedProfile {
+            profile_id,
+            payment_profile_id,
+        })
+    }
+
+    async fn delete_payment_method(
+        &self,
+        _profile_id: &str,
+        payment_profile_id: &str,
+    ) -> GatewayResult<()> {
+        self.saved_cards.lock().unwrap().remove(payment_profile_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        authorize_net::{Address, CreditCard},
+        money::Currency,
+        shipping::ShippingMethod,
+    };
+
+    fn order() -> Order {
+        Order {
+            id: 1001,
+            items: vec![],
+            dropped_items: vec![],
+            shipping_method: ShippingMethod::Standard,
+            currency: Currency::base(),
+            cart_id: None,
+            invoice_number: None,
+            transaction_id: None,
+            payment_under_review: false,
+            payment_in_doubt: false,
+            payment_deferred: false,
+            payment_method_id: None,
+            customer_token: None,
+            order_token: None,
+        }
+    }
+
+    fn invoice() -> Invoice {
+        let zero = Money::zero(Currency::base());
+        Invoice {
+            lines: vec![],
+            subtotal: zero.clone(),
+            discounts: vec![],
+            discount_total: zero.clone(),
+            shipping_method: ShippingMethod::Standard,
+            shipping: zero.clone(),
+            shipping_tax: zero.clone(),
+            taxes: zero.clone(),
+            exempted_tax: zero.clone(),
+            tax_exemption_id: None,
+            prices_include_tax: false,
+            total: zero,
+            conversion: None,
+        }
+    }
+
+    fn customer(card_number: &str) -> Customer {
+        let address = Address {
+            first_name: String::from("Ada"),
+            last_name: String::from("Lovelace"),
+            company: String::from(""),
+            address: String::from("12 Analytical Way"),
+            city: String::from("Springfield"),
+            state: String::from("IL"),
+            zip: String::from("62701"),
+            country: String::from("US"),
+        };
+
+        Customer {
+            first_name: String::from("Ada"),
+            last_name: String::from("Lovelace"),
+            email: String::from("ada@example.com"),
+            phone_number: String::from("555 0100 200"),
+            ip_address: String::from("127.0.0.1"),
+            billing_address: address.clone(),
+            shipping_address: address,
+            payment: Payment::CreditCard(CreditCard {
+                card_number: card_number.to_string(),
+                expiration_date: String::from("2030-12"),
+                card_code: String::from("123"),
+            }),
+            tax_exemption_id: None,
+        }
+    }
+
+    fn response(response_code: &str, result_code: &str) -> GatewayResponse {
+        GatewayResponse {
+            response_code: response_code.to_string(),
+            result_code: result_code.to_string(),
+            messages: vec![GatewayMessage {
+                code: response_code.to_string(),
+                text: String::from("Gateway says so"),
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn outcome_follows_the_response_code() {
+        assert_eq!(response("1", "Ok").outcome(), PaymentOutcome::Approved);
+        assert_eq!(
+            response("2", "Error").outcome(),
+            PaymentOutcome::Declined(String::from("Gateway says so"))
+        );
+        assert_eq!(
+            response("4", "Ok").outcome(),
+            PaymentOutcome::HeldForReview(String::from("Gateway says so"))
+        );
+        assert_eq!(
+            response("3", "Error").outcome(),
+            PaymentOutcome::Error(String::from("Gateway says so"))
+        );
+    }
+
+    #[test]
+    fn outcome_is_an_error_when_an_approval_comes_with_a_failed_call() {
+        assert_eq!(
+            response("1", "Error").outcome(),
+            PaymentOutcome::Error(String::from("Gateway says so"))
+        );
+    }
+
+    #[test]
+    fn reason_falls_back_when_the_gateway_gives_none() {
+        let response = GatewayResponse {
+            response_code: String::from("2"),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            response.outcome(),
+            PaymentOutcome::Declined(String::from("No reason given"))
+        );
+    }
+
+    #[tokio::test]
+    async fn mock_approves_unscripted_cards() {
+        let gateway = MockGateway::with_test_cards();
+        let response = gateway
+            .charge(&order(), &invoice(), &customer("4111111111111111"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.outcome(), PaymentOutcome::Approved);
+        assert_eq!(response.account_number, "XXXX1111");
+        assert_eq!(
+            gateway
+                .transaction_status(&response.transaction_id)
+                .await
+                .unwrap(),
+            TransactionStatus::CapturedPendingSettlement
+        );
+    }
+
+    #[tokio::test]
+    async fn mock_declines_the_declined_test_card() {
+        let gateway = MockGateway::with_test_cards();
+        let response = gateway
+            .authorize(&order(), &invoice(), &customer("4000000000000002"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.outcome().code(), "declined");
+        assert_eq!(
+            gateway
+                .transaction_status(&response.transaction_id)
+                .await
+                .unwrap(),
+            TransactionStatus::Declined
+        );
+    }
+
+    #[tokio::test]
+    async fn mock_holds_the_review_test_card() {
+        let gateway = MockGateway::with_test_cards();
+        let response = gateway
+            .authorize(&order(), &invoice(), &customer("4000000000000127"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.outcome().code(), "held_for_review");
+        assert_eq!(
+            gateway
+                .transaction_status(&response.transaction_id)
+                .await
+                .unwrap(),
+            TransactionStatus::UnderReview
+        );
+    }
+
+    #[tokio::test]
+    async fn mock_loses_the_reply_but_keeps_the_charge() {
+        let gateway = MockGateway::with_test_cards();
+        let error = gateway
+            .charge(&order(), &invoice(), &customer("4000000000000267"))
+            .await
+            .unwrap_err();
+        assert!(error.is_in_doubt());
+
+        let found = resolve_in_doubt(&gateway, &order().id.to_string(), Utc::now())
+            .await
+            .unwrap()
+            .expect("The lost charge can be found by its reference");
+        assert_eq!(found.outcome(), PaymentOutcome::Approved);
+    }
+
+    #[tokio::test]
+    async fn mock_reports_unreachable_requests_as_not_sent() {
+        let gateway = MockGateway::new();
+        gateway.script(
+            "4111111111111111",
+            MockBehavior::new(MockOutcome::Unreachable(String::from("Connection refused"))),
+        );
+
+        let error = gateway
+            .charge(&order(), &invoice(), &customer("4111111111111111"))
+            .await
+            .unwrap_err();
+        assert!(error.was_not_sent());
+        assert!(gateway
+            .find_transaction(&order().id.to_string(), Utc::now())
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
@@ -2,13 +2,23 @@ use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-use crate::{db::POOL, ecommerce::Customer, models::*};
+use crate::{
+    authorize_net::Address,
+    carts::reserved_elsewhere,
+    db::POOL,
+    ecommerce::Customer,
+    models::*,
+    money::{Currency, Money},
+    shipping::ShippingMethod,
+};
 
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Item {
     pub id: i32,
     pub qty: i32,
-    pub price: f32,
+    /// Price the client was shown; replaced by the server price before use
+    #[serde(default)]
+    pub price: Money,
 }
 
 #[derive(Clone)]
@@ -36,7 +46,9 @@ impl LockedInventory {
 
                     match result_product {
                         Some(product) => {
-                            if product.stock < 0 {
+                            let reserved =
+                                reserved_elsewhere(conn, product.id, order.cart_id.as_deref())?;
+                            if product.stock < reserved {
                                 return Err(diesel::result::Error::RollbackTransaction);
                             }
                         }
@@ -53,7 +65,76 @@ impl LockedInventory {
             Ok(_) => {
                 self.items.insert(order.id, order.items.clone());
 
-                return true;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Holds as much of each order line as current stock allows. Lines that
+    /// can only be partly held are trimmed, and everything that could not be
+    /// held is moved to `order.dropped_items`. Returns false when nothing at
+    /// all could be held.
+    pub fn hold_available_items(&mut self, order: &mut Order) -> bool {
+        use crate::schema::products::dsl::*;
+
+        let conn = &mut POOL.get().unwrap();
+
+        let hold_transaction = conn
+            .build_transaction()
+            .read_write()
+            .run::<(Vec<Item>, Vec<Item>), diesel::result::Error, _>(|conn| {
+                let mut held_items: Vec<Item> = vec![];
+                let mut dropped_items: Vec<Item> = vec![];
+
+                for order_item in &order.items {
+                    let result_product: Option<Product> = products
+                        .find(order_item.id)
+                        .for_update()
+                        .first(conn)
+                        .optional()?;
+
+                    let available = match result_product {
+                        Some(product) => (product.stock
+                            - reserved_elsewhere(conn, product.id, order.cart_id.as_deref())?)
+                        .max(0),
+                        None => 0,
+                    };
+                    let held_qty = order_item.qty.min(available);
+
+                    if held_qty > 0 {
+                        diesel::update(products.find(order_item.id))
+                            .set(stock.eq(stock - held_qty))
+                            .execute(conn)?;
+
+                        held_items.push(Item {
+                            qty: held_qty,
+                            ..order_item.clone()
+                        });
+                    }
+
+                    if held_qty < order_item.qty {
+                        dropped_items.push(Item {
+                            qty: order_item.qty - held_qty,
+                            ..order_item.clone()
+                        });
+                    }
+                }
+
+                if held_items.is_empty() {
+                    return Err(diesel::result::Error::RollbackTransaction);
+                }
+
+                Ok((held_items, dropped_items))
+            });
+
+        match hold_transaction {
+            Ok((held_items, dropped_items)) => {
+                self.items.insert(order.id, held_items.clone());
+                order.items = held_items;
+                order.dropped_items = dropped_items;
+
+                true
             }
             Err(_) => false,
         }
@@ -90,10 +171,87 @@ impl LockedInventory {
 pub struct Order {
     pub id: usize,
     pub items: Vec<Item>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dropped_items: Vec<Item>,
+    pub shipping_method: ShippingMethod,
+    /// Currency the customer is charged in
+    pub currency: Currency,
+    /// Cart the order was checked out from, whose reservations it may use
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cart_id: Option<String>,
+    /// Number of the invoice issued once payment went through
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invoice_number: Option<String>,
+    /// Gateway transaction the order was paid with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transaction_id: Option<String>,
+    /// The payment was authorized but is waiting on a fraud review
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub payment_under_review: bool,
+    /// No reply came back from the gateway, so the payment is checked again
+    /// later before the order is either kept or undone
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub payment_in_doubt: bool,
+    /// Taken while the payment gateway was down, to be charged once it is
+    /// back
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub payment_deferred: bool,
+    /// The card used, once saved for next time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payment_method_id: Option<i32>,
+    /// Lets the customer use their saved payment methods, handed out when
+    /// one is saved
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub customer_token: Option<String>,
+    /// Lets whoever placed the order look it and its receipt up
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order_token: Option<String>,
+}
+
+/// Value of the given items at their current prices, before any discounts
+pub fn items_total(items: &[Item], currency: Currency) -> Money {
+    items.iter().fold(Money::zero(currency), |total, item| {
+        total + item.price.times(item.qty)
+    })
 }
 
 #[derive(Clone, Deserialize)]
 pub struct CreateOrderRequest {
     pub customer: Customer,
     pub items: Vec<Item>,
+    /// Accept whatever part of the order can be held instead of failing the
+    /// whole order when some lines are out of stock
+    #[serde(default)]
+    pub allow_partial: bool,
+    /// Token from `POST /quote` whose prices should be honored
+    #[serde(default)]
+    pub quote_token: Option<String>,
+    #[serde(default)]
+    pub coupon_code: Option<String>,
+    #[serde(default)]
+    pub shipping_method: ShippingMethod,
+    /// Currency to charge in, the store's base currency when not given
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    /// Keep the card or payment token for later orders once it is approved
+    #[serde(default)]
+    pub save_payment_method: bool,
+    /// Token from an earlier order that saved a payment method. Needed to
+    /// pay with a saved method or to save another one.
+    #[serde(default)]
+    pub customer_token: Option<String>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct QuoteRequest {
+    pub items: Vec<Item>,
+    /// Taxes are only included in the quote when the destination is known
+    #[serde(default)]
+    pub shipping_address: Option<Address>,
+    #[serde(default)]
+    pub coupon_code: Option<String>,
+    #[serde(default)]
+    pub shipping_method: ShippingMethod,
+    #[serde(default)]
+    pub currency: Option<Currency>,
 }
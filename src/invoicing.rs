@@ -0,0 +1,278 @@
+use bigdecimal::{BigDecimal, Zero};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    authorize_net::Address,
+    db::{DatabaseError, TransactionError, POOL},
+    ecommerce::{Customer, Invoice},
+    models::*,
+    money::{Currency, Money},
+};
+
+const INVOICE_COUNTER: &str = "invoice";
+const CREDIT_NOTE_COUNTER: &str = "credit_note";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CreditReason {
+    Refund,
+    Cancellation,
+}
+
+impl CreditReason {
+    pub fn code(&self) -> &'static str {
+        match self {
+            CreditReason::Refund => "refund",
+            CreditReason::Cancellation => "cancellation",
+        }
+    }
+}
+
+pub enum CreditNoteError {
+    InvoiceNotFound,
+    CurrencyMismatch,
+    InvalidAmount,
+    ExceedsBalance(Money),
+    Database(DatabaseError),
+}
+
+impl From<DatabaseError> for CreditNoteError {
+    fn from(error: DatabaseError) -> Self {
+        CreditNoteError::Database(error)
+    }
+}
+
+impl CreditNoteError {
+    pub fn describe(&self) -> String {
+        match self {
+            CreditNoteError::InvoiceNotFound => "This invoice does not exist".to_string(),
+            CreditNoteError::CurrencyMismatch => {
+                "Credit notes must be in the currency of the invoice".to_string()
+            }
+            CreditNoteError::InvalidAmount => "Credit notes must be for more than zero".to_string(),
+            CreditNoteError::ExceedsBalance(balance) => format!(
+                "Only {} {} is left to credit on this invoice",
+                balance,
+                balance.currency.code()
+            ),
+            CreditNoteError::Database(error) => error.describe(),
+        }
+    }
+}
+
+/// An issued invoice as it was at the time of issue, along with every credit
+/// note raised against it since
+#[derive(Serialize)]
+pub struct InvoiceRecord {
+    pub invoice_number: String,
+    pub order_id: i32,
+    pub customer_email: String,
+    pub issued_at: DateTime<Utc>,
+    pub invoice: Invoice,
+    pub credit_notes: Vec<CreditNote>,
+    /// Invoice total less everything already credited
+    pub balance: Money,
+    pub billing_address: Option<Address>,
+    pub shipping_address: Option<Address>,
+    /// Card on the invoice, or for payments taken after the invoice was
+    /// issued, the card the gateway reported
+    pub masked_card: Option<String>,
+}
+
+/// Stores `invoice` under the next invoice number. The counter row is locked
+/// for the duration of the transaction, so numbers are handed out in order
+/// and a rolled back issue never leaves a gap. Nothing is kept of how the
+/// customer paid beyond `masked_card`.
+pub fn issue_invoice(
+    order_id: i32,
+    customer: &Customer,
+    masked_card: Option<&str>,
+    invoice: &Invoice,
+) -> QueryResult<IssuedInvoice> {
+    use crate::schema::invoices;
+
+    let conn = &mut POOL.get().unwrap();
+    let document = serde_json::to_string(invoice).expect("Unable to serialize invoice");
+    let billing_address =
+        serde_json::to_string(&customer.billing_address).expect("Unable to serialize address");
+    let shipping_address =
+        serde_json::to_string(&customer.shipping_address).expect("Unable to serialize address");
+
+    conn.build_transaction().read_write().run(|conn| {
+        let number = next_document_number(conn, INVOICE_COUNTER)?;
+
+        diesel::insert_into(invoices::table)
+            .values(&NewIssuedInvoice {
+                invoice_number: &format!("INV-{:06}", number),
+                order_id: &order_id,
+                customer_email: &customer.email,
+                currency: invoice.total.currency.code(),
+                total: &invoice.total.amount,
+                document: &document,
+                billing_address: Some(&billing_address),
+                shipping_address: Some(&shipping_address),
+                masked_card,
+            })
+            .get_result(conn)
+    })
+}
+
+pub fn find_invoice(number: &str) -> Option<InvoiceRecord> {
+    use crate::schema::invoices;
+
+    let conn = &mut POOL.get().unwrap();
+    let issued: IssuedInvoice = invoices::table
+        .filter(invoices::invoice_number.eq(number.trim().to_uppercase()))
+        .first(conn)
+        .optional()
+        .expect("Unable to look up invoice")?;
+
+    Some(load_record(conn, issued))
+}
+
+/// The most recent invoice issued for an order
+pub fn find_order_invoice(order_id: i32) -> Option<InvoiceRecord> {
+    use crate::schema::invoices;
+
+    let conn = &mut POOL.get().unwrap();
+    let issued: IssuedInvoice = invoices::table
+        .filter(invoices::order_id.eq(order_id))
+        .order(invoices::id.desc())
+        .first(conn)
+        .optional()
+        .expect("Unable to look up invoice")?;
+
+    Some(load_record(conn, issued))
+}
+
+fn load_record(conn: &mut PgConnection, issued: IssuedInvoice) -> InvoiceRecord {
+    use crate::schema::credit_notes;
+
+    let credit_notes = credit_notes::table
+        .filter(credit_notes::invoice_id.eq(issued.id))
+        .order(credit_notes::id)
+        .load::<CreditNote>(conn)
+        .expect("Unable to load credit notes");
+
+    let invoice: Invoice =
+        serde_json::from_str(&issued.document).expect("Stored invoice is not valid");
+    let credited = credit_notes
+        .iter()
+        .fold(BigDecimal::zero(), |total, note| total + &note.amount);
+    let balance = Money::new(&issued.total - credited, invoice.total.currency);
+    let parse_address = |address: &Option<String>| {
+        address
+            .as_ref()
+            .map(|address| serde_json::from_str(address).expect("Stored address is not valid"))
+    };
+
+    InvoiceRecord {
+        billing_address: parse_address(&issued.billing_address),
+        shipping_address: parse_address(&issued.shipping_address),
+        masked_card: issued
+            .masked_card
+            .or_else(|| paid_with(conn, issued.order_id)),
+        invoice_number: issued.invoice_number,
+        order_id: issued.order_id,
+        customer_email: issued.customer_email,
+        issued_at: issued.issued_at,
+        invoice,
+        credit_notes,
+        balance,
+    }
+}
+
+/// The card the gateway last reported for an order's payments. Invoices are
+/// never changed once issued, so this is how a card learned later is found.
+fn paid_with(conn: &mut PgConnection, order_id: i32) -> Option<String> {
+    use crate::schema::payments;
+
+    payments::table
+        .filter(payments::order_id.eq(order_id))
+        .filter(payments::account_number.is_not_null())
+        .order(payments::id.desc())
+        .select(payments::account_number)
+        .first::<Option<String>>(conn)
+        .optional()
+        .expect("Unable to look up payment card")
+        .flatten()
+}
+
+/// Credits part or all of an issued invoice. Without an `amount` the whole
+/// remaining balance is credited, which is what a cancellation normally
+/// wants. The invoice row is locked while its balance is checked, so
+/// concurrent credit notes can never credit more than was invoiced.
+pub fn issue_credit_note(
+    invoice_number: &str,
+    reason: CreditReason,
+    amount: Option<&Money>,
+    note: Option<&str>,
+) -> Result<CreditNote, CreditNoteError> {
+    use crate::schema::{credit_notes, invoices};
+
+    let conn = &mut POOL.get().unwrap();
+
+    conn.build_transaction()
+        .read_write()
+        .run::<CreditNote, TransactionError<CreditNoteError>, _>(|conn| {
+            let issued: IssuedInvoice = invoices::table
+                .filter(invoices::invoice_number.eq(invoice_number.trim().to_uppercase()))
+                .for_update()
+                .first(conn)
+                .optional()?
+                .ok_or(CreditNoteError::InvoiceNotFound)?;
+            let currency =
+                Currency::from_code(&issued.currency).expect("Stored invoice has no currency");
+
+            let credited: Option<BigDecimal> = credit_notes::table
+                .filter(credit_notes::invoice_id.eq(issued.id))
+                .select(diesel::dsl::sum(credit_notes::amount))
+                .first(conn)?;
+            let balance = Money::new(&issued.total - credited.unwrap_or_default(), currency);
+
+            let amount = match amount {
+                Some(amount) if amount.currency != currency => {
+                    return Err(CreditNoteError::CurrencyMismatch.into())
+                }
+                Some(amount) => amount.clone(),
+                None => balance.clone(),
+            };
+            if amount.is_zero() || amount.is_negative() {
+                return Err(CreditNoteError::InvalidAmount.into());
+            }
+            if amount > balance {
+                return Err(CreditNoteError::ExceedsBalance(balance).into());
+            }
+
+            let number = next_document_number(conn, CREDIT_NOTE_COUNTER)?;
+
+            Ok(diesel::insert_into(credit_notes::table)
+                .values(&NewCreditNote {
+                    credit_note_number: &format!("CN-{:06}", number),
+                    invoice_id: &issued.id,
+                    reason: reason.code(),
+                    note,
+                    amount: &amount.amount,
+                })
+                .get_result(conn)?)
+        })
+        .map_err(TransactionError::into_domain)
+}
+
+fn next_document_number(conn: &mut PgConnection, counter: &str) -> QueryResult<i32> {
+    use crate::schema::document_counters::dsl::*;
+
+    let number: i32 = document_counters
+        .find(counter)
+        .select(next_number)
+        .for_update()
+        .first(conn)?;
+
+    diesel::update(document_counters.find(counter))
+        .set(next_number.eq(number + 1))
+        .execute(conn)?;
+
+    Ok(number)
+}
@@ -1,16 +1,37 @@
+pub mod access;
 pub mod authorize_net;
+pub mod breaker;
+pub mod cards;
+pub mod carts;
+pub mod coupons;
 pub mod db;
+pub mod discounts;
 pub mod ecommerce;
+pub mod exchange;
+pub mod fraud;
+pub mod gateway;
 pub mod inventory;
+pub mod invoicing;
 pub mod models;
+pub mod money;
+pub mod orders;
+pub mod payment_methods;
+pub mod payments;
+pub mod pricing;
+pub mod receipts;
+pub mod refunds;
 pub mod schema;
+pub mod settings;
+pub mod shipping;
+pub mod tax;
+pub mod validation;
 
-use bigdecimal::BigDecimal;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::r2d2::{ConnectionManager, Pool};
 use dotenvy::dotenv;
 use models::Product;
+use money::Money;
 use std::env;
 
 use crate::models::NewProduct;
@@ -26,18 +47,13 @@ pub fn create_pool() -> Pool<ConnectionManager<PgConnection>> {
         .expect("Could not create connection pool")
 }
 
-pub fn create_product(
-    conn: &mut PgConnection,
-    title: &str,
-    stock: &i32,
-    price: &BigDecimal,
-) -> Product {
+pub fn create_product(conn: &mut PgConnection, title: &str, stock: &i32, price: &Money) -> Product {
     use crate::schema::products;
 
     let new_product = NewProduct {
         title,
         stock,
-        price,
+        price: &price.amount,
     };
 
     diesel::insert_into(products::table)
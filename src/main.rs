@@ -4,16 +4,19 @@ use axum::{
         Path, Query, State, WebSocketUpgrade,
     },
     http::StatusCode,
-    response::{sse::Event, IntoResponse, Sse},
-    routing::{get, post},
+    response::{sse::Event, IntoResponse, Response, Sse},
+    routing::{delete, get, post},
     Json, Router,
 };
-use bigdecimal::{BigDecimal, FromPrimitive};
+use bigdecimal::BigDecimal;
+use chrono::Utc;
 use diesel::prelude::*;
 use futures::Stream;
-use http::{header::CONTENT_TYPE, Method};
+use http::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    HeaderMap, Method,
+};
 use lazy_static::lazy_static;
-use rand::Rng;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::{
@@ -21,16 +24,39 @@ use std::{
     convert::Infallible,
     net::SocketAddr,
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, Instant},
 };
 use tokio::sync::broadcast::{self, Sender};
 use traffic_jam::*;
 
-use crate::authorize_net::ChargeCreditCardRequest;
+use crate::access::{is_admin, may_view_order, sign_order};
+use crate::authorize_net::{Address, Payment};
+use crate::breaker::{BreakerState, BreakerStatus, CircuitBreaker};
+use crate::carts::*;
+use crate::coupons::*;
 use crate::db::POOL;
-use crate::ecommerce::{Discount, Invoice};
+use crate::discounts::*;
+use crate::ecommerce::{Customer, Invoice};
+use crate::exchange::*;
+use crate::fraud::{finish_screening, screen_order, screen_payment, FraudAction, Screening};
+use crate::gateway::{
+    configured_gateway, resolve_in_doubt, GatewayResponse, GatewayResult, PaymentGateway,
+    PaymentOutcome,
+};
 use crate::inventory::*;
+use crate::invoicing::*;
 use crate::models::*;
+use crate::money::*;
+use crate::orders::*;
+use crate::payment_methods::*;
+use crate::payments::*;
+use crate::pricing::*;
+use crate::receipts::*;
+use crate::refunds::*;
+use crate::settings::SETTINGS;
+use crate::shipping::*;
+use crate::tax::TaxRates;
+use crate::validation::*;
 use tower_http::cors::{Any, CorsLayer};
 
 #[derive(Serialize)]
@@ -38,13 +64,23 @@ struct ResultProduct {
     id: i32,
     title: String,
     stock: i32,
-    price: BigDecimal,
+    price: Money,
+    purchasable: bool,
+    max_order_qty: i32,
+    category: Option<String>,
+    tax_category: String,
+    weight_grams: i32,
+    length_mm: i32,
+    width_mm: i32,
+    height_mm: i32,
 }
 
 #[derive(Serialize)]
 struct RequestError {
     message: String,
     detail: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    problems: Vec<ValidationError>,
 }
 
 #[derive(Serialize)]
@@ -56,6 +92,21 @@ struct DetailedResponse<T> {
 #[derive(Clone)]
 struct AppState {
     tx: Sender<String>,
+    gateway: Arc<dyn PaymentGateway>,
+    /// The same gateway, for checking whether it is up
+    breaker: Arc<CircuitBreaker>,
+}
+
+/// An order taken while the gateway was down, kept with what is needed to
+/// charge it later. Only saved payment methods and tokens are deferred, so
+/// no card details are ever held, and the queue only lives in memory.
+struct DeferredPayment {
+    order: Order,
+    deferred_at: Instant,
+    invoice: Invoice,
+    customer: Customer,
+    /// What the fraud rules made of the order when it was taken
+    screening: Screening,
 }
 
 lazy_static! {
@@ -65,16 +116,48 @@ lazy_static! {
         }));
     static ref UDPATE_QUEUE: Arc<Mutex<VecDeque<String>>> =
         Arc::new(Mutex::new(VecDeque::from([])));
+    static ref DEFERRED_PAYMENTS: Arc<Mutex<VecDeque<DeferredPayment>>> =
+        Arc::new(Mutex::new(VecDeque::from([])));
 }
 
 #[tokio::main]
 async fn main() {
+    // Refuse to start with secrets that would let anyone forge prices or
+    // customer tokens, work card numbers back out of their fingerprints, or
+    // act as staff
+    lazy_static::initialize(&pricing::QUOTE_SECRET);
+    lazy_static::initialize(&payment_methods::CUSTOMER_TOKEN_SECRET);
+    lazy_static::initialize(&fraud::CARD_FINGERPRINT_SECRET);
+    lazy_static::initialize(&access::ADMIN_TOKEN);
+
     let (tx, _) = broadcast::channel::<String>(100);
-    let app_state = AppState { tx: tx.clone() };
+    let breaker_tx = tx.clone();
+    let breaker = Arc::new(CircuitBreaker::new(configured_gateway()).with_listener(
+        move |status| {
+            let breaker_msg = json!({ "payment_gateway": status }).to_string();
+            let _ = breaker_tx.send(breaker_msg.to_owned());
+            UDPATE_QUEUE.lock().unwrap().push_back(breaker_msg);
+        },
+    ));
+    let app_state = AppState {
+        tx: tx.clone(),
+        gateway: breaker.clone(),
+        breaker,
+    };
+
+    // The payment details of orders deferred before a restart are gone
+    for order in deferred_orders() {
+        eprintln!(
+            "Cancelling order #{}, its deferred payment was lost on restart",
+            order.id
+        );
+        let _ = cancel_deferred(order.id);
+    }
+    tokio::spawn(charge_deferred_payments(app_state.clone()));
 
     let cors = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST])
-        .allow_headers([CONTENT_TYPE])
+        .allow_methods([Method::GET, Method::POST, Method::DELETE])
+        .allow_headers([AUTHORIZATION, CONTENT_TYPE])
         .allow_origin(Any);
 
     let app = Router::new()
@@ -83,7 +166,38 @@ async fn main() {
             "/product/:product_id",
             get(product_data).post(update_product),
         )
+        .route("/quote", post(create_quote))
+        .route("/shipping_options", post(shipping_options))
         .route("/process_order", post(process_order))
+        .route("/cart", post(create_cart_handler))
+        .route("/cart/:cart_id", get(cart_data).post(update_cart_handler))
+        .route(
+            "/cart/:cart_id/line/:product_id",
+            post(update_cart_line).delete(remove_cart_line_handler),
+        )
+        .route("/cart/:cart_id/checkout", post(checkout_cart))
+        .route("/exchange_rates", get(query_exchange_rates))
+        .route("/exchange_rate/:currency", post(update_exchange_rate))
+        .route("/order/:order_id", get(order_data))
+        .route("/order/:order_id/ship", post(ship_order))
+        .route("/order/:order_id/refund", post(refund_order_handler))
+        .route("/order/:order_id/receipt", get(order_receipt))
+        .route("/order/:order_id/review", post(review_order))
+        .route("/review_queue", get(review_queue))
+        .route("/invoice/:invoice_number", get(invoice_data))
+        .route(
+            "/invoice/:invoice_number/credit_note",
+            post(create_credit_note),
+        )
+        .route(
+            "/customer/:email/payment_methods",
+            get(customer_payment_methods_handler),
+        )
+        .route(
+            "/payment_method/:payment_method_id",
+            delete(remove_payment_method_handler),
+        )
+        .route("/health", get(health))
         .route("/event_stream", get(sse_handler))
         .route("/event_socket", get(ws_handler))
         .layer(cors)
@@ -99,6 +213,8 @@ async fn main() {
 struct Pagination {
     offset: i64,
     limit: i64,
+    /// Show prices converted into this currency
+    currency: Option<Currency>,
 }
 
 async fn query_products(
@@ -112,11 +228,20 @@ async fn query_products(
     let offset = query.offset;
     let limit = query.limit;
 
-    let results = products
+    let conversion = match Conversion::to_presentment(query.currency.unwrap_or_else(Currency::base))
+    {
+        Ok(conversion) => conversion,
+        Err(exchange_error) => return exchange_error_response(exchange_error),
+    };
+
+    let mut results = products
         .offset(offset)
         .limit(limit)
         .load::<Product>(conn)
         .unwrap();
+    for product in &mut results {
+        product.price = conversion.convert(&product.price);
+    }
 
     (
         StatusCode::OK,
@@ -146,6 +271,14 @@ async fn product_data(
                     title: item.title,
                     stock: item.stock,
                     price: item.price,
+                    purchasable: item.purchasable,
+                    max_order_qty: item.max_order_qty,
+                    category: item.category,
+                    tax_category: item.tax_category,
+                    weight_grams: item.weight_grams,
+                    length_mm: item.length_mm,
+                    width_mm: item.width_mm,
+                    height_mm: item.height_mm,
                 }),
                 error: None,
             }),
@@ -161,6 +294,7 @@ async fn product_data(
                         product_id
                     )
                     .to_string(),
+                    problems: vec![],
                 }),
             }),
         ),
@@ -180,7 +314,15 @@ async fn update_product(
         .set((
             title.eq(new_product.title),
             stock.eq(new_product.stock),
-            price.eq(new_product.price),
+            price.eq(new_product.price.amount),
+            purchasable.eq(new_product.purchasable),
+            max_order_qty.eq(new_product.max_order_qty),
+            category.eq(new_product.category),
+            tax_category.eq(new_product.tax_category),
+            weight_grams.eq(new_product.weight_grams),
+            length_mm.eq(new_product.length_mm),
+            width_mm.eq(new_product.width_mm),
+            height_mm.eq(new_product.height_mm),
         ))
         .get_result::<Product>(conn);
 
@@ -208,19 +350,345 @@ async fn update_product(
                 error: Some(RequestError {
                     message: "Could not find product to update".to_string(),
                     detail: "Please specify a valid product id".to_string(),
+                    problems: vec![],
                 }),
             }),
         ),
     }
 }
 
+fn build_invoice(
+    order: &Order,
+    coupon: Option<&Coupon>,
+    shipping_address: Option<&Address>,
+    tax_rates: &TaxRates,
+    conversion: &Conversion,
+) -> Result<Invoice, ShippingError> {
+    let currency = Currency::base();
+    let discounts = DiscountRules::for_order(coupon).apply(&order.items, currency);
+    let goods_total = items_total(&order.items, currency) - discount_total(&discounts, currency);
+
+    let shipping = match shipping_address {
+        Some(address) => quote_shipping(
+            address,
+            &order.items,
+            order.shipping_method,
+            &goods_total.floor_at_zero(),
+        )?,
+        None => ShippingQuote::unquoted(order.shipping_method, currency),
+    };
+
+    Ok(Invoice::create(
+        order, discounts, shipping, tax_rates, conversion,
+    ))
+}
+
+fn exchange_error_response<T>(
+    exchange_error: ExchangeError,
+) -> (StatusCode, Json<DetailedResponse<T>>) {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(DetailedResponse {
+            data: None,
+            error: Some(RequestError {
+                message: "Unable to use currency".to_string(),
+                detail: exchange_error.describe(),
+                problems: vec![],
+            }),
+        }),
+    )
+}
+
+fn shipping_error_response<T>(
+    shipping_error: ShippingError,
+) -> (StatusCode, Json<DetailedResponse<T>>) {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(DetailedResponse {
+            data: None,
+            error: Some(RequestError {
+                message: "Unable to ship order".to_string(),
+                detail: shipping_error.describe(),
+                problems: vec![],
+            }),
+        }),
+    )
+}
+
+fn pricing_error_response<T>(
+    pricing_error: PricingError,
+) -> (StatusCode, Json<DetailedResponse<T>>) {
+    let (message, detail, problems) = match pricing_error {
+        PricingError::InvalidQuote => (
+            "Invalid price quote",
+            "The quote token is malformed or does not cover every item".to_string(),
+            vec![],
+        ),
+        PricingError::ExpiredQuote => (
+            "Price quote expired",
+            "Please request a new quote before placing the order".to_string(),
+            vec![],
+        ),
+        PricingError::Exchange(exchange_error) => return exchange_error_response(exchange_error),
+        PricingError::PriceChanged(changes) => (
+            "Prices have changed",
+            format!(
+                "{} item(s) no longer match the current price",
+                changes.len()
+            ),
+            changes
+                .iter()
+                .map(|change| ValidationError {
+                    field: format!("items[id={}].price", change.id),
+                    message: format!(
+                        "Price changed from {} to {}",
+                        change.expected, change.current
+                    ),
+                })
+                .collect(),
+        ),
+    };
+
+    (
+        StatusCode::CONFLICT,
+        Json(DetailedResponse {
+            data: None,
+            error: Some(RequestError {
+                message: message.to_string(),
+                detail,
+                problems,
+            }),
+        }),
+    )
+}
+
+fn coupon_error_response<T>(coupon_error: CouponError) -> (StatusCode, Json<DetailedResponse<T>>) {
+    let status = match coupon_error {
+        CouponError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::UNPROCESSABLE_ENTITY,
+    };
+
+    (
+        status,
+        Json(DetailedResponse {
+            data: None,
+            error: Some(RequestError {
+                message: "Coupon code cannot be applied".to_string(),
+                detail: coupon_error.describe(),
+                problems: vec![],
+            }),
+        }),
+    )
+}
+
+async fn create_quote(
+    Json(mut req_body): Json<QuoteRequest>,
+) -> (StatusCode, Json<DetailedResponse<Quote>>) {
+    let problems = validate_items(&mut req_body.items);
+    if !problems.is_empty() {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(DetailedResponse {
+                data: None,
+                error: Some(RequestError {
+                    message: "Invalid quote request".to_string(),
+                    detail: format!("{} problem(s) found with the quote", problems.len()),
+                    problems,
+                }),
+            }),
+        );
+    }
+
+    apply_catalog_prices(&mut req_body.items);
+
+    let conversion =
+        match Conversion::to_presentment(req_body.currency.unwrap_or_else(Currency::base)) {
+            Ok(conversion) => conversion,
+            Err(exchange_error) => return exchange_error_response(exchange_error),
+        };
+
+    let coupon = match &req_body.coupon_code {
+        Some(code) => match find_valid_coupon(code, None, &req_body.items) {
+            Ok(coupon) => Some(coupon),
+            Err(coupon_error) => return coupon_error_response(coupon_error),
+        },
+        None => None,
+    };
+
+    let order = Order {
+        id: 0,
+        items: req_body.items,
+        dropped_items: vec![],
+        shipping_method: req_body.shipping_method,
+        currency: conversion.to,
+        cart_id: None,
+        invoice_number: None,
+        transaction_id: None,
+        payment_under_review: false,
+        payment_in_doubt: false,
+        payment_deferred: false,
+        payment_method_id: None,
+        customer_token: None,
+        order_token: None,
+    };
+    let tax_rates = match &req_body.shipping_address {
+        Some(address) => TaxRates::lookup(address, &order.items),
+        None => TaxRates::none(),
+    };
+    let invoice = match build_invoice(
+        &order,
+        coupon.as_ref(),
+        req_body.shipping_address.as_ref(),
+        &tax_rates,
+        &conversion,
+    ) {
+        Ok(invoice) => invoice,
+        Err(shipping_error) => return shipping_error_response(shipping_error),
+    };
+
+    (
+        StatusCode::OK,
+        Json(DetailedResponse {
+            data: Some(Quote::create(&order.items, &conversion, invoice)),
+            error: None,
+        }),
+    )
+}
+
+async fn shipping_options(
+    Json(mut req_body): Json<QuoteRequest>,
+) -> (StatusCode, Json<DetailedResponse<Vec<ShippingQuote>>>) {
+    let problems = validate_items(&mut req_body.items);
+    if !problems.is_empty() {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(DetailedResponse {
+                data: None,
+                error: Some(RequestError {
+                    message: "Invalid shipping request".to_string(),
+                    detail: format!("{} problem(s) found with the items", problems.len()),
+                    problems,
+                }),
+            }),
+        );
+    }
+
+    let shipping_address = match &req_body.shipping_address {
+        Some(address) => address,
+        None => {
+            return (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(DetailedResponse {
+                    data: None,
+                    error: Some(RequestError {
+                        message: "Invalid shipping request".to_string(),
+                        detail: "A shipping address is required".to_string(),
+                        problems: vec![],
+                    }),
+                }),
+            )
+        }
+    };
+
+    apply_catalog_prices(&mut req_body.items);
+    let goods_total = items_total(&req_body.items, Currency::base());
+
+    (
+        StatusCode::OK,
+        Json(DetailedResponse {
+            data: Some(available_shipping(
+                shipping_address,
+                &req_body.items,
+                &goods_total,
+            )),
+            error: None,
+        }),
+    )
+}
+
 async fn process_order(
     State(state): State<AppState>,
     Json(req_body): Json<CreateOrderRequest>,
 ) -> (StatusCode, Json<DetailedResponse<Order>>) {
-    use self::schema::products::dsl::*;
+    place_order(state, req_body, None).await
+}
+
+/// Validates, holds and charges an order. Orders checked out from a cart may
+/// use the stock that cart has reserved.
+async fn place_order(
+    state: AppState,
+    mut req_body: CreateOrderRequest,
+    cart_id: Option<String>,
+) -> (StatusCode, Json<DetailedResponse<Order>>) {
+    if let Err(problems) = validate_order_request(&mut req_body) {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(DetailedResponse {
+                data: None,
+                error: Some(RequestError {
+                    message: "Invalid order request".to_string(),
+                    detail: format!("{} problem(s) found with the order", problems.len()),
+                    problems,
+                }),
+            }),
+        );
+    }
+
+    let currency = req_body.currency.unwrap_or_else(Currency::base);
+    // Cart lines are already at their catalog prices, so there is no client
+    // price to check them against
+    let conversion = if cart_id.is_some() {
+        Conversion::to_presentment(currency).map_err(PricingError::Exchange)
+    } else {
+        apply_server_prices(
+            &mut req_body.items,
+            req_body.quote_token.as_deref(),
+            currency,
+        )
+    };
+    let conversion = match conversion {
+        Ok(conversion) => conversion,
+        Err(pricing_error) => return pricing_error_response(pricing_error),
+    };
+
+    let coupon = match &req_body.coupon_code {
+        Some(code) => {
+            match find_valid_coupon(code, Some(&req_body.customer.email), &req_body.items) {
+                Ok(coupon) => Some(coupon),
+                Err(coupon_error) => return coupon_error_response(coupon_error),
+            }
+        }
+        None => None,
+    };
+
+    if let Err(shipping_error) = quote_shipping(
+        &req_body.customer.shipping_address,
+        &req_body.items,
+        req_body.shipping_method,
+        &items_total(&req_body.items, Currency::base()),
+    ) {
+        return shipping_error_response(shipping_error);
+    }
+
+    // Without a queue to defer to, or with card details that are never
+    // queued, an order cannot be taken while the gateway is down, so it is
+    // turned away before any stock is held
+    let deferrable = SETTINGS.defer_payments && req_body.customer.payment.can_wait(Duration::ZERO);
+    if !deferrable && !state.breaker.allows_requests() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(DetailedResponse {
+                data: None,
+                error: Some(RequestError {
+                    message: "Payments are unavailable".to_string(),
+                    detail: "The payment gateway is down, please try again shortly".to_string(),
+                    problems: vec![],
+                }),
+            }),
+        );
+    }
 
-    let order_id: usize = rand::thread_rng().gen_range(1..10000);
+    let order_id = next_order_id();
     let processing_msg = format!("Processing order {}", order_id).to_string();
     let _ = state.tx.send(processing_msg.to_owned());
     UDPATE_QUEUE
@@ -228,51 +696,328 @@ async fn process_order(
         .unwrap()
         .push_back(processing_msg.to_owned());
 
-    let new_order: Order = Order {
+    let mut new_order: Order = Order {
         id: order_id,
         items: req_body.items,
+        dropped_items: vec![],
+        shipping_method: req_body.shipping_method,
+        currency: conversion.to,
+        cart_id,
+        invoice_number: None,
+        transaction_id: None,
+        payment_under_review: false,
+        payment_in_doubt: false,
+        payment_deferred: false,
+        payment_method_id: None,
+        customer_token: None,
+        order_token: Some(sign_order(order_id as i32)),
     };
 
-    let discounts: Vec<Discount> = vec![];
+    let process_handle = tokio::spawn(async move {
+        let held = if req_body.allow_partial {
+            HOLDING_INVENTORY
+                .lock()
+                .unwrap()
+                .hold_available_items(&mut new_order)
+        } else {
+            HOLDING_INVENTORY.lock().unwrap().hold_items(&new_order)
+        };
+
+        if held {
+            let tax_rates = TaxRates::lookup(&req_body.customer.shipping_address, &new_order.items)
+                .with_exemption(req_body.customer.tax_exemption_id.clone());
+            let invoice = match build_invoice(
+                &new_order,
+                coupon.as_ref(),
+                Some(&req_body.customer.shipping_address),
+                &tax_rates,
+                &conversion,
+            ) {
+                Ok(invoice) => invoice,
+                Err(shipping_error) => {
+                    HOLDING_INVENTORY.lock().unwrap().undo_hold(&order_id);
+                    return shipping_error_response(shipping_error);
+                }
+            };
 
-    let invoice = Invoice::create(
-        &new_order,
-        discounts,
-        BigDecimal::from_f32(5.0).unwrap(),
-        BigDecimal::from_f32(0.0715).unwrap(),
-    );
+            // A coupon that took nothing off, say because dropped lines put
+            // the order under its minimum, is not counted against its limits
+            let coupon = coupon.filter(|coupon| {
+                invoice.discounts.iter().any(|discount| {
+                    discount.rule_id == coupon.discount_rule_id && !discount.amount.is_zero()
+                })
+            });
+            if let Some(coupon) = &coupon {
+                if let Err(coupon_error) =
+                    redeem_coupon(coupon, &req_body.customer.email, order_id as i32)
+                {
+                    HOLDING_INVENTORY.lock().unwrap().undo_hold(&order_id);
+                    return coupon_error_response(coupon_error);
+                }
+            }
 
-    let process_handle = tokio::spawn(async move {
-        if HOLDING_INVENTORY.lock().unwrap().hold_items(&new_order) {
-            match ChargeCreditCardRequest::create(&new_order, invoice, req_body.customer).await {
-                Ok(_) => {
+            let mut screening = screen_order(order_id as i32, &req_body.customer, &invoice);
+            if screening.rejected() {
+                HOLDING_INVENTORY.lock().unwrap().undo_hold(&order_id);
+                if let Some(coupon) = &coupon {
+                    release_coupon(coupon, order_id as i32);
+                }
+                let blocked_msg = format!("Order #{} was blocked by fraud screening", order_id);
+                let _ = state.tx.send(blocked_msg.to_owned());
+                UDPATE_QUEUE.lock().unwrap().push_back(blocked_msg);
+
+                // Which rule was broken is kept from the customer
+                return (
+                    StatusCode::FORBIDDEN,
+                    Json(DetailedResponse {
+                        data: None,
+                        error: Some(RequestError {
+                            message: "Order could not be accepted".to_string(),
+                            detail: "Please contact us to complete this order".to_string(),
+                            problems: vec![],
+                        }),
+                    }),
+                );
+            }
+
+            let sent_at = Utc::now();
+            let payment = if SETTINGS.capture_on_shipment {
+                state
+                    .gateway
+                    .authorize(&new_order, &invoice, &req_body.customer)
+                    .await
+            } else {
+                state
+                    .gateway
+                    .charge(&new_order, &invoice, &req_body.customer)
+                    .await
+            };
+            record_payment(
+                order_id as i32,
+                if SETTINGS.capture_on_shipment {
+                    PaymentAction::Authorize
+                } else {
+                    PaymentAction::Charge
+                },
+                Some(&invoice.total),
+                &payment,
+            );
+            // Payments that never reached a down gateway are charged later
+            let deferred = deferrable
+                && matches!(&payment, Err(gateway_error) if gateway_error.was_not_sent());
+            // `None` while nobody knows whether the payment went through, or
+            // when it has been deferred
+            // Payments made with opaque data only learn the card from the
+            // gateway's reply
+            let mut masked_card = req_body.customer.payment.masked();
+            let mut last_response = None;
+            let mut outcome = match payment {
+                _ if deferred => None,
+                Ok(response) => {
+                    new_order.transaction_id = Some(response.transaction_id.clone());
+                    if !response.account_number.is_empty() {
+                        masked_card = Some(response.account_number.clone());
+                    }
+                    let outcome = response.outcome();
+                    last_response = Some(response);
+                    Some(outcome)
+                }
+                Err(gateway_error) if gateway_error.is_in_doubt() => {
+                    // The reply was lost, so the gateway is asked what became
+                    // of the request before the order is given up on
+                    match resolve_in_doubt(state.gateway.as_ref(), &order_id.to_string(), sent_at)
+                        .await
+                    {
+                        Ok(Some(response)) => {
+                            record_payment(
+                                order_id as i32,
+                                PaymentAction::Lookup,
+                                None,
+                                &Ok(response.clone()),
+                            );
+                            new_order.transaction_id = Some(response.transaction_id.clone());
+                            let outcome = response.outcome();
+                            last_response = Some(response);
+                            Some(outcome)
+                        }
+                        // It may just not show up yet, so the capture job
+                        // decides once it has had time to
+                        Ok(None) | Err(_) => None,
+                    }
+                }
+                Err(gateway_error) => Some(PaymentOutcome::Error(gateway_error.describe())),
+            };
+
+            // The gateway's address and security code checks only come back
+            // with a payment it accepted
+            let paid = matches!(
+                outcome,
+                Some(PaymentOutcome::Approved) | Some(PaymentOutcome::HeldForReview(_))
+            );
+            if let (true, Some(response)) = (paid, &last_response) {
+                screening = screening.merge(screen_payment(response));
+                if screening.rejected() {
+                    let voided = state.gateway.void(&response.transaction_id).await;
+                    record_payment(order_id as i32, PaymentAction::Void, None, &voided);
+                    match voided.map(|voided| voided.outcome()) {
+                        Ok(PaymentOutcome::Approved) => {
+                            outcome = Some(PaymentOutcome::Declined(String::from(
+                                "The payment could not be verified",
+                            )))
+                        }
+                        // The funds stay held, so the order is kept for
+                        // someone to reject once the gateway lets it
+                        _ => {
+                            screening.action = FraudAction::Review;
+                            screening.reasons.push(String::from(
+                                "Rejected, but the payment could not be voided",
+                            ));
+                        }
+                    }
+                }
+                if screening.held() && matches!(outcome, Some(PaymentOutcome::Approved)) {
+                    outcome = Some(PaymentOutcome::HeldForReview(String::from(
+                        "Held for fraud review",
+                    )));
+                }
+            }
+            finish_screening(
+                order_id as i32,
+                &screening,
+                match &outcome {
+                    Some(outcome) => outcome.code(),
+                    None if deferred => PaymentStatus::Deferred.code(),
+                    None => PaymentStatus::InDoubt.code(),
+                },
+            );
+
+            match &outcome {
+                None | Some(PaymentOutcome::Approved) | Some(PaymentOutcome::HeldForReview(_)) => {
                     HOLDING_INVENTORY.lock().unwrap().release_order(&order_id);
+                    new_order.payment_under_review =
+                        matches!(outcome, Some(PaymentOutcome::HeldForReview(_)));
+                    new_order.payment_in_doubt = outcome.is_none() && !deferred;
+                    new_order.payment_deferred = deferred;
+
+                    // The customer may already have paid at this point, so a
+                    // failure to store the invoice must not fail the order
+                    new_order.invoice_number = match issue_invoice(
+                        order_id as i32,
+                        &req_body.customer,
+                        masked_card.as_deref(),
+                        &invoice,
+                    ) {
+                        Ok(issued) => Some(issued.invoice_number),
+                        Err(error) => {
+                            eprintln!("Unable to issue invoice for order #{}: {}", order_id, error);
+                            None
+                        }
+                    };
+                    let payment_status = if new_order.payment_deferred {
+                        PaymentStatus::Deferred
+                    } else if new_order.payment_in_doubt {
+                        PaymentStatus::InDoubt
+                    } else if new_order.payment_under_review {
+                        PaymentStatus::UnderReview
+                    } else if SETTINGS.capture_on_shipment {
+                        PaymentStatus::Authorized
+                    } else {
+                        PaymentStatus::Captured
+                    };
+                    if let Err(error) = record_order(
+                        &new_order,
+                        &req_body.customer.email,
+                        &invoice,
+                        payment_status,
+                        &screening.reasons,
+                    ) {
+                        eprintln!("Unable to record order #{}: {}", order_id, error);
+                    }
+                    if let Some(cart_id) = &new_order.cart_id {
+                        mark_checked_out(cart_id);
+                    }
+                    if deferred {
+                        DEFERRED_PAYMENTS
+                            .lock()
+                            .unwrap()
+                            .push_back(DeferredPayment {
+                                order: new_order.clone(),
+                                deferred_at: Instant::now(),
+                                invoice: invoice.clone(),
+                                customer: req_body.customer.clone(),
+                                screening: screening.clone(),
+                            });
+                    }
+
+                    // Saving is only a convenience, so the order stands if it fails
+                    let savable = req_body.save_payment_method
+                        && matches!(outcome, Some(PaymentOutcome::Approved))
+                        && !matches!(req_body.customer.payment, Payment::Profile(_));
+                    if let (true, Some(response)) = (savable, &last_response) {
+                        match save_payment_method(
+                            state.gateway.as_ref(),
+                            &req_body.customer,
+                            response,
+                            req_body.customer_token.as_deref(),
+                        )
+                        .await
+                        {
+                            Ok((method, customer_token)) => {
+                                new_order.payment_method_id = Some(method.id);
+                                new_order.customer_token = Some(customer_token);
+                            }
+                            Err(method_error) => eprintln!(
+                                "Unable to save payment method for order #{}: {}",
+                                order_id,
+                                method_error.describe()
+                            ),
+                        }
+                    }
 
                     let order_product_ids: Vec<i32> =
                         new_order.items.iter().map(|item| item.id).collect();
+                    broadcast_stock(&state, order_product_ids);
 
-                    let conn = &mut POOL.get().unwrap();
-                    let new_stock_values = products
-                        .filter(id.eq_any(order_product_ids))
-                        .load::<Product>(conn)
-                        .expect("Unable to retrieve current stock values for order products");
+                    let pending_msg = match &outcome {
+                        Some(PaymentOutcome::HeldForReview(reason)) => Some(format!(
+                            "Payment for order #{} is held for review: {}",
+                            order_id, reason
+                        )),
+                        None if deferred => Some(format!(
+                            "Payment for order #{} is deferred until the payment gateway is back",
+                            order_id
+                        )),
+                        None => Some(format!(
+                            "Payment for order #{} could not be confirmed and will be checked again",
+                            order_id
+                        )),
+                        _ => None,
+                    };
+                    if let Some(pending_msg) = pending_msg {
+                        let _ = state.tx.send(pending_msg.to_owned());
+                        UDPATE_QUEUE.lock().unwrap().push_back(pending_msg);
+                    }
 
-                    let completion_msg = json!(new_stock_values).to_string();
-                    let _ = state.tx.send(completion_msg.to_owned());
-                    UDPATE_QUEUE
-                        .lock()
-                        .unwrap()
-                        .push_back(completion_msg.to_owned());
                     return (
-                        StatusCode::OK,
+                        if new_order.payment_under_review
+                            || new_order.payment_in_doubt
+                            || new_order.payment_deferred
+                        {
+                            StatusCode::ACCEPTED
+                        } else {
+                            StatusCode::OK
+                        },
                         Json(DetailedResponse {
                             data: Some(new_order),
                             error: None,
                         }),
                     );
                 }
-                Err(_) => {
+                Some(PaymentOutcome::Declined(reason)) | Some(PaymentOutcome::Error(reason)) => {
                     HOLDING_INVENTORY.lock().unwrap().undo_hold(&order_id);
+                    if let Some(coupon) = &coupon {
+                        release_coupon(coupon, order_id as i32);
+                    }
                     let failure_msg =
                         format!("Error while collecting payment for order #{}", order_id);
                     let _ = state.tx.send(failure_msg.to_owned());
@@ -280,13 +1025,21 @@ async fn process_order(
                         .lock()
                         .unwrap()
                         .push_back(failure_msg.to_owned());
+
+                    let (status, message) = match &outcome {
+                        Some(PaymentOutcome::Declined(_)) => {
+                            (StatusCode::PAYMENT_REQUIRED, "Payment was declined")
+                        }
+                        _ => (StatusCode::BAD_GATEWAY, "Unable to process payment method"),
+                    };
                     return (
-                        StatusCode::OK,
+                        status,
                         Json(DetailedResponse {
                             data: None,
                             error: Some(RequestError {
-                                message: "Unable to process payment method".to_string(),
-                                detail: "Invalid payment details".to_string(),
+                                message: message.to_string(),
+                                detail: reason.clone(),
+                                problems: vec![],
                             }),
                         }),
                     );
@@ -294,47 +1047,1130 @@ async fn process_order(
             }
         }
 
-        return (
+        (
             StatusCode::OK,
             Json(DetailedResponse {
                 data: None,
                 error: Some(RequestError {
                     message: "Unable to hold inventory for order".to_string(),
                     detail: "Item in order is most likely out of stock".to_string(),
+                    problems: vec![],
                 }),
             }),
-        );
+        )
     });
 
     process_handle.await.unwrap()
 }
 
-async fn sse_handler() -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    let stream = async_stream::stream! {
-        let mut interval = tokio::time::interval(Duration::from_millis(100));
-        loop {
-            interval.tick().await;
-            let latest_update = UDPATE_QUEUE.lock().unwrap().pop_front();
-            match latest_update {
-                Some(data) => {
-                    yield Ok(Event::default().data(data));
-                },
-                None => {}
-            }
+async fn query_exchange_rates() -> (StatusCode, Json<DetailedResponse<Vec<ExchangeRate>>>) {
+    (
+        StatusCode::OK,
+        Json(DetailedResponse {
+            data: Some(exchange_rates()),
+            error: None,
+        }),
+    )
+}
+
+#[derive(Deserialize)]
+struct ExchangeRateRequest {
+    rate: BigDecimal,
+}
+
+async fn update_exchange_rate(
+    Path(currency_code): Path<String>,
+    Json(req_body): Json<ExchangeRateRequest>,
+) -> (StatusCode, Json<DetailedResponse<ExchangeRate>>) {
+    let target = match Currency::from_code(&currency_code) {
+        Some(target) if target != Currency::base() => target,
+        _ => {
+            return (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(DetailedResponse {
+                    data: None,
+                    error: Some(RequestError {
+                        message: "Unable to update exchange rate".to_string(),
+                        detail: format!(
+                            "{} is not a supported currency other than the base currency",
+                            currency_code
+                        ),
+                        problems: vec![],
+                    }),
+                }),
+            )
         }
     };
 
-    Sse::new(stream)
+    match set_exchange_rate(target, &req_body.rate) {
+        Ok(exchange_rate) => (
+            StatusCode::OK,
+            Json(DetailedResponse {
+                data: Some(exchange_rate),
+                error: None,
+            }),
+        ),
+        Err(exchange_error) => exchange_error_response(exchange_error),
+    }
 }
 
-async fn ws_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> impl IntoResponse {
-    ws.on_upgrade(|ws| async { ws_loop(state, ws).await })
+/// An order with everything support needs to reconcile its payment
+#[derive(Serialize)]
+struct OrderView {
+    /// Left out for orders whose payment never went through
+    order: Option<PlacedOrder>,
+    invoice_number: Option<String>,
+    payments: Vec<PaymentAttempt>,
+    refunds: Vec<Refund>,
 }
 
-async fn ws_loop(app_state: AppState, mut ws: WebSocket) {
-    let mut rx = app_state.tx.subscribe();
+/// Needs the order token handed out with the order, the customer's token or
+/// the admin credential
+async fn order_data(
+    Path(order_id): Path<i32>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<DetailedResponse<OrderView>>) {
+    let order = find_order(order_id);
+    let customer_email = order.as_ref().map(|order| order.customer_email.as_str());
+    if !may_view_order(order_id, customer_email, bearer_token(&headers)) {
+        return unauthorized_response("A valid order or customer token is required");
+    }
 
-    while let Ok(msg) = rx.recv().await {
-        ws.send(Message::Text(msg)).await.unwrap();
+    let payments = order_payments(order_id);
+    if order.is_none() && payments.is_empty() {
+        return order_error_response(OrderError::NotFound);
+    }
+
+    (
+        StatusCode::OK,
+        Json(DetailedResponse {
+            data: Some(OrderView {
+                order,
+                invoice_number: find_order_invoice(order_id).map(|record| record.invoice_number),
+                payments,
+                refunds: order_refunds(order_id),
+            }),
+            error: None,
+        }),
+    )
+}
+
+#[derive(Deserialize)]
+struct ShipOrderRequest {
+    /// Leave out when the whole order shipped
+    lines: Option<Vec<LineQuantity>>,
+}
+
+/// Marks an order as shipped and captures its payment. A capture that fails
+/// for a reason other than a decline is left for the `payment_capture` job.
+async fn ship_order(
+    State(state): State<AppState>,
+    Path(order_id): Path<i32>,
+    headers: HeaderMap,
+    Json(req_body): Json<ShipOrderRequest>,
+) -> (StatusCode, Json<DetailedResponse<PlacedOrder>>) {
+    if !is_admin(bearer_token(&headers)) {
+        return unauthorized_response(ADMIN_REQUIRED);
+    }
+    if let Err(order_error) = mark_shipped(order_id, req_body.lines.as_deref()) {
+        return order_error_response(order_error);
+    }
+
+    match capture_order(state.gateway.as_ref(), order_id).await {
+        Ok(order) => (
+            StatusCode::OK,
+            Json(DetailedResponse {
+                data: Some(order),
+                error: None,
+            }),
+        ),
+        Err(OrderError::CaptureDeferred(_)) => (
+            StatusCode::ACCEPTED,
+            Json(DetailedResponse {
+                data: find_order(order_id),
+                error: None,
+            }),
+        ),
+        Err(order_error) => order_error_response(order_error),
+    }
+}
+
+/// Refunds or voids an order, see `refunds::refund_order`
+async fn refund_order_handler(
+    State(state): State<AppState>,
+    Path(order_id): Path<i32>,
+    headers: HeaderMap,
+    Json(req_body): Json<RefundRequest>,
+) -> (StatusCode, Json<DetailedResponse<Refund>>) {
+    if !is_admin(bearer_token(&headers)) {
+        return unauthorized_response(ADMIN_REQUIRED);
+    }
+    match refund_order(state.gateway.as_ref(), order_id, &req_body).await {
+        Ok(refund) => {
+            if refund.restocked {
+                let restocked_ids: Vec<i32> =
+                    refund.lines().iter().map(|line| line.product_id).collect();
+                broadcast_stock(&state, restocked_ids);
+            }
+
+            (
+                StatusCode::OK,
+                Json(DetailedResponse {
+                    data: Some(refund),
+                    error: None,
+                }),
+            )
+        }
+        Err(order_error) => order_error_response(order_error),
+    }
+}
+
+#[derive(Deserialize)]
+struct ReviewOrderRequest {
+    approve: bool,
+}
+
+/// Approves or rejects an order held for review. Rejected orders have their
+/// payment voided and their stock put back.
+async fn review_order(
+    State(state): State<AppState>,
+    Path(order_id): Path<i32>,
+    headers: HeaderMap,
+    Json(req_body): Json<ReviewOrderRequest>,
+) -> (StatusCode, Json<DetailedResponse<PlacedOrder>>) {
+    if !is_admin(bearer_token(&headers)) {
+        return unauthorized_response(ADMIN_REQUIRED);
+    }
+    let reviewed = if req_body.approve {
+        approve_order(state.gateway.as_ref(), order_id).await
+    } else {
+        reject_order(state.gateway.as_ref(), order_id).await
+    };
+
+    match reviewed {
+        Ok(order) => {
+            if order.status() == PaymentStatus::Voided {
+                if let Some(record) = find_order_invoice(order_id) {
+                    let restocked_ids: Vec<i32> = record
+                        .invoice
+                        .lines
+                        .iter()
+                        .map(|line| line.product_id)
+                        .collect();
+                    broadcast_stock(&state, restocked_ids);
+                }
+            }
+
+            (
+                StatusCode::OK,
+                Json(DetailedResponse {
+                    data: Some(order),
+                    error: None,
+                }),
+            )
+        }
+        Err(order_error) => order_error_response(order_error),
+    }
+}
+
+/// Orders held for review, oldest first
+async fn review_queue(
+    headers: HeaderMap,
+) -> (StatusCode, Json<DetailedResponse<Vec<PlacedOrder>>>) {
+    if !is_admin(bearer_token(&headers)) {
+        return unauthorized_response(ADMIN_REQUIRED);
+    }
+
+    (
+        StatusCode::OK,
+        Json(DetailedResponse {
+            data: Some(orders_under_review()),
+            error: None,
+        }),
+    )
+}
+
+#[derive(Deserialize)]
+struct ReceiptQuery {
+    #[serde(default)]
+    format: ReceiptFormat,
+}
+
+/// Needs the same token as the order itself, as the receipt shows the
+/// customer's addresses and card
+async fn order_receipt(
+    Path(order_id): Path<i32>,
+    Query(query): Query<ReceiptQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let order = find_order(order_id);
+    let customer_email = order.as_ref().map(|order| order.customer_email.as_str());
+    if !may_view_order(order_id, customer_email, bearer_token(&headers)) {
+        return unauthorized_response::<()>("A valid order or customer token is required")
+            .into_response();
+    }
+
+    let record = match find_order_invoice(order_id) {
+        Some(record) => record,
+        None => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(DetailedResponse::<()> {
+                    data: None,
+                    error: Some(RequestError {
+                        message: "Receipt not found".to_string(),
+                        detail: format!("No invoice was issued for order #{}", order_id),
+                        problems: vec![],
+                    }),
+                }),
+            )
+                .into_response()
+        }
+    };
+
+    match render_receipt(&record, query.format) {
+        Ok(receipt) => (
+            StatusCode::OK,
+            [(CONTENT_TYPE, query.format.content_type())],
+            receipt,
+        )
+            .into_response(),
+        Err(render_error) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(DetailedResponse::<()> {
+                data: None,
+                error: Some(RequestError {
+                    message: "Unable to render receipt".to_string(),
+                    detail: render_error.to_string(),
+                    problems: vec![],
+                }),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+async fn invoice_data(
+    Path(invoice_number): Path<String>,
+) -> (StatusCode, Json<DetailedResponse<InvoiceRecord>>) {
+    match find_invoice(&invoice_number) {
+        Some(record) => (
+            StatusCode::OK,
+            Json(DetailedResponse {
+                data: Some(record),
+                error: None,
+            }),
+        ),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(DetailedResponse {
+                data: None,
+                error: Some(RequestError {
+                    message: "Invoice not found".to_string(),
+                    detail: format!("No invoice was issued with number {}", invoice_number),
+                    problems: vec![],
+                }),
+            }),
+        ),
+    }
+}
+
+#[derive(Deserialize)]
+struct CreditNoteRequest {
+    reason: CreditReason,
+    /// Defaults to the invoice's remaining balance
+    amount: Option<Money>,
+    note: Option<String>,
+}
+
+async fn create_credit_note(
+    Path(invoice_number): Path<String>,
+    Json(req_body): Json<CreditNoteRequest>,
+) -> (StatusCode, Json<DetailedResponse<CreditNote>>) {
+    match issue_credit_note(
+        &invoice_number,
+        req_body.reason,
+        req_body.amount.as_ref(),
+        req_body.note.as_deref(),
+    ) {
+        Ok(credit_note) => (
+            StatusCode::OK,
+            Json(DetailedResponse {
+                data: Some(credit_note),
+                error: None,
+            }),
+        ),
+        Err(credit_note_error) => {
+            let status = match credit_note_error {
+                CreditNoteError::InvoiceNotFound => StatusCode::NOT_FOUND,
+                CreditNoteError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::UNPROCESSABLE_ENTITY,
+            };
+
+            (
+                status,
+                Json(DetailedResponse {
+                    data: None,
+                    error: Some(RequestError {
+                        message: "Unable to issue credit note".to_string(),
+                        detail: credit_note_error.describe(),
+                        problems: vec![],
+                    }),
+                }),
+            )
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CartView {
+    cart: Cart,
+    lines: Vec<CartLine>,
+    /// Current prices for the cart, left out while it is empty
+    invoice: Option<Invoice>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    problems: Vec<ValidationError>,
+}
+
+impl CartView {
+    /// Prices the cart as it stands right now. Shipping and taxes are only
+    /// known once the customer gives an address at checkout.
+    fn load(cart: Cart) -> Self {
+        let lines = cart_lines(&cart.id);
+        let mut items = cart_items(&cart.id);
+        if items.is_empty() {
+            return CartView {
+                cart,
+                lines,
+                invoice: None,
+                problems: vec![],
+            };
+        }
+
+        let mut problems = validate_items(&mut items);
+
+        let coupon = cart.coupon_code.as_ref().and_then(|code| {
+            match find_valid_coupon(code, None, &items) {
+                Ok(coupon) => Some(coupon),
+                Err(coupon_error) => {
+                    problems.push(ValidationError::new(
+                        "coupon_code",
+                        &coupon_error.describe(),
+                    ));
+                    None
+                }
+            }
+        });
+        let currency = Currency::from_code(&cart.currency).unwrap_or_else(Currency::base);
+        let conversion = Conversion::to_presentment(currency).unwrap_or_else(|exchange_error| {
+            problems.push(ValidationError::new("currency", &exchange_error.describe()));
+            Conversion::identity(Currency::base())
+        });
+        let shipping_method = ShippingMethod::from_code(&cart.shipping_method).unwrap_or_default();
+
+        let order = Order {
+            id: 0,
+            items,
+            dropped_items: vec![],
+            shipping_method,
+            currency: conversion.to,
+            cart_id: Some(cart.id.clone()),
+            invoice_number: None,
+            transaction_id: None,
+            payment_under_review: false,
+            payment_in_doubt: false,
+            payment_deferred: false,
+            payment_method_id: None,
+            customer_token: None,
+            order_token: None,
+        };
+        let invoice = build_invoice(
+            &order,
+            coupon.as_ref(),
+            None,
+            &TaxRates::none(),
+            &conversion,
+        )
+        .ok();
+
+        CartView {
+            cart,
+            lines,
+            invoice,
+            problems,
+        }
+    }
+}
+
+fn cart_error_response<T>(cart_error: CartError) -> (StatusCode, Json<DetailedResponse<T>>) {
+    let status = match cart_error {
+        CartError::NotFound | CartError::ProductNotFound => StatusCode::NOT_FOUND,
+        CartError::CheckedOut | CartError::InsufficientStock(_) => StatusCode::CONFLICT,
+        CartError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    (
+        status,
+        Json(DetailedResponse {
+            data: None,
+            error: Some(RequestError {
+                message: "Unable to update cart".to_string(),
+                detail: cart_error.describe(),
+                problems: vec![],
+            }),
+        }),
+    )
+}
+
+/// Sends the current stock of the given products to every listener
+fn broadcast_stock(state: &AppState, product_ids: Vec<i32>) {
+    use self::schema::products::dsl::*;
+
+    let conn = &mut POOL.get().unwrap();
+    let new_stock_values = products
+        .filter(id.eq_any(product_ids))
+        .load::<Product>(conn)
+        .expect("Unable to retrieve current stock values for order products");
+
+    let completion_msg = json!(new_stock_values).to_string();
+    let _ = state.tx.send(completion_msg.to_owned());
+    UDPATE_QUEUE
+        .lock()
+        .unwrap()
+        .push_back(completion_msg.to_owned());
+}
+
+fn order_error_response<T>(order_error: OrderError) -> (StatusCode, Json<DetailedResponse<T>>) {
+    let status = match order_error {
+        OrderError::NotFound | OrderError::InvoiceNotFound => StatusCode::NOT_FOUND,
+        OrderError::UnknownLine(_) | OrderError::InvalidQuantity(_) => {
+            StatusCode::UNPROCESSABLE_ENTITY
+        }
+        OrderError::InvalidStatus(_)
+        | OrderError::HeldByGateway
+        | OrderError::PartialRefundUnavailable
+        | OrderError::NothingToRefund
+        | OrderError::RefundExceedsBalance(_) => StatusCode::CONFLICT,
+        OrderError::CaptureDeclined(_) => StatusCode::PAYMENT_REQUIRED,
+        OrderError::CaptureDeferred(_)
+        | OrderError::StillInDoubt(_)
+        | OrderError::RefundFailed(_)
+        | OrderError::ReviewFailed(_) => StatusCode::BAD_GATEWAY,
+        OrderError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    (
+        status,
+        Json(DetailedResponse {
+            data: None,
+            error: Some(RequestError {
+                message: "Unable to update order".to_string(),
+                detail: order_error.describe(),
+                problems: vec![],
+            }),
+        }),
+    )
+}
+
+const ADMIN_REQUIRED: &str = "The admin token is required to change orders";
+
+fn unauthorized_response<T>(detail: &str) -> (StatusCode, Json<DetailedResponse<T>>) {
+    (
+        StatusCode::UNAUTHORIZED,
+        Json(DetailedResponse {
+            data: None,
+            error: Some(RequestError {
+                message: "Not authorized".to_string(),
+                detail: detail.to_string(),
+                problems: vec![],
+            }),
+        }),
+    )
+}
+
+/// The customer, order or admin token sent as `Authorization: Bearer <token>`
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+async fn customer_payment_methods_handler(
+    Path(email): Path<String>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<DetailedResponse<Vec<PaymentMethod>>>) {
+    match customer_payment_methods(&email, bearer_token(&headers)) {
+        Ok(methods) => (
+            StatusCode::OK,
+            Json(DetailedResponse {
+                data: Some(methods),
+                error: None,
+            }),
+        ),
+        Err(method_error) => payment_method_error_response(method_error),
+    }
+}
+
+async fn remove_payment_method_handler(
+    State(state): State<AppState>,
+    Path(payment_method_id): Path<i32>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<DetailedResponse<()>>) {
+    match remove_payment_method(
+        state.gateway.as_ref(),
+        payment_method_id,
+        bearer_token(&headers),
+    )
+    .await
+    {
+        Ok(()) => (
+            StatusCode::OK,
+            Json(DetailedResponse {
+                data: Some(()),
+                error: None,
+            }),
+        ),
+        Err(method_error) => payment_method_error_response(method_error),
+    }
+}
+
+fn payment_method_error_response<T>(
+    method_error: PaymentMethodError,
+) -> (StatusCode, Json<DetailedResponse<T>>) {
+    let status = match method_error {
+        PaymentMethodError::NotFound => StatusCode::NOT_FOUND,
+        PaymentMethodError::Unauthorized => StatusCode::UNAUTHORIZED,
+        PaymentMethodError::Expired | PaymentMethodError::NotSavable => StatusCode::CONFLICT,
+        PaymentMethodError::Gateway(_) => StatusCode::BAD_GATEWAY,
+    };
+
+    (
+        status,
+        Json(DetailedResponse {
+            data: None,
+            error: Some(RequestError {
+                message: "Unable to update payment methods".to_string(),
+                detail: method_error.describe(),
+                problems: vec![],
+            }),
+        }),
+    )
+}
+
+fn cart_response(cart: Cart) -> (StatusCode, Json<DetailedResponse<CartView>>) {
+    (
+        StatusCode::OK,
+        Json(DetailedResponse {
+            data: Some(CartView::load(cart)),
+            error: None,
+        }),
+    )
+}
+
+#[derive(Deserialize)]
+struct CartRequest {
+    #[serde(default)]
+    currency: Option<Currency>,
+    #[serde(default)]
+    shipping_method: ShippingMethod,
+    #[serde(default)]
+    coupon_code: Option<String>,
+}
+
+async fn create_cart_handler(
+    Json(req_body): Json<CartRequest>,
+) -> (StatusCode, Json<DetailedResponse<CartView>>) {
+    let currency = req_body.currency.unwrap_or_else(Currency::base);
+    if let Err(exchange_error) = Conversion::to_presentment(currency) {
+        return exchange_error_response(exchange_error);
+    }
+
+    cart_response(create_cart(
+        currency,
+        req_body.shipping_method,
+        req_body.coupon_code.as_deref(),
+    ))
+}
+
+async fn cart_data(Path(cart_id): Path<String>) -> (StatusCode, Json<DetailedResponse<CartView>>) {
+    match find_cart(&cart_id) {
+        Ok(cart) => cart_response(cart),
+        Err(cart_error) => cart_error_response(cart_error),
+    }
+}
+
+async fn update_cart_handler(
+    Path(cart_id): Path<String>,
+    Json(req_body): Json<CartRequest>,
+) -> (StatusCode, Json<DetailedResponse<CartView>>) {
+    let cart = match find_cart(&cart_id) {
+        Ok(cart) => cart,
+        Err(cart_error) => return cart_error_response(cart_error),
+    };
+
+    let currency = req_body.currency.unwrap_or_else(Currency::base);
+    if let Err(exchange_error) = Conversion::to_presentment(currency) {
+        return exchange_error_response(exchange_error);
+    }
+
+    cart_response(update_cart(
+        &cart,
+        currency,
+        req_body.shipping_method,
+        req_body.coupon_code.as_deref(),
+    ))
+}
+
+#[derive(Deserialize)]
+struct CartLineRequest {
+    /// New quantity for the line, zero removes it
+    qty: i32,
+    /// Set the units aside for a short while, see `set_cart_line`
+    #[serde(default)]
+    reserve: bool,
+}
+
+async fn update_cart_line(
+    Path((cart_id, line_product_id)): Path<(String, i32)>,
+    Json(req_body): Json<CartLineRequest>,
+) -> (StatusCode, Json<DetailedResponse<CartView>>) {
+    let cart = match find_cart(&cart_id) {
+        Ok(cart) => cart,
+        Err(cart_error) => return cart_error_response(cart_error),
+    };
+
+    if req_body.qty <= 0 {
+        remove_cart_line(&cart, line_product_id);
+        return cart_response(cart);
+    }
+
+    let problems = validate_items(&mut vec![Item {
+        id: line_product_id,
+        qty: req_body.qty,
+        price: Money::default(),
+    }]);
+    if !problems.is_empty() {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(DetailedResponse {
+                data: None,
+                error: Some(RequestError {
+                    message: "Invalid cart line".to_string(),
+                    detail: format!("{} problem(s) found with the line", problems.len()),
+                    problems,
+                }),
+            }),
+        );
+    }
+
+    match set_cart_line(&cart, line_product_id, req_body.qty, req_body.reserve) {
+        Ok(_) => cart_response(cart),
+        Err(cart_error) => cart_error_response(cart_error),
+    }
+}
+
+async fn remove_cart_line_handler(
+    Path((cart_id, line_product_id)): Path<(String, i32)>,
+) -> (StatusCode, Json<DetailedResponse<CartView>>) {
+    match find_cart(&cart_id) {
+        Ok(cart) => {
+            remove_cart_line(&cart, line_product_id);
+            cart_response(cart)
+        }
+        Err(cart_error) => cart_error_response(cart_error),
+    }
+}
+
+#[derive(Deserialize)]
+struct CheckoutRequest {
+    customer: Customer,
+    #[serde(default)]
+    allow_partial: bool,
+    #[serde(default)]
+    save_payment_method: bool,
+    #[serde(default)]
+    customer_token: Option<String>,
+}
+
+async fn checkout_cart(
+    State(state): State<AppState>,
+    Path(cart_id): Path<String>,
+    Json(req_body): Json<CheckoutRequest>,
+) -> (StatusCode, Json<DetailedResponse<Order>>) {
+    let cart = match claim_cart(&cart_id) {
+        Ok(cart) => cart,
+        Err(cart_error) => return cart_error_response(cart_error),
+    };
+
+    let order_request = CreateOrderRequest {
+        customer: req_body.customer,
+        items: cart_items(&cart.id),
+        allow_partial: req_body.allow_partial,
+        quote_token: None,
+        coupon_code: cart.coupon_code.clone(),
+        shipping_method: ShippingMethod::from_code(&cart.shipping_method).unwrap_or_default(),
+        currency: Currency::from_code(&cart.currency),
+        save_payment_method: req_body.save_payment_method,
+        customer_token: req_body.customer_token,
+    };
+
+    let response = place_order(state, order_request, Some(cart.id.clone())).await;
+    if !response.0.is_success() {
+        release_cart(&cart.id);
+    }
+
+    response
+}
+
+#[derive(Serialize)]
+struct HealthView {
+    payment_gateway: BreakerStatus,
+    deferred_payments: usize,
+}
+
+/// Reports whether the payment gateway is usable. Responds 503 while the
+/// breaker is open so load balancers and monitors can tell.
+async fn health(State(state): State<AppState>) -> (StatusCode, Json<DetailedResponse<HealthView>>) {
+    let payment_gateway = state.breaker.status();
+    let status = match payment_gateway.state {
+        BreakerState::Open => StatusCode::SERVICE_UNAVAILABLE,
+        BreakerState::Closed | BreakerState::HalfOpen => StatusCode::OK,
+    };
+
+    (
+        status,
+        Json(DetailedResponse {
+            data: Some(HealthView {
+                payment_gateway,
+                deferred_payments: DEFERRED_PAYMENTS.lock().unwrap().len(),
+            }),
+            error: None,
+        }),
+    )
+}
+
+/// Charges orders taken while the gateway was down, as soon as the breaker
+/// lets requests through again. A payment that still cannot be sent goes
+/// back to the front of the queue.
+async fn charge_deferred_payments(state: AppState) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+
+        while state.breaker.allows_requests() {
+            let deferred = DEFERRED_PAYMENTS.lock().unwrap().pop_front();
+            let deferred = match deferred {
+                Some(deferred) => deferred,
+                None => break,
+            };
+
+            if let Some(unsent) = charge_deferred(&state, deferred).await {
+                DEFERRED_PAYMENTS.lock().unwrap().push_front(unsent);
+                break;
+            }
+        }
+    }
+}
+
+/// Charges one deferred order and settles it with the result. Gives the
+/// payment back when it still could not be sent. Anything else that goes
+/// wrong is logged, so one order can never hold up the rest of the queue.
+async fn charge_deferred(state: &AppState, deferred: DeferredPayment) -> Option<DeferredPayment> {
+    let order_id = deferred.order.id as i32;
+
+    // A token that expired in the queue would only be declined
+    if !deferred
+        .customer
+        .payment
+        .can_wait(deferred.deferred_at.elapsed())
+    {
+        match cancel_deferred(order_id) {
+            Ok(order) => {
+                let expired_msg = format!(
+                    "Deferred payment for order #{} expired before the payment gateway was back",
+                    order.id
+                );
+                let _ = state.tx.send(expired_msg.to_owned());
+                UDPATE_QUEUE.lock().unwrap().push_back(expired_msg);
+                finish_screening(order_id, &deferred.screening, "error");
+
+                let order_product_ids: Vec<i32> =
+                    deferred.order.items.iter().map(|item| item.id).collect();
+                broadcast_stock(state, order_product_ids);
+            }
+            Err(order_error) => eprintln!(
+                "Unable to cancel expired deferred payment for order #{}: {}",
+                order_id,
+                order_error.describe()
+            ),
+        }
+        return None;
+    }
+
+    let payment = if SETTINGS.capture_on_shipment {
+        state
+            .gateway
+            .authorize(&deferred.order, &deferred.invoice, &deferred.customer)
+            .await
+    } else {
+        state
+            .gateway
+            .charge(&deferred.order, &deferred.invoice, &deferred.customer)
+            .await
+    };
+    // The card the gateway reports is kept with the attempt, as the invoice
+    // was issued before it was known and cannot be changed
+    record_payment(
+        order_id,
+        if SETTINGS.capture_on_shipment {
+            PaymentAction::Authorize
+        } else {
+            PaymentAction::Charge
+        },
+        Some(&deferred.invoice.total),
+        &payment,
+    );
+    if matches!(&payment, Err(gateway_error) if gateway_error.was_not_sent()) {
+        return Some(deferred);
+    }
+
+    let payment_screening = match &payment {
+        Ok(response)
+            if matches!(
+                response.outcome(),
+                PaymentOutcome::Approved | PaymentOutcome::HeldForReview(_)
+            ) =>
+        {
+            screen_payment(response)
+        }
+        _ => Screening::default(),
+    };
+    finish_screening(
+        order_id,
+        &deferred.screening.clone().merge(payment_screening.clone()),
+        payment_outcome_code(&payment),
+    );
+    match finish_deferred(order_id, &payment) {
+        Ok(order) => {
+            let order = screen_deferred(state, order, &payment_screening).await;
+            let deferred_msg = format!(
+                "Deferred payment for order #{} is {}",
+                order.id, order.payment_status
+            );
+            let _ = state.tx.send(deferred_msg.to_owned());
+            UDPATE_QUEUE.lock().unwrap().push_back(deferred_msg);
+
+            let order_product_ids: Vec<i32> =
+                deferred.order.items.iter().map(|item| item.id).collect();
+            broadcast_stock(state, order_product_ids);
+        }
+        Err(order_error) => eprintln!(
+            "Unable to finish deferred payment for order #{}: {}",
+            order_id,
+            order_error.describe()
+        ),
+    }
+
+    None
+}
+
+/// How a payment's result is stored on its fraud screening
+fn payment_outcome_code(payment: &GatewayResult<GatewayResponse>) -> &'static str {
+    match payment {
+        Ok(response) => response.outcome().code(),
+        Err(gateway_error) if gateway_error.is_in_doubt() => PaymentStatus::InDoubt.code(),
+        Err(_) => "error",
+    }
+}
+
+/// Holds or rejects a deferred order whose payment failed the address or
+/// security code check once it was finally charged. An order that cannot be
+/// rejected is left held for someone to reject by hand.
+async fn screen_deferred(
+    state: &AppState,
+    order: PlacedOrder,
+    screening: &Screening,
+) -> PlacedOrder {
+    if screening.action == FraudAction::Allow {
+        return order;
+    }
+
+    let held = match hold_for_review(order.id, &screening.reasons) {
+        Ok(held) => held,
+        Err(_) => return order,
+    };
+    if !screening.rejected() {
+        return held;
+    }
+
+    match reject_order(state.gateway.as_ref(), held.id).await {
+        Ok(rejected) => rejected,
+        Err(order_error) => {
+            eprintln!(
+                "Unable to reject order #{} after fraud screening: {}",
+                held.id,
+                order_error.describe()
+            );
+            held
+        }
+    }
+}
+
+async fn sse_handler() -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = async_stream::stream! {
+        let mut interval = tokio::time::interval(Duration::from_millis(100));
+        loop {
+            interval.tick().await;
+            let latest_update = UDPATE_QUEUE.lock().unwrap().pop_front();
+            if let Some(data) = latest_update {
+                yield Ok(Event::default().data(data));
+            }
+        }
+    };
+
+    Sse::new(stream)
+}
+
+async fn ws_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> impl IntoResponse {
+    ws.on_upgrade(|ws| async { ws_loop(state, ws).await })
+}
+
+async fn ws_loop(app_state: AppState, mut ws: WebSocket) {
+    let mut rx = app_state.tx.subscribe();
+
+    while let Ok(msg) = rx.recv().await {
+        ws.send(Message::Text(msg)).await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authorize_net::OpaqueData;
+    use crate::gateway::MockGateway;
+    use std::{env, sync::Once};
+
+    /// Points the pool at `TEST_DATABASE_URL` before anything connects
+    fn use_test_database() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            let url = env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set");
+            env::set_var("DATABASE_URL", url);
+        });
+    }
+
+    fn app_state() -> AppState {
+        let (tx, _) = broadcast::channel::<String>(100);
+        let breaker = Arc::new(CircuitBreaker::new(Arc::new(MockGateway::new())));
+        AppState {
+            tx,
+            gateway: breaker.clone(),
+            breaker,
+        }
+    }
+
+    fn customer() -> Customer {
+        let address = Address {
+            first_name: String::from("Ada"),
+            last_name: String::from("Lovelace"),
+            company: String::from(""),
+            address: String::from("12 Analytical Way"),
+            city: String::from("Springfield"),
+            state: String::from("IL"),
+            zip: String::from("62701"),
+            country: String::from("US"),
+        };
+
+        Customer {
+            first_name: String::from("Ada"),
+            last_name: String::from("Lovelace"),
+            email: String::from("ada@example.com"),
+            phone_number: String::from("555 0100 200"),
+            ip_address: String::from("127.0.0.1"),
+            billing_address: address.clone(),
+            shipping_address: address,
+            payment: Payment::OpaqueData(OpaqueData {
+                data_descriptor: String::from("COMMON.ACCEPT.INAPP.PAYMENT"),
+                data_value: String::from("mock-token-1111"),
+            }),
+            tax_exemption_id: None,
+        }
+    }
+
+    fn invoice() -> Invoice {
+        let zero = Money::zero(Currency::base());
+        Invoice {
+            lines: vec![],
+            subtotal: zero.clone(),
+            discounts: vec![],
+            discount_total: zero.clone(),
+            shipping_method: ShippingMethod::Standard,
+            shipping: zero.clone(),
+            shipping_tax: zero.clone(),
+            taxes: zero.clone(),
+            exempted_tax: zero.clone(),
+            tax_exemption_id: None,
+            prices_include_tax: false,
+            total: zero,
+            conversion: None,
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database at TEST_DATABASE_URL"]
+    async fn deferred_charge_settles_an_invoiced_order() {
+        use_test_database();
+
+        let order_id = next_order_id();
+        let order = Order {
+            id: order_id,
+            items: vec![],
+            dropped_items: vec![],
+            shipping_method: ShippingMethod::Standard,
+            currency: Currency::base(),
+            cart_id: None,
+            invoice_number: None,
+            transaction_id: None,
+            payment_under_review: false,
+            payment_in_doubt: false,
+            payment_deferred: true,
+            payment_method_id: None,
+            customer_token: None,
+            order_token: None,
+        };
+        let customer = customer();
+        let invoice = invoice();
+        let order_id = order_id as i32;
+        issue_invoice(order_id, &customer, None, &invoice).unwrap();
+        record_order(
+            &order,
+            &customer.email,
+            &invoice,
+            PaymentStatus::Deferred,
+            &[],
+        )
+        .unwrap();
+
+        let deferred = DeferredPayment {
+            order,
+            deferred_at: Instant::now(),
+            invoice,
+            customer,
+            screening: Screening::default(),
+        };
+        assert!(charge_deferred(&app_state(), deferred).await.is_none());
+
+        let expected = if SETTINGS.capture_on_shipment {
+            PaymentStatus::Authorized
+        } else {
+            PaymentStatus::Captured
+        };
+        assert_eq!(find_order(order_id).unwrap().status(), expected);
+        assert_eq!(
+            find_order_invoice(order_id).unwrap().masked_card.as_deref(),
+            Some("XXXX1111")
+        );
     }
 }
@@ -1,15 +1,31 @@
 use bigdecimal::BigDecimal;
+use chrono::{DateTime, Utc};
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
 
-use crate::schema::products;
+use crate::{
+    money::Money,
+    schema::{
+        cart_lines, carts, coupon_redemptions, credit_notes, customer_profiles, exchange_rates,
+        fraud_screenings, invoices, orders, payment_methods, payments, products, refunds,
+    },
+};
 
 #[derive(Queryable, Deserialize, Serialize)]
 pub struct Product {
     pub id: i32,
     pub title: String,
     pub stock: i32,
-    pub price: BigDecimal,
+    #[diesel(deserialize_as = BigDecimal)]
+    pub price: Money,
+    pub purchasable: bool,
+    pub max_order_qty: i32,
+    pub category: Option<String>,
+    pub tax_category: String,
+    pub weight_grams: i32,
+    pub length_mm: i32,
+    pub width_mm: i32,
+    pub height_mm: i32,
 }
 
 #[derive(Insertable)]
@@ -19,3 +35,336 @@ pub struct NewProduct<'a> {
     pub stock: &'a i32,
     pub price: &'a BigDecimal,
 }
+
+#[derive(Queryable, Clone)]
+pub struct DiscountRule {
+    pub id: i32,
+    pub name: String,
+    pub kind: String,
+    pub value: BigDecimal,
+    pub product_id: Option<i32>,
+    pub category: Option<String>,
+    pub buy_qty: Option<i32>,
+    pub get_qty: Option<i32>,
+    pub min_subtotal: Option<BigDecimal>,
+    pub stackable: bool,
+    pub exclusive_group: Option<String>,
+    pub automatic: bool,
+    pub active: bool,
+}
+
+#[derive(Queryable, Clone)]
+pub struct DiscountTier {
+    pub id: i32,
+    pub rule_id: i32,
+    pub min_subtotal: BigDecimal,
+    pub value: BigDecimal,
+}
+
+#[derive(Queryable, Clone)]
+pub struct Coupon {
+    pub id: i32,
+    pub code: String,
+    pub discount_rule_id: i32,
+    pub starts_at: Option<DateTime<Utc>>,
+    pub ends_at: Option<DateTime<Utc>>,
+    pub max_redemptions: Option<i32>,
+    pub max_redemptions_per_customer: Option<i32>,
+    pub min_subtotal: Option<BigDecimal>,
+    pub redemptions: i32,
+    pub active: bool,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = coupon_redemptions)]
+pub struct NewCouponRedemption<'a> {
+    pub coupon_id: &'a i32,
+    pub customer_email: &'a str,
+    pub order_id: &'a i32,
+}
+
+#[derive(Queryable, Clone)]
+pub struct TaxRate {
+    pub id: i32,
+    pub country: String,
+    pub state: Option<String>,
+    pub zip_prefix: Option<String>,
+    pub tax_category: String,
+    pub rate: BigDecimal,
+    pub shipping_taxable: bool,
+}
+
+#[derive(Queryable, Clone)]
+pub struct TaxExemption {
+    pub id: i32,
+    pub certificate_id: String,
+    pub customer_email: String,
+    pub country: String,
+    pub state: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub active: bool,
+}
+
+#[derive(Queryable, Clone)]
+pub struct ShippingZone {
+    pub id: i32,
+    pub name: String,
+    pub country: String,
+    pub state: Option<String>,
+}
+
+#[derive(Queryable, Clone)]
+pub struct ShippingRate {
+    pub id: i32,
+    pub zone_id: i32,
+    pub method: String,
+    pub min_weight_grams: i32,
+    pub max_weight_grams: Option<i32>,
+    pub base_fee: BigDecimal,
+    pub per_kg_fee: BigDecimal,
+}
+
+#[derive(Queryable, Clone, Serialize)]
+pub struct IssuedInvoice {
+    pub id: i32,
+    pub invoice_number: String,
+    pub order_id: i32,
+    pub customer_email: String,
+    pub currency: String,
+    pub total: BigDecimal,
+    pub document: String,
+    pub issued_at: DateTime<Utc>,
+    pub billing_address: Option<String>,
+    pub shipping_address: Option<String>,
+    pub masked_card: Option<String>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = invoices)]
+pub struct NewIssuedInvoice<'a> {
+    pub invoice_number: &'a str,
+    pub order_id: &'a i32,
+    pub customer_email: &'a str,
+    pub currency: &'a str,
+    pub total: &'a BigDecimal,
+    pub document: &'a str,
+    pub billing_address: Option<&'a str>,
+    pub shipping_address: Option<&'a str>,
+    pub masked_card: Option<&'a str>,
+}
+
+#[derive(Queryable, Clone, Serialize)]
+pub struct CreditNote {
+    pub id: i32,
+    pub credit_note_number: String,
+    pub invoice_id: i32,
+    pub reason: String,
+    pub note: Option<String>,
+    pub amount: BigDecimal,
+    pub issued_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = credit_notes)]
+pub struct NewCreditNote<'a> {
+    pub credit_note_number: &'a str,
+    pub invoice_id: &'a i32,
+    pub reason: &'a str,
+    pub note: Option<&'a str>,
+    pub amount: &'a BigDecimal,
+}
+
+#[derive(Queryable, Clone, Serialize)]
+pub struct ExchangeRate {
+    pub currency: String,
+    pub rate: BigDecimal,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, AsChangeset)]
+#[diesel(table_name = exchange_rates)]
+pub struct NewExchangeRate<'a> {
+    pub currency: &'a str,
+    pub rate: &'a BigDecimal,
+    pub updated_at: &'a DateTime<Utc>,
+}
+
+#[derive(Queryable, Clone, Serialize)]
+pub struct Cart {
+    pub id: String,
+    pub currency: String,
+    pub shipping_method: String,
+    pub coupon_code: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub checked_out_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = carts)]
+pub struct NewCart<'a> {
+    pub id: &'a str,
+    pub currency: &'a str,
+    pub shipping_method: &'a str,
+    pub coupon_code: Option<&'a str>,
+}
+
+#[derive(Queryable, Clone, Serialize)]
+pub struct CartLine {
+    pub id: i32,
+    pub cart_id: String,
+    pub product_id: i32,
+    pub qty: i32,
+    pub reserved_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = cart_lines)]
+pub struct NewCartLine<'a> {
+    pub cart_id: &'a str,
+    pub product_id: &'a i32,
+    pub qty: &'a i32,
+    pub reserved_until: Option<&'a DateTime<Utc>>,
+}
+
+#[derive(Queryable, Clone, Serialize)]
+pub struct PlacedOrder {
+    pub id: i32,
+    pub customer_email: String,
+    pub currency: String,
+    pub total: BigDecimal,
+    pub transaction_id: Option<String>,
+    pub payment_status: String,
+    pub capture_amount: Option<BigDecimal>,
+    pub captured_amount: BigDecimal,
+    pub shipped_lines: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub shipped_at: Option<DateTime<Utc>>,
+    pub captured_at: Option<DateTime<Utc>>,
+    pub refunded_amount: BigDecimal,
+    /// JSON list of why the order was held for manual review
+    pub review_reasons: Option<String>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = orders)]
+pub struct NewPlacedOrder<'a> {
+    pub id: &'a i32,
+    pub customer_email: &'a str,
+    pub currency: &'a str,
+    pub total: &'a BigDecimal,
+    pub transaction_id: Option<&'a str>,
+    pub payment_status: &'a str,
+    pub captured_amount: &'a BigDecimal,
+    pub captured_at: Option<&'a DateTime<Utc>>,
+    pub review_reasons: Option<&'a str>,
+}
+
+#[derive(Queryable, Clone, Serialize)]
+pub struct Refund {
+    pub id: i32,
+    pub order_id: i32,
+    pub kind: String,
+    pub amount: BigDecimal,
+    pub transaction_id: Option<String>,
+    pub credit_note_number: Option<String>,
+    pub lines: String,
+    pub restocked: bool,
+    pub note: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = refunds)]
+pub struct NewRefund<'a> {
+    pub order_id: &'a i32,
+    pub kind: &'a str,
+    pub amount: &'a BigDecimal,
+    pub transaction_id: Option<&'a str>,
+    pub credit_note_number: Option<&'a str>,
+    pub lines: &'a str,
+    pub restocked: &'a bool,
+    pub note: Option<&'a str>,
+}
+
+#[derive(Queryable, Clone, Serialize)]
+pub struct PaymentAttempt {
+    pub id: i32,
+    pub order_id: i32,
+    pub action: String,
+    pub amount: Option<BigDecimal>,
+    pub currency: Option<String>,
+    pub outcome: String,
+    pub message: Option<String>,
+    pub transaction_id: Option<String>,
+    pub response_code: Option<String>,
+    pub result_code: Option<String>,
+    pub auth_code: Option<String>,
+    pub avs_result_code: Option<String>,
+    pub cvv_result_code: Option<String>,
+    pub account_type: Option<String>,
+    pub account_number: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = payments)]
+pub struct NewPaymentAttempt<'a> {
+    pub order_id: &'a i32,
+    pub action: &'a str,
+    pub amount: Option<&'a BigDecimal>,
+    pub currency: Option<&'a str>,
+    pub outcome: &'a str,
+    pub message: Option<&'a str>,
+    pub transaction_id: Option<&'a str>,
+    pub response_code: Option<&'a str>,
+    pub result_code: Option<&'a str>,
+    pub auth_code: Option<&'a str>,
+    pub avs_result_code: Option<&'a str>,
+    pub cvv_result_code: Option<&'a str>,
+    pub account_type: Option<&'a str>,
+    pub account_number: Option<&'a str>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = customer_profiles)]
+pub struct NewCustomerProfile<'a> {
+    pub customer_email: &'a str,
+    pub profile_id: &'a str,
+}
+
+/// A saved card as the customer sees it. The gateway's id for it is kept
+/// out of responses.
+#[derive(Queryable, Clone, Serialize)]
+pub struct PaymentMethod {
+    pub id: i32,
+    #[serde(skip_serializing)]
+    pub customer_email: String,
+    #[serde(skip_serializing)]
+    pub payment_profile_id: String,
+    pub card_type: Option<String>,
+    pub masked_card: String,
+    pub expiration_date: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = payment_methods)]
+pub struct NewPaymentMethod<'a> {
+    pub customer_email: &'a str,
+    pub payment_profile_id: &'a str,
+    pub card_type: Option<&'a str>,
+    pub masked_card: &'a str,
+    pub expiration_date: Option<&'a str>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = fraud_screenings)]
+pub struct NewFraudScreening<'a> {
+    pub order_id: &'a i32,
+    pub customer_email: &'a str,
+    pub ip_address: &'a str,
+    pub card_fingerprint: Option<&'a str>,
+    pub decision: &'a str,
+    pub reasons: Option<&'a str>,
+}
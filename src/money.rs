@@ -0,0 +1,354 @@
+use bigdecimal::{BigDecimal, FromPrimitive, Zero};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::{
+    cmp::Ordering,
+    fmt,
+    ops::{Add, AddAssign, Sub, SubAssign},
+    str::FromStr,
+};
+
+use crate::settings::SETTINGS;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Currency {
+    #[default]
+    USD,
+    EUR,
+    GBP,
+    CAD,
+    JPY,
+}
+
+impl Currency {
+    /// Number of decimal places amounts in this currency are kept at
+    pub fn minor_units(&self) -> i64 {
+        match self {
+            Currency::JPY => 0,
+            _ => 2,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Currency::USD => "USD",
+            Currency::EUR => "EUR",
+            Currency::GBP => "GBP",
+            Currency::CAD => "CAD",
+            Currency::JPY => "JPY",
+        }
+    }
+
+    /// The store's base currency, see `StoreSettings::base_currency`
+    pub fn base() -> Self {
+        SETTINGS.base_currency
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        match code.trim().to_uppercase().as_str() {
+            "USD" => Some(Currency::USD),
+            "EUR" => Some(Currency::EUR),
+            "GBP" => Some(Currency::GBP),
+            "CAD" => Some(Currency::CAD),
+            "JPY" => Some(Currency::JPY),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rounding {
+    /// Ties round away from zero, e.g. 0.125 -> 0.13
+    HalfUp,
+    /// Ties round to the nearest even digit, e.g. 0.125 -> 0.12
+    HalfEven,
+    /// Always towards zero
+    Down,
+    /// Always away from zero
+    Up,
+}
+
+/// An amount in a specific currency, always held at the currency's number of
+/// minor units. Arithmetic between different currencies panics, since that is
+/// always a bug in the caller rather than something to recover from.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Money {
+    pub amount: BigDecimal,
+    pub currency: Currency,
+}
+
+impl Money {
+    pub fn new(amount: BigDecimal, currency: Currency) -> Self {
+        Self::rounded(&amount, currency, Rounding::HalfUp)
+    }
+
+    pub fn rounded(amount: &BigDecimal, currency: Currency, rounding: Rounding) -> Self {
+        Money {
+            amount: round_to(amount, currency.minor_units(), rounding),
+            currency,
+        }
+    }
+
+    pub fn zero(currency: Currency) -> Self {
+        Self::new(BigDecimal::zero(), currency)
+    }
+
+    pub fn parse(amount: &str, currency: Currency) -> Option<Self> {
+        BigDecimal::from_str(amount.trim())
+            .ok()
+            .map(|amount| Self::new(amount, currency))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.amount.is_zero()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.amount < BigDecimal::zero()
+    }
+
+    pub fn times(&self, qty: i32) -> Self {
+        Self::new(&self.amount * BigDecimal::from(qty), self.currency)
+    }
+
+    /// Multiplies by a rate such as a tax or discount percentage, rounding the
+    /// result back to the currency's minor units
+    pub fn apply_rate(&self, rate: &BigDecimal, rounding: Rounding) -> Self {
+        Self::rounded(&(&self.amount * rate), self.currency, rounding)
+    }
+
+    /// Never lets an amount drop below zero
+    pub fn floor_at_zero(self) -> Self {
+        if self.is_negative() {
+            Self::zero(self.currency)
+        } else {
+            self
+        }
+    }
+
+    pub fn min(self, other: Self) -> Self {
+        self.assert_same_currency(&other);
+        if other.amount < self.amount {
+            other
+        } else {
+            self
+        }
+    }
+
+    fn assert_same_currency(&self, other: &Money) {
+        assert_eq!(
+            self.currency,
+            other.currency,
+            "Cannot combine {} and {} amounts",
+            self.currency.code(),
+            other.currency.code()
+        );
+    }
+}
+
+/// Amounts from outside are held to the same precision as those made with
+/// `Money::new`. An amount smaller than the currency's minor unit would be
+/// rounded to something the sender never asked for, so it is refused.
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct RawMoney {
+            amount: BigDecimal,
+            currency: Currency,
+        }
+
+        let raw = RawMoney::deserialize(deserializer)?;
+        let scale = raw.currency.minor_units();
+        if round_to(&raw.amount, scale, Rounding::Down) != raw.amount {
+            return Err(de::Error::custom(format!(
+                "{} amounts may have at most {} decimal places",
+                raw.currency.code(),
+                scale
+            )));
+        }
+
+        Ok(Money {
+            amount: raw.amount.with_scale(scale),
+            currency: raw.currency,
+        })
+    }
+}
+
+impl Default for Money {
+    fn default() -> Self {
+        Self::zero(Currency::base())
+    }
+}
+
+impl From<BigDecimal> for Money {
+    /// Amounts stored without a currency are in the store's base currency
+    fn from(amount: BigDecimal) -> Self {
+        Self::new(amount, Currency::base())
+    }
+}
+
+/// Formats just the amount at the currency's precision, which is the form the
+/// payment gateway expects
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.amount.with_scale(self.currency.minor_units()))
+    }
+}
+
+impl PartialOrd for Money {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self.currency == other.currency {
+            self.amount.partial_cmp(&other.amount)
+        } else {
+            None
+        }
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, other: Money) -> Money {
+        self.assert_same_currency(&other);
+        Money::new(self.amount + other.amount, self.currency)
+    }
+}
+
+impl AddAssign for Money {
+    fn add_assign(&mut self, other: Money) {
+        *self = self.clone() + other;
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, other: Money) -> Money {
+        self.assert_same_currency(&other);
+        Money::new(self.amount - other.amount, self.currency)
+    }
+}
+
+impl SubAssign for Money {
+    fn sub_assign(&mut self, other: Money) {
+        *self = self.clone() - other;
+    }
+}
+
+fn round_to(amount: &BigDecimal, scale: i64, rounding: Rounding) -> BigDecimal {
+    let truncated = amount.with_scale(scale);
+    let remainder = amount - &truncated;
+    if remainder.is_zero() {
+        return truncated;
+    }
+
+    let unit = BigDecimal::from(1) / BigDecimal::from_i64(10_i64.pow(scale as u32)).unwrap();
+    let away_from_zero = if amount < &BigDecimal::zero() {
+        &truncated - &unit
+    } else {
+        &truncated + &unit
+    };
+
+    let round_away = match rounding {
+        Rounding::Down => false,
+        Rounding::Up => true,
+        Rounding::HalfUp | Rounding::HalfEven => {
+            match (remainder.abs() * BigDecimal::from(2)).cmp(&unit) {
+                Ordering::Greater => true,
+                Ordering::Less => false,
+                Ordering::Equal => {
+                    rounding == Rounding::HalfUp || {
+                        let last_digit = (&truncated / &unit).with_scale(0) % BigDecimal::from(2);
+                        !last_digit.is_zero()
+                    }
+                }
+            }
+        }
+    };
+
+    if round_away {
+        away_from_zero.with_scale(scale)
+    } else {
+        truncated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decimal(value: &str) -> BigDecimal {
+        BigDecimal::from_str(value).unwrap()
+    }
+
+    #[test]
+    fn round_to_leaves_exact_amounts_alone() {
+        assert_eq!(round_to(&decimal("1.25"), 2, Rounding::Up), decimal("1.25"));
+        assert_eq!(round_to(&decimal("7"), 0, Rounding::Down), decimal("7"));
+    }
+
+    #[test]
+    fn round_to_half_up_rounds_ties_away_from_zero() {
+        assert_eq!(
+            round_to(&decimal("0.125"), 2, Rounding::HalfUp),
+            decimal("0.13")
+        );
+        assert_eq!(
+            round_to(&decimal("-0.125"), 2, Rounding::HalfUp),
+            decimal("-0.13")
+        );
+        assert_eq!(
+            round_to(&decimal("0.124"), 2, Rounding::HalfUp),
+            decimal("0.12")
+        );
+    }
+
+    #[test]
+    fn round_to_half_even_rounds_ties_to_even() {
+        assert_eq!(
+            round_to(&decimal("0.125"), 2, Rounding::HalfEven),
+            decimal("0.12")
+        );
+        assert_eq!(
+            round_to(&decimal("0.135"), 2, Rounding::HalfEven),
+            decimal("0.14")
+        );
+        assert_eq!(
+            round_to(&decimal("0.1251"), 2, Rounding::HalfEven),
+            decimal("0.13")
+        );
+    }
+
+    #[test]
+    fn round_to_down_and_up() {
+        assert_eq!(
+            round_to(&decimal("1.999"), 2, Rounding::Down),
+            decimal("1.99")
+        );
+        assert_eq!(
+            round_to(&decimal("1.001"), 2, Rounding::Up),
+            decimal("1.01")
+        );
+        assert_eq!(
+            round_to(&decimal("-1.001"), 2, Rounding::Up),
+            decimal("-1.01")
+        );
+        assert_eq!(round_to(&decimal("1.5"), 0, Rounding::Down), decimal("1"));
+    }
+
+    #[test]
+    fn deserialize_keeps_whole_minor_units() {
+        let money: Money =
+            serde_json::from_str(r#"{"amount": "12.5", "currency": "USD"}"#).unwrap();
+        assert_eq!(money, Money::new(decimal("12.50"), Currency::USD));
+        assert_eq!(money.to_string(), "12.50");
+    }
+
+    #[test]
+    fn deserialize_refuses_sub_unit_amounts() {
+        assert!(
+            serde_json::from_str::<Money>(r#"{"amount": "0.001", "currency": "USD"}"#).is_err()
+        );
+        assert!(serde_json::from_str::<Money>(r#"{"amount": "5.5", "currency": "JPY"}"#).is_err());
+        assert!(serde_json::from_str::<Money>(r#"{"amount": "500", "currency": "JPY"}"#).is_ok());
+    }
+}
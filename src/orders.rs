@@ -0,0 +1,894 @@
+use bigdecimal::BigDecimal;
+use chrono::Utc;
+use diesel::{prelude::*, sql_types::BigInt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    coupons::release_order_coupons,
+    db::{DatabaseError, TransactionError, POOL},
+    ecommerce::{Invoice, InvoiceLine},
+    fraud::encode_reasons,
+    gateway::{
+        resolve_in_doubt, GatewayResponse, GatewayResult, PaymentGateway, PaymentOutcome,
+        TransactionStatus,
+    },
+    inventory::Order,
+    invoicing::{find_order_invoice, issue_credit_note, CreditReason},
+    models::*,
+    money::{Currency, Money, Rounding},
+    payments::{record_payment, PaymentAction},
+    settings::SETTINGS,
+};
+
+/// How long a transaction may take to show up at the gateway. An in doubt
+/// order is only given up on once it is older than this.
+const IN_DOUBT_GRACE_MINUTES: i64 = 30;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentStatus {
+    /// Funds are authorized and will be captured once the order ships
+    Authorized,
+    /// Authorized, but held by the gateway's fraud filters or our own until
+    /// someone approves or rejects it
+    UnderReview,
+    /// Shipped and waiting for the capture job
+    CapturePending,
+    /// A capture request is with the gateway right now
+    Capturing,
+    Captured,
+    /// The gateway refused the capture, usually because the authorization
+    /// expired
+    CaptureFailed,
+    /// A refund or void is with the gateway right now
+    Refunding,
+    PartiallyRefunded,
+    Refunded,
+    /// Cancelled before the payment settled
+    Voided,
+    /// No reply came back from the gateway, so it is not known yet whether
+    /// the customer paid
+    InDoubt,
+    /// The payment turned out never to have been taken
+    Failed,
+    /// Taken while the gateway was down and charged once it is back
+    Deferred,
+}
+
+impl PaymentStatus {
+    pub fn code(&self) -> &'static str {
+        match self {
+            PaymentStatus::Authorized => "authorized",
+            PaymentStatus::UnderReview => "under_review",
+            PaymentStatus::CapturePending => "capture_pending",
+            PaymentStatus::Capturing => "capturing",
+            PaymentStatus::Captured => "captured",
+            PaymentStatus::CaptureFailed => "capture_failed",
+            PaymentStatus::Refunding => "refunding",
+            PaymentStatus::PartiallyRefunded => "partially_refunded",
+            PaymentStatus::Refunded => "refunded",
+            PaymentStatus::Voided => "voided",
+            PaymentStatus::InDoubt => "in_doubt",
+            PaymentStatus::Failed => "failed",
+            PaymentStatus::Deferred => "deferred",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        match code {
+            "authorized" => Some(PaymentStatus::Authorized),
+            "under_review" => Some(PaymentStatus::UnderReview),
+            "capture_pending" => Some(PaymentStatus::CapturePending),
+            "capturing" => Some(PaymentStatus::Capturing),
+            "captured" => Some(PaymentStatus::Captured),
+            "capture_failed" => Some(PaymentStatus::CaptureFailed),
+            "refunding" => Some(PaymentStatus::Refunding),
+            "partially_refunded" => Some(PaymentStatus::PartiallyRefunded),
+            "refunded" => Some(PaymentStatus::Refunded),
+            "voided" => Some(PaymentStatus::Voided),
+            "in_doubt" => Some(PaymentStatus::InDoubt),
+            "failed" => Some(PaymentStatus::Failed),
+            "deferred" => Some(PaymentStatus::Deferred),
+            _ => None,
+        }
+    }
+}
+
+impl PlacedOrder {
+    pub fn status(&self) -> PaymentStatus {
+        PaymentStatus::from_code(&self.payment_status).expect("Stored order has no payment status")
+    }
+
+    pub fn currency(&self) -> Currency {
+        Currency::from_code(&self.currency).expect("Stored order has no currency")
+    }
+}
+
+pub enum OrderError {
+    NotFound,
+    /// The order is not in a state that allows this
+    InvalidStatus(PaymentStatus),
+    InvoiceNotFound,
+    UnknownLine(i32),
+    InvalidQuantity(i32),
+    /// The capture went to the gateway and was refused
+    CaptureDeclined(String),
+    /// The capture could not be completed and will be retried
+    CaptureDeferred(String),
+    /// The gateway still cannot say what became of the payment
+    StillInDoubt(String),
+    /// Payments can only be cancelled as a whole until they are captured
+    /// and settled
+    PartialRefundUnavailable,
+    NothingToRefund,
+    RefundExceedsBalance(Money),
+    RefundFailed(String),
+    /// The gateway's fraud filters still hold the payment, so it has to be
+    /// approved there first
+    HeldByGateway,
+    /// The gateway could not be asked about or void the payment
+    ReviewFailed(String),
+    Database(DatabaseError),
+}
+
+impl From<DatabaseError> for OrderError {
+    fn from(error: DatabaseError) -> Self {
+        OrderError::Database(error)
+    }
+}
+
+impl OrderError {
+    pub fn describe(&self) -> String {
+        match self {
+            OrderError::NotFound => "This order does not exist".to_string(),
+            OrderError::InvalidStatus(status) => {
+                format!("This order's payment is {}", status.code())
+            }
+            OrderError::InvoiceNotFound => "This order has not been invoiced".to_string(),
+            OrderError::UnknownLine(product_id) => {
+                format!("Product {} is not part of this order", product_id)
+            }
+            OrderError::InvalidQuantity(product_id) => format!(
+                "The quantity of product {} must be between 1 and the units left on the order",
+                product_id
+            ),
+            OrderError::CaptureDeclined(reason) => format!("The capture was declined: {}", reason),
+            OrderError::CaptureDeferred(reason) => {
+                format!("The capture will be retried later: {}", reason)
+            }
+            OrderError::StillInDoubt(reason) => {
+                format!("The payment is still in doubt: {}", reason)
+            }
+            OrderError::PartialRefundUnavailable => {
+                "Only the whole order can be refunded until its payment settles".to_string()
+            }
+            OrderError::NothingToRefund => "Nothing is left to refund on this order".to_string(),
+            OrderError::RefundExceedsBalance(balance) => format!(
+                "Only {} {} is left to refund on this order",
+                balance,
+                balance.currency.code()
+            ),
+            OrderError::RefundFailed(reason) => format!("The refund failed: {}", reason),
+            OrderError::HeldByGateway => {
+                "The payment gateway is still holding this payment for review".to_string()
+            }
+            OrderError::ReviewFailed(reason) => {
+                format!("The review could not be completed: {}", reason)
+            }
+            OrderError::Database(error) => error.describe(),
+        }
+    }
+}
+
+/// A product on an order and a number of its units, such as the units that
+/// went out in a shipment
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LineQuantity {
+    pub product_id: i32,
+    pub qty: i32,
+}
+
+/// Adds up lines naming the same product, so their units are only counted
+/// once against the order. Every line has to be for at least one unit.
+pub fn merge_lines(lines: &[LineQuantity]) -> Result<Vec<LineQuantity>, OrderError> {
+    let mut merged: Vec<LineQuantity> = vec![];
+
+    for line in lines {
+        if line.qty < 1 {
+            return Err(OrderError::InvalidQuantity(line.product_id));
+        }
+        match merged
+            .iter_mut()
+            .find(|merged_line| merged_line.product_id == line.product_id)
+        {
+            Some(merged_line) => {
+                merged_line.qty = merged_line
+                    .qty
+                    .checked_add(line.qty)
+                    .ok_or(OrderError::InvalidQuantity(line.product_id))?
+            }
+            None => merged.push(line.clone()),
+        }
+    }
+
+    Ok(merged)
+}
+
+pub fn next_order_id() -> usize {
+    let conn = &mut POOL.get().unwrap();
+
+    diesel::select(diesel::dsl::sql::<BigInt>("nextval('order_ids')"))
+        .get_result::<i64>(conn)
+        .expect("Unable to allocate order id") as usize
+}
+
+/// Stores an order whose payment went through. Orders paid with an
+/// immediate capture are stored as captured straight away. `review_reasons`
+/// are why our fraud rules held the order for review.
+pub fn record_order(
+    order: &Order,
+    customer_email: &str,
+    invoice: &Invoice,
+    status: PaymentStatus,
+    review_reasons: &[String],
+) -> QueryResult<PlacedOrder> {
+    use crate::schema::orders;
+
+    let conn = &mut POOL.get().unwrap();
+    let (captured_amount, captured_at) = if status == PaymentStatus::Captured {
+        (invoice.total.amount.clone(), Some(Utc::now()))
+    } else {
+        (BigDecimal::from(0), None)
+    };
+    let review_reasons = encode_reasons(review_reasons);
+
+    diesel::insert_into(orders::table)
+        .values(&NewPlacedOrder {
+            id: &(order.id as i32),
+            customer_email,
+            currency: invoice.total.currency.code(),
+            total: &invoice.total.amount,
+            transaction_id: order.transaction_id.as_deref(),
+            payment_status: status.code(),
+            captured_amount: &captured_amount,
+            captured_at: captured_at.as_ref(),
+            review_reasons: review_reasons.as_deref(),
+        })
+        .get_result(conn)
+}
+
+pub fn find_order(order_id: i32) -> Option<PlacedOrder> {
+    use crate::schema::orders;
+
+    let conn = &mut POOL.get().unwrap();
+
+    orders::table
+        .find(order_id)
+        .first(conn)
+        .optional()
+        .expect("Unable to look up order")
+}
+
+/// Shipped orders still waiting to be captured, oldest first
+pub fn pending_captures() -> Vec<PlacedOrder> {
+    use crate::schema::orders;
+
+    let conn = &mut POOL.get().unwrap();
+
+    orders::table
+        .filter(orders::payment_status.eq(PaymentStatus::CapturePending.code()))
+        .order(orders::shipped_at)
+        .load::<PlacedOrder>(conn)
+        .expect("Unable to load orders awaiting capture")
+}
+
+/// Marks an authorized order as shipped and works out how much to capture.
+/// Without `lines` everything on the invoice shipped. An authorization can
+/// only be captured once, so whatever does not ship now is released.
+pub fn mark_shipped(
+    order_id: i32,
+    lines: Option<&[LineQuantity]>,
+) -> Result<PlacedOrder, OrderError> {
+    use crate::schema::orders;
+
+    find_order(order_id).ok_or(OrderError::NotFound)?;
+    let record = find_order_invoice(order_id).ok_or(OrderError::InvoiceNotFound)?;
+    let lines = lines.map(merge_lines).transpose()?;
+    let lines = lines.as_deref();
+    let capture_amount = match lines {
+        Some(lines) => shipped_amount(&record.invoice, lines)?,
+        None => record.invoice.total.clone(),
+    };
+    let shipped_lines =
+        lines.map(|lines| serde_json::to_string(lines).expect("Unable to serialize shipped lines"));
+
+    let conn = &mut POOL.get().unwrap();
+
+    conn.build_transaction()
+        .read_write()
+        .run::<PlacedOrder, TransactionError<OrderError>, _>(|conn| {
+            let order: PlacedOrder = orders::table
+                .find(order_id)
+                .for_update()
+                .first(conn)
+                .optional()?
+                .ok_or(OrderError::NotFound)?;
+            if order.status() != PaymentStatus::Authorized {
+                return Err(OrderError::InvalidStatus(order.status()).into());
+            }
+
+            Ok(diesel::update(orders::table.find(order_id))
+                .set((
+                    orders::payment_status.eq(PaymentStatus::CapturePending.code()),
+                    orders::capture_amount.eq(&capture_amount.amount),
+                    orders::shipped_lines.eq(&shipped_lines),
+                    orders::shipped_at.eq(Utc::now()),
+                ))
+                .get_result(conn)?)
+        })
+        .map_err(TransactionError::into_domain)
+}
+
+/// Captures a shipped order. The order is moved to `capturing` first so the
+/// capture job and a shipment can never capture it twice. When less than the
+/// invoice total is captured, the difference is credited on the invoice.
+pub async fn capture_order(
+    gateway: &dyn PaymentGateway,
+    order_id: i32,
+) -> Result<PlacedOrder, OrderError> {
+    let order = claim_capture(order_id)?;
+    let currency = order.currency();
+    let amount = Money::new(
+        order
+            .capture_amount
+            .clone()
+            .expect("Shipped order has no capture amount"),
+        currency,
+    );
+    let transaction_id = order
+        .transaction_id
+        .as_deref()
+        .expect("Authorized order has no transaction id");
+
+    // A capture whose reply was lost may have gone through anyway, so the
+    // transaction is checked first and never captured twice
+    let already_captured = match gateway.transaction_status(transaction_id).await {
+        Ok(status) => matches!(
+            status,
+            TransactionStatus::CapturedPendingSettlement | TransactionStatus::Settled
+        ),
+        Err(gateway_error) => {
+            set_status(order_id, PaymentStatus::CapturePending);
+            return Err(OrderError::CaptureDeferred(gateway_error.describe()));
+        }
+    };
+
+    let outcome = if already_captured {
+        PaymentOutcome::Approved
+    } else {
+        let result = gateway.capture(transaction_id, &amount).await;
+        record_payment(order_id, PaymentAction::Capture, Some(&amount), &result);
+        match result {
+            Ok(response) => response.outcome(),
+            Err(gateway_error) => {
+                set_status(order_id, PaymentStatus::CapturePending);
+                return Err(OrderError::CaptureDeferred(gateway_error.describe()));
+            }
+        }
+    };
+
+    match outcome {
+        PaymentOutcome::Approved => {
+            let record = find_order_invoice(order_id);
+            let shipped: Option<Vec<LineQuantity>> = order
+                .shipped_lines
+                .as_deref()
+                .and_then(|shipped| serde_json::from_str(shipped).ok());
+            let unshipped = match (&record, &shipped) {
+                (Some(record), Some(shipped)) => unshipped_lines(&record.invoice, shipped),
+                _ => vec![],
+            };
+            let captured = finish_capture(order_id, &amount, &unshipped);
+
+            let released = Money::new(&order.total - &amount.amount, currency);
+            if !released.is_zero() {
+                if let Some(record) = record {
+                    if let Err(error) = issue_credit_note(
+                        &record.invoice_number,
+                        CreditReason::Cancellation,
+                        Some(&released),
+                        Some("Not shipped, authorization released"),
+                    ) {
+                        eprintln!(
+                            "Unable to credit unshipped items on order #{}: {}",
+                            order_id,
+                            error.describe()
+                        );
+                    }
+                }
+            }
+
+            Ok(captured)
+        }
+        PaymentOutcome::Declined(reason) => {
+            set_status(order_id, PaymentStatus::CaptureFailed);
+            Err(OrderError::CaptureDeclined(reason))
+        }
+        // The gateway holds the funds until the capture is approved there, so
+        // the order waits and is checked again on the next capture run
+        PaymentOutcome::HeldForReview(reason) => {
+            set_status(order_id, PaymentStatus::CapturePending);
+            Err(OrderError::CaptureDeferred(format!(
+                "Capture is held for review: {}",
+                reason
+            )))
+        }
+        PaymentOutcome::Error(reason) => {
+            set_status(order_id, PaymentStatus::CapturePending);
+            Err(OrderError::CaptureDeferred(reason))
+        }
+    }
+}
+
+/// Orders whose payment reply was lost, oldest first
+pub fn in_doubt_orders() -> Vec<PlacedOrder> {
+    use crate::schema::orders;
+
+    let conn = &mut POOL.get().unwrap();
+
+    orders::table
+        .filter(orders::payment_status.eq(PaymentStatus::InDoubt.code()))
+        .order(orders::created_at)
+        .load::<PlacedOrder>(conn)
+        .expect("Unable to load orders in doubt")
+}
+
+/// Settles an in doubt order by looking its transaction up at the gateway.
+/// Orders the gateway never saw or declined give their stock and coupons
+/// back and have their invoice cancelled. While the gateway cannot be
+/// reached, or the order is too recent for its transaction to be sure to
+/// show up, the order stays in doubt.
+pub async fn resolve_order(
+    gateway: &dyn PaymentGateway,
+    order_id: i32,
+) -> Result<PlacedOrder, OrderError> {
+    let order = find_order(order_id).ok_or(OrderError::NotFound)?;
+    if order.status() != PaymentStatus::InDoubt {
+        return Err(OrderError::InvalidStatus(order.status()));
+    }
+
+    let response = resolve_in_doubt(gateway, &order_id.to_string(), order.created_at)
+        .await
+        .map_err(|gateway_error| OrderError::StillInDoubt(gateway_error.describe()))?;
+    match &response {
+        Some(response) => {
+            record_payment(order_id, PaymentAction::Lookup, None, &Ok(response.clone()))
+        }
+        None if Utc::now() - order.created_at
+            < chrono::Duration::minutes(IN_DOUBT_GRACE_MINUTES) =>
+        {
+            return Err(OrderError::StillInDoubt(String::from(
+                "The transaction has not shown up at the gateway yet",
+            )))
+        }
+        None => {}
+    }
+
+    Ok(settle_order(&order, response.as_ref()))
+}
+
+/// Orders held for review, oldest first
+pub fn orders_under_review() -> Vec<PlacedOrder> {
+    use crate::schema::orders;
+
+    let conn = &mut POOL.get().unwrap();
+
+    orders::table
+        .filter(orders::payment_status.eq(PaymentStatus::UnderReview.code()))
+        .order(orders::created_at)
+        .load::<PlacedOrder>(conn)
+        .expect("Unable to load orders under review")
+}
+
+/// Orders taken while the gateway was down, oldest first
+pub fn deferred_orders() -> Vec<PlacedOrder> {
+    use crate::schema::orders;
+
+    let conn = &mut POOL.get().unwrap();
+
+    orders::table
+        .filter(orders::payment_status.eq(PaymentStatus::Deferred.code()))
+        .order(orders::created_at)
+        .load::<PlacedOrder>(conn)
+        .expect("Unable to load deferred orders")
+}
+
+/// Settles a deferred order with the result of finally charging it. Orders
+/// whose charge went out without a reply are left in doubt.
+pub fn finish_deferred(
+    order_id: i32,
+    result: &GatewayResult<GatewayResponse>,
+) -> Result<PlacedOrder, OrderError> {
+    let order = find_order(order_id).ok_or(OrderError::NotFound)?;
+    if order.status() != PaymentStatus::Deferred {
+        return Err(OrderError::InvalidStatus(order.status()));
+    }
+
+    match result {
+        Ok(response) => Ok(settle_order(&order, Some(response))),
+        Err(gateway_error) if gateway_error.is_in_doubt() => {
+            set_status(order_id, PaymentStatus::InDoubt);
+            find_order(order_id).ok_or(OrderError::NotFound)
+        }
+        Err(_) => Ok(fail_order(
+            &order,
+            PaymentStatus::Failed,
+            "Payment was never taken",
+        )),
+    }
+}
+
+/// Gives up on a deferred order that can no longer be charged
+pub fn cancel_deferred(order_id: i32) -> Result<PlacedOrder, OrderError> {
+    let order = find_order(order_id).ok_or(OrderError::NotFound)?;
+    if order.status() != PaymentStatus::Deferred {
+        return Err(OrderError::InvalidStatus(order.status()));
+    }
+
+    Ok(fail_order(
+        &order,
+        PaymentStatus::Failed,
+        "Payment was never taken",
+    ))
+}
+
+/// Moves an order on from the gateway's answer to its payment. `None` means
+/// the payment was never made. Orders our fraud rules held stay held once
+/// paid.
+fn settle_order(order: &PlacedOrder, response: Option<&GatewayResponse>) -> PlacedOrder {
+    use crate::schema::orders;
+
+    let never_paid = || fail_order(order, PaymentStatus::Failed, "Payment was never taken");
+    let (status, transaction_id) = match response {
+        Some(response) => match response.outcome() {
+            PaymentOutcome::Approved if order.review_reasons.is_some() => {
+                (PaymentStatus::UnderReview, &response.transaction_id)
+            }
+            PaymentOutcome::Approved if SETTINGS.capture_on_shipment => {
+                (PaymentStatus::Authorized, &response.transaction_id)
+            }
+            PaymentOutcome::Approved => (PaymentStatus::Captured, &response.transaction_id),
+            PaymentOutcome::HeldForReview(_) => {
+                (PaymentStatus::UnderReview, &response.transaction_id)
+            }
+            PaymentOutcome::Declined(_) | PaymentOutcome::Error(_) => return never_paid(),
+        },
+        None => return never_paid(),
+    };
+
+    let conn = &mut POOL.get().unwrap();
+    let captured = status == PaymentStatus::Captured;
+
+    diesel::update(orders::table.find(order.id))
+        .set((
+            orders::payment_status.eq(status.code()),
+            orders::transaction_id.eq(transaction_id),
+            orders::captured_amount.eq(if captured {
+                order.total.clone()
+            } else {
+                order.captured_amount.clone()
+            }),
+            orders::captured_at.eq(if captured { Some(Utc::now()) } else { None }),
+        ))
+        .get_result(conn)
+        .unwrap_or_else(|error| panic!("Unable to settle order #{}: {}", order.id, error))
+}
+
+/// Undoes an order that was never paid for, or whose payment was voided,
+/// and moves it to `status`. `note` is put on the credit note cancelling
+/// its invoice.
+fn fail_order(order: &PlacedOrder, status: PaymentStatus, note: &str) -> PlacedOrder {
+    use crate::schema::{orders, products};
+
+    let record = find_order_invoice(order.id);
+    if let Some(record) = &record {
+        if let Err(error) = issue_credit_note(
+            &record.invoice_number,
+            CreditReason::Cancellation,
+            None,
+            Some(note),
+        ) {
+            eprintln!(
+                "Unable to cancel invoice for order #{}: {}",
+                order.id,
+                error.describe()
+            );
+        }
+    }
+    release_order_coupons(order.id);
+
+    let conn = &mut POOL.get().unwrap();
+
+    conn.build_transaction()
+        .read_write()
+        .run::<PlacedOrder, diesel::result::Error, _>(|conn| {
+            if let Some(record) = &record {
+                for line in &record.invoice.lines {
+                    diesel::update(products::table.find(line.product_id))
+                        .set(products::stock.eq(products::stock + line.qty))
+                        .execute(conn)?;
+                }
+            }
+
+            diesel::update(orders::table.find(order.id))
+                .set(orders::payment_status.eq(status.code()))
+                .get_result(conn)
+        })
+        .unwrap_or_else(|error| panic!("Unable to fail order #{}: {}", order.id, error))
+}
+
+/// Holds a paid order for review, adding `reasons` to any it was already
+/// held for
+pub fn hold_for_review(order_id: i32, reasons: &[String]) -> Result<PlacedOrder, OrderError> {
+    use crate::schema::orders;
+
+    let order = find_order(order_id).ok_or(OrderError::NotFound)?;
+    if !matches!(
+        order.status(),
+        PaymentStatus::Authorized | PaymentStatus::Captured | PaymentStatus::UnderReview
+    ) {
+        return Err(OrderError::InvalidStatus(order.status()));
+    }
+
+    let mut all_reasons: Vec<String> = order
+        .review_reasons
+        .as_deref()
+        .and_then(|stored| serde_json::from_str(stored).ok())
+        .unwrap_or_default();
+    all_reasons.extend(reasons.iter().cloned());
+
+    let conn = &mut POOL.get().unwrap();
+
+    Ok(diesel::update(orders::table.find(order_id))
+        .set((
+            orders::payment_status.eq(PaymentStatus::UnderReview.code()),
+            orders::review_reasons.eq(encode_reasons(&all_reasons)),
+        ))
+        .get_result(conn)
+        .expect("Unable to hold order for review"))
+}
+
+/// Lets an order held for review go ahead. Payments the gateway's fraud
+/// filters hold have to be approved at the gateway first, so the
+/// transaction is checked before the order is released.
+pub async fn approve_order(
+    gateway: &dyn PaymentGateway,
+    order_id: i32,
+) -> Result<PlacedOrder, OrderError> {
+    use crate::schema::orders;
+
+    let order = find_order(order_id).ok_or(OrderError::NotFound)?;
+    if order.status() != PaymentStatus::UnderReview {
+        return Err(OrderError::InvalidStatus(order.status()));
+    }
+    let transaction_id = order
+        .transaction_id
+        .as_deref()
+        .expect("Order under review has no transaction id");
+
+    let status = match gateway.transaction_status(transaction_id).await {
+        Ok(TransactionStatus::UnderReview) => return Err(OrderError::HeldByGateway),
+        Ok(TransactionStatus::AuthorizedPendingCapture) => PaymentStatus::Authorized,
+        Ok(TransactionStatus::CapturedPendingSettlement | TransactionStatus::Settled) => {
+            PaymentStatus::Captured
+        }
+        Ok(status) => {
+            return Err(OrderError::ReviewFailed(format!(
+                "the transaction is {:?}",
+                status
+            )))
+        }
+        Err(gateway_error) => return Err(OrderError::ReviewFailed(gateway_error.describe())),
+    };
+
+    let conn = &mut POOL.get().unwrap();
+    let captured = status == PaymentStatus::Captured;
+
+    Ok(diesel::update(orders::table.find(order_id))
+        .set((
+            orders::payment_status.eq(status.code()),
+            orders::captured_amount.eq(if captured {
+                order.total.clone()
+            } else {
+                order.captured_amount.clone()
+            }),
+            orders::captured_at.eq(if captured { Some(Utc::now()) } else { None }),
+        ))
+        .get_result(conn)
+        .expect("Unable to approve order"))
+}
+
+/// Turns away an order held for review. Its payment is voided, then its
+/// stock and coupons are given back and its invoice is cancelled. A payment
+/// that has already settled cannot be voided and has to be refunded.
+pub async fn reject_order(
+    gateway: &dyn PaymentGateway,
+    order_id: i32,
+) -> Result<PlacedOrder, OrderError> {
+    let order = find_order(order_id).ok_or(OrderError::NotFound)?;
+    if order.status() != PaymentStatus::UnderReview {
+        return Err(OrderError::InvalidStatus(order.status()));
+    }
+    let transaction_id = order
+        .transaction_id
+        .as_deref()
+        .expect("Order under review has no transaction id");
+
+    let result = gateway.void(transaction_id).await;
+    record_payment(order_id, PaymentAction::Void, None, &result);
+    match result.map(|response| response.outcome()) {
+        Ok(PaymentOutcome::Approved) => Ok(fail_order(
+            &order,
+            PaymentStatus::Voided,
+            "Rejected in fraud review",
+        )),
+        Ok(PaymentOutcome::Declined(reason) | PaymentOutcome::Error(reason))
+        | Ok(PaymentOutcome::HeldForReview(reason)) => Err(OrderError::ReviewFailed(reason)),
+        Err(gateway_error) => Err(OrderError::ReviewFailed(gateway_error.describe())),
+    }
+}
+
+fn claim_capture(order_id: i32) -> Result<PlacedOrder, OrderError> {
+    use crate::schema::orders;
+
+    let conn = &mut POOL.get().unwrap();
+    let claimed: Option<PlacedOrder> = diesel::update(
+        orders::table
+            .find(order_id)
+            .filter(orders::payment_status.eq(PaymentStatus::CapturePending.code())),
+    )
+    .set(orders::payment_status.eq(PaymentStatus::Capturing.code()))
+    .get_result(conn)
+    .optional()
+    .expect("Unable to claim order for capture");
+
+    match claimed {
+        Some(order) => Ok(order),
+        None => match find_order(order_id) {
+            Some(order) => Err(OrderError::InvalidStatus(order.status())),
+            None => Err(OrderError::NotFound),
+        },
+    }
+}
+
+/// Records a capture and puts the units that never shipped back in stock,
+/// since their authorization is released with it
+fn finish_capture(order_id: i32, amount: &Money, unshipped: &[LineQuantity]) -> PlacedOrder {
+    use crate::schema::{orders, products};
+
+    let conn = &mut POOL.get().unwrap();
+
+    conn.build_transaction()
+        .read_write()
+        .run::<PlacedOrder, diesel::result::Error, _>(|conn| {
+            for line in unshipped {
+                diesel::update(products::table.find(line.product_id))
+                    .set(products::stock.eq(products::stock + line.qty))
+                    .execute(conn)?;
+            }
+
+            diesel::update(orders::table.find(order_id))
+                .set((
+                    orders::payment_status.eq(PaymentStatus::Captured.code()),
+                    orders::captured_amount.eq(&amount.amount),
+                    orders::captured_at.eq(Utc::now()),
+                ))
+                .get_result(conn)
+        })
+        .expect("Unable to record capture")
+}
+
+/// The units of every invoice line that are not in `shipped`
+fn unshipped_lines(invoice: &Invoice, shipped: &[LineQuantity]) -> Vec<LineQuantity> {
+    invoice
+        .lines
+        .iter()
+        .filter_map(|line| {
+            let shipped_qty: i32 = shipped
+                .iter()
+                .filter(|shipped_line| shipped_line.product_id == line.product_id)
+                .map(|shipped_line| shipped_line.qty)
+                .sum();
+            let qty = line.qty - shipped_qty;
+
+            (qty > 0).then_some(LineQuantity {
+                product_id: line.product_id,
+                qty,
+            })
+        })
+        .collect()
+}
+
+fn set_status(order_id: i32, status: PaymentStatus) {
+    use crate::schema::orders;
+
+    let conn = &mut POOL.get().unwrap();
+
+    diesel::update(orders::table.find(order_id))
+        .set(orders::payment_status.eq(status.code()))
+        .execute(conn)
+        .expect("Unable to update order payment status");
+}
+
+/// What a line costs the customer once its discount and tax are applied
+pub(crate) fn line_charge(invoice: &Invoice, line: &InvoiceLine) -> Money {
+    let discounted = line.line_total.clone() - line.discount.clone();
+
+    if invoice.prices_include_tax {
+        discounted - line.tax.exempted.clone()
+    } else {
+        discounted + line.tax.amount.clone()
+    }
+}
+
+/// The share of the invoice total covered by a partial shipment. Shipping is
+/// charged in full with the shipment, since it has already been paid for.
+fn shipped_amount(invoice: &Invoice, shipped: &[LineQuantity]) -> Result<Money, OrderError> {
+    let currency = invoice.total.currency;
+    let goods = invoice
+        .lines
+        .iter()
+        .fold(Money::zero(currency), |total, line| {
+            total + line_charge(invoice, line)
+        });
+    let mut amount = invoice.total.clone() - goods;
+
+    for shipped_line in shipped {
+        let line = invoice
+            .lines
+            .iter()
+            .find(|line| line.product_id == shipped_line.product_id)
+            .ok_or(OrderError::UnknownLine(shipped_line.product_id))?;
+        if shipped_line.qty < 1 || shipped_line.qty > line.qty {
+            return Err(OrderError::InvalidQuantity(shipped_line.product_id));
+        }
+
+        let share = BigDecimal::from(shipped_line.qty) / BigDecimal::from(line.qty);
+        amount += line_charge(invoice, line).apply_rate(&share, Rounding::HalfUp);
+    }
+
+    Ok(amount.min(invoice.total.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(product_id: i32, qty: i32) -> LineQuantity {
+        LineQuantity { product_id, qty }
+    }
+
+    #[test]
+    fn merge_lines_adds_up_the_same_product() {
+        let merged = match merge_lines(&[line(1, 2), line(2, 1), line(1, 3)]) {
+            Ok(merged) => merged,
+            Err(order_error) => panic!("{}", order_error.describe()),
+        };
+
+        assert_eq!(merged.len(), 2);
+        assert_eq!((merged[0].product_id, merged[0].qty), (1, 5));
+        assert_eq!((merged[1].product_id, merged[1].qty), (2, 1));
+    }
+
+    #[test]
+    fn merge_lines_refuses_empty_and_overflowing_lines() {
+        assert!(matches!(
+            merge_lines(&[line(1, 2), line(1, 0)]),
+            Err(OrderError::InvalidQuantity(1))
+        ));
+        assert!(matches!(
+            merge_lines(&[line(1, i32::MAX), line(1, 1)]),
+            Err(OrderError::InvalidQuantity(1))
+        ));
+    }
+}
@@ -0,0 +1,281 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Datelike, Utc};
+use diesel::prelude::*;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use sha2::Sha256;
+
+use crate::{
+    authorize_net::{Payment, PaymentProfile},
+    cards::parse_expiration,
+    db::POOL,
+    ecommerce::Customer,
+    gateway::{GatewayResponse, PaymentGateway},
+    models::*,
+    settings::required_secret,
+};
+
+lazy_static! {
+    /// Key customer tokens are signed with, from `CUSTOMER_TOKEN_SECRET`
+    pub static ref CUSTOMER_TOKEN_SECRET: Vec<u8> = required_secret("CUSTOMER_TOKEN_SECRET");
+}
+
+pub enum PaymentMethodError {
+    NotFound,
+    /// No valid customer token was given for the customer's saved methods
+    Unauthorized,
+    Expired,
+    /// Only cards and payment tokens can be saved
+    NotSavable,
+    Gateway(String),
+}
+
+impl PaymentMethodError {
+    pub fn describe(&self) -> String {
+        match self {
+            PaymentMethodError::NotFound => "This saved payment method does not exist".to_string(),
+            PaymentMethodError::Unauthorized => {
+                "A valid customer token is required to use saved payment methods".to_string()
+            }
+            PaymentMethodError::Expired => "This saved card has expired".to_string(),
+            PaymentMethodError::NotSavable => {
+                "Only a new card or payment token can be saved".to_string()
+            }
+            PaymentMethodError::Gateway(reason) => {
+                format!("Unable to update saved payment methods: {}", reason)
+            }
+        }
+    }
+}
+
+/// Every payment method a customer has saved, newest first
+pub fn customer_payment_methods(
+    email: &str,
+    customer_token: Option<&str>,
+) -> Result<Vec<PaymentMethod>, PaymentMethodError> {
+    use crate::schema::payment_methods;
+
+    authorize_customer(email, customer_token)?;
+    let conn = &mut POOL.get().unwrap();
+
+    Ok(payment_methods::table
+        .filter(payment_methods::customer_email.eq(normalize_email(email)))
+        .order(payment_methods::id.desc())
+        .load::<PaymentMethod>(conn)
+        .expect("Unable to load payment methods"))
+}
+
+/// Looks up a saved payment method for charging. Only methods saved by the
+/// customer the token was issued to are found.
+pub fn saved_payment(
+    id: i32,
+    email: &str,
+    customer_token: Option<&str>,
+) -> Result<PaymentProfile, PaymentMethodError> {
+    authorize_customer(email, customer_token)?;
+    let (method, profile_id) = find_with_profile(id)?;
+    if method.customer_email != normalize_email(email) {
+        return Err(PaymentMethodError::NotFound);
+    }
+
+    let today = Utc::now().date_naive();
+    if let Some((year, month)) = method.expiration_date.as_deref().and_then(parse_expiration) {
+        if (year, month) < (today.year(), today.month()) {
+            return Err(PaymentMethodError::Expired);
+        }
+    }
+
+    Ok(PaymentProfile {
+        profile_id,
+        payment_profile_id: method.payment_profile_id,
+        masked_card: method.masked_card,
+    })
+}
+
+/// Keeps the card or token behind an approved transaction so the customer
+/// can pay with it again. Only the gateway's ids and what is printed on a
+/// receipt are stored. Returns the new method along with the customer token
+/// that unlocks the customer's saved methods. Once a customer has saved
+/// methods, a new one is only added with their token, so knowing someone's
+/// email is never enough to get a token for their cards.
+pub async fn save_payment_method(
+    gateway: &dyn PaymentGateway,
+    customer: &Customer,
+    response: &GatewayResponse,
+    customer_token: Option<&str>,
+) -> Result<(PaymentMethod, String), PaymentMethodError> {
+    use crate::schema::{customer_profiles, payment_methods};
+
+    let expiration_date = match &customer.payment {
+        Payment::CreditCard(card) => Some(card.expiration_date.clone()),
+        Payment::OpaqueData(_) => None,
+        Payment::SavedMethod(_) | Payment::Profile(_) => {
+            return Err(PaymentMethodError::NotSavable)
+        }
+    };
+    let email = normalize_email(&customer.email);
+
+    let existing_profile: Option<String> = customer_profiles::table
+        .find(&email)
+        .select(customer_profiles::profile_id)
+        .first(&mut POOL.get().unwrap())
+        .optional()
+        .expect("Unable to look up customer profile");
+    if let Some(profile_id) = &existing_profile {
+        if !token_matches(&email, profile_id, customer_token) {
+            return Err(PaymentMethodError::Unauthorized);
+        }
+    }
+    let saved = gateway
+        .save_payment_method(
+            &response.transaction_id,
+            customer,
+            existing_profile.as_deref(),
+        )
+        .await
+        .map_err(|gateway_error| PaymentMethodError::Gateway(gateway_error.describe()))?;
+
+    let non_empty = |value: &str| Some(value.to_string()).filter(|value| !value.is_empty());
+    let masked_card = non_empty(&response.account_number)
+        .or_else(|| customer.payment.masked())
+        .unwrap_or_else(|| String::from("XXXX"));
+    let card_type = non_empty(&response.account_type);
+    let conn = &mut POOL.get().unwrap();
+
+    let method = conn
+        .build_transaction()
+        .read_write()
+        .run::<PaymentMethod, diesel::result::Error, _>(|conn| {
+            diesel::insert_into(customer_profiles::table)
+                .values(&NewCustomerProfile {
+                    customer_email: &email,
+                    profile_id: &saved.profile_id,
+                })
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+
+            diesel::insert_into(payment_methods::table)
+                .values(&NewPaymentMethod {
+                    customer_email: &email,
+                    payment_profile_id: &saved.payment_profile_id,
+                    card_type: card_type.as_deref(),
+                    masked_card: &masked_card,
+                    expiration_date: expiration_date.as_deref(),
+                })
+                .get_result(conn)
+        })
+        .unwrap_or_else(|error| {
+            panic!(
+                "Unable to store payment method for {}: {}",
+                customer.email, error
+            )
+        });
+
+    // A profile someone else started first is kept, so the token is for
+    // whichever profile the method was stored under
+    let profile_id: String = customer_profiles::table
+        .find(&email)
+        .select(customer_profiles::profile_id)
+        .first(conn)
+        .expect("Unable to look up customer profile");
+
+    Ok((method, sign_customer(&email, &profile_id)))
+}
+
+/// Removes a saved payment method from the gateway and from our records.
+/// Only the customer who saved it can remove it.
+pub async fn remove_payment_method(
+    gateway: &dyn PaymentGateway,
+    id: i32,
+    customer_token: Option<&str>,
+) -> Result<(), PaymentMethodError> {
+    use crate::schema::payment_methods;
+
+    let (method, profile_id) = find_with_profile(id)?;
+    if !token_matches(&method.customer_email, &profile_id, customer_token) {
+        return Err(PaymentMethodError::Unauthorized);
+    }
+    gateway
+        .delete_payment_method(&profile_id, &method.payment_profile_id)
+        .await
+        .map_err(|gateway_error| PaymentMethodError::Gateway(gateway_error.describe()))?;
+
+    let conn = &mut POOL.get().unwrap();
+
+    diesel::delete(payment_methods::table.find(id))
+        .execute(conn)
+        .expect("Unable to remove payment method");
+
+    Ok(())
+}
+
+fn find_with_profile(id: i32) -> Result<(PaymentMethod, String), PaymentMethodError> {
+    use crate::schema::{customer_profiles, payment_methods};
+
+    let conn = &mut POOL.get().unwrap();
+
+    payment_methods::table
+        .inner_join(customer_profiles::table)
+        .filter(payment_methods::id.eq(id))
+        .select((payment_methods::all_columns, customer_profiles::profile_id))
+        .first::<(PaymentMethod, String)>(conn)
+        .optional()
+        .expect("Unable to look up payment method")
+        .ok_or(PaymentMethodError::NotFound)
+}
+
+/// Whether `customer_token` was issued to the customer behind `email`
+pub fn is_customer(email: &str, customer_token: Option<&str>) -> bool {
+    authorize_customer(email, customer_token).is_ok()
+}
+
+/// Checks that `customer_token` was issued to the customer behind `email`.
+/// A customer without saved methods has no token to check against.
+fn authorize_customer(email: &str, customer_token: Option<&str>) -> Result<(), PaymentMethodError> {
+    use crate::schema::customer_profiles;
+
+    let email = normalize_email(email);
+    let profile_id: Option<String> = customer_profiles::table
+        .find(&email)
+        .select(customer_profiles::profile_id)
+        .first(&mut POOL.get().unwrap())
+        .optional()
+        .expect("Unable to look up customer profile");
+
+    match profile_id {
+        Some(profile_id) if token_matches(&email, &profile_id, customer_token) => Ok(()),
+        _ => Err(PaymentMethodError::Unauthorized),
+    }
+}
+
+fn token_matches(email: &str, profile_id: &str, customer_token: Option<&str>) -> bool {
+    let signature = match customer_token.map(|token| URL_SAFE_NO_PAD.decode(token.trim())) {
+        Some(Ok(signature)) => signature,
+        _ => return false,
+    };
+
+    customer_mac(email, profile_id)
+        .verify_slice(&signature)
+        .is_ok()
+}
+
+/// The token a customer shows to use their saved payment methods. It is tied
+/// to the gateway profile, which is never shown to anyone, so it cannot be
+/// made from an email address alone.
+fn sign_customer(email: &str, profile_id: &str) -> String {
+    URL_SAFE_NO_PAD.encode(customer_mac(email, profile_id).finalize().into_bytes())
+}
+
+fn customer_mac(email: &str, profile_id: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&CUSTOMER_TOKEN_SECRET)
+        .expect("HMAC accepts keys of any length");
+    mac.update(b"customer:");
+    mac.update(email.as_bytes());
+    mac.update(b":");
+    mac.update(profile_id.as_bytes());
+    mac
+}
+
+fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
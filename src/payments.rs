@@ -0,0 +1,117 @@
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::POOL,
+    gateway::{GatewayError, GatewayResponse, GatewayResult},
+    models::*,
+    money::Money,
+};
+
+/// Outcome stored when the gateway could not be reached or understood
+const GATEWAY_FAILURE: &str = "gateway_failure";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PaymentAction {
+    Authorize,
+    Charge,
+    Capture,
+    Void,
+    Refund,
+    /// A transaction looked up after its reply was lost
+    Lookup,
+}
+
+impl PaymentAction {
+    pub fn code(&self) -> &'static str {
+        match self {
+            PaymentAction::Authorize => "authorize",
+            PaymentAction::Charge => "charge",
+            PaymentAction::Capture => "capture",
+            PaymentAction::Void => "void",
+            PaymentAction::Refund => "refund",
+            PaymentAction::Lookup => "lookup",
+        }
+    }
+}
+
+/// Keeps a gateway request and what came back, whether it went through or
+/// not. Failing to store it is only logged, since the payment itself has
+/// already happened by now.
+pub fn record_payment(
+    order_id: i32,
+    action: PaymentAction,
+    amount: Option<&Money>,
+    result: &GatewayResult<GatewayResponse>,
+) {
+    use crate::schema::payments;
+
+    // The circuit breaker refused it, so nothing was sent
+    if let Err(GatewayError::Unavailable(_)) = result {
+        return;
+    }
+
+    let conn = &mut POOL.get().unwrap();
+    let non_empty = |value: &str| Some(value.to_string()).filter(|value| !value.is_empty());
+
+    let (outcome, message, response) = match result {
+        Ok(response) => {
+            let outcome = response.outcome();
+            (outcome.code(), response.reason(), Some(response))
+        }
+        Err(gateway_error) => (GATEWAY_FAILURE, gateway_error.describe(), None),
+    };
+    let field = |value: fn(&GatewayResponse) -> &String| {
+        response.and_then(|response| non_empty(value(response)))
+    };
+    let transaction_id = field(|response| &response.transaction_id);
+    let response_code = field(|response| &response.response_code);
+    let result_code = field(|response| &response.result_code);
+    let auth_code = field(|response| &response.auth_code);
+    let avs_result_code = field(|response| &response.avs_result_code);
+    let cvv_result_code = field(|response| &response.cvv_result_code);
+    let account_type = field(|response| &response.account_type);
+    let account_number = field(|response| &response.account_number);
+
+    let inserted = diesel::insert_into(payments::table)
+        .values(&NewPaymentAttempt {
+            order_id: &order_id,
+            action: action.code(),
+            amount: amount.map(|amount| &amount.amount),
+            currency: amount.map(|amount| amount.currency.code()),
+            outcome,
+            message: Some(&message),
+            transaction_id: transaction_id.as_deref(),
+            response_code: response_code.as_deref(),
+            result_code: result_code.as_deref(),
+            auth_code: auth_code.as_deref(),
+            avs_result_code: avs_result_code.as_deref(),
+            cvv_result_code: cvv_result_code.as_deref(),
+            account_type: account_type.as_deref(),
+            account_number: account_number.as_deref(),
+        })
+        .execute(conn);
+
+    if let Err(error) = inserted {
+        eprintln!(
+            "Unable to record {} attempt for order #{}: {}",
+            action.code(),
+            order_id,
+            error
+        );
+    }
+}
+
+/// Every gateway request made for an order, oldest first
+pub fn order_payments(order_id: i32) -> Vec<PaymentAttempt> {
+    use crate::schema::payments;
+
+    let conn = &mut POOL.get().unwrap();
+
+    payments::table
+        .filter(payments::order_id.eq(order_id))
+        .order(payments::id)
+        .load::<PaymentAttempt>(conn)
+        .expect("Unable to load payments")
+}
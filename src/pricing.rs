@@ -0,0 +1,284 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use diesel::prelude::*;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    db::POOL,
+    ecommerce::Invoice,
+    exchange::{Conversion, ExchangeError},
+    inventory::Item,
+    models::*,
+    money::{Currency, Money},
+    settings::required_secret,
+};
+
+/// How long a quoted price is honored after it was issued
+pub const QUOTE_TTL_SECONDS: u64 = 15 * 60;
+
+type HmacSha256 = Hmac<Sha256>;
+
+lazy_static! {
+    /// Key quote tokens are signed with, from `QUOTE_SECRET`
+    pub static ref QUOTE_SECRET: Vec<u8> = required_secret("QUOTE_SECRET");
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct QuotedPrice {
+    pub id: i32,
+    pub price: Money,
+}
+
+#[derive(Deserialize, Serialize)]
+struct QuotePayload {
+    prices: Vec<QuotedPrice>,
+    /// Rate the quoted invoice was converted at, honored along with the
+    /// prices
+    conversion: Conversion,
+    expires_at: u64,
+}
+
+#[derive(Serialize)]
+pub struct Quote {
+    pub invoice: Invoice,
+    pub token: String,
+    pub expires_at: u64,
+}
+
+#[derive(Clone, Serialize)]
+pub struct PriceChange {
+    pub id: i32,
+    pub expected: Money,
+    pub current: Money,
+}
+
+pub enum PricingError {
+    InvalidQuote,
+    ExpiredQuote,
+    PriceChanged(Vec<PriceChange>),
+    /// No rate to show prices in the requested currency
+    Exchange(ExchangeError),
+}
+
+impl Quote {
+    /// Signs the prices already applied to `items`, and the rate they were
+    /// converted at, so that they can be honored by a later order even if
+    /// the catalog or exchange rates change in between.
+    pub fn create(items: &[Item], conversion: &Conversion, invoice: Invoice) -> Self {
+        let expires_at = unix_now() + QUOTE_TTL_SECONDS;
+        let payload = QuotePayload {
+            prices: items
+                .iter()
+                .map(|item| QuotedPrice {
+                    id: item.id,
+                    price: item.price.clone(),
+                })
+                .collect(),
+            conversion: conversion.clone(),
+            expires_at,
+        };
+
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&payload).unwrap());
+        let signature = URL_SAFE_NO_PAD.encode(sign(payload.as_bytes()));
+
+        Quote {
+            invoice,
+            token: format!("{}.{}", payload, signature),
+            expires_at,
+        }
+    }
+
+    fn verify(token: &str) -> Result<QuotePayload, PricingError> {
+        let (payload, signature) = token.split_once('.').ok_or(PricingError::InvalidQuote)?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| PricingError::InvalidQuote)?;
+
+        let mut mac = quote_mac();
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| PricingError::InvalidQuote)?;
+
+        let payload = URL_SAFE_NO_PAD
+            .decode(payload)
+            .map_err(|_| PricingError::InvalidQuote)?;
+        let payload: QuotePayload =
+            serde_json::from_slice(&payload).map_err(|_| PricingError::InvalidQuote)?;
+
+        if payload.expires_at < unix_now() {
+            return Err(PricingError::ExpiredQuote);
+        }
+
+        Ok(payload)
+    }
+}
+
+/// Replaces client supplied prices with the ones we will actually charge.
+///
+/// With a valid quote token the quoted prices are used, and the quoted
+/// conversion into `currency` is returned so the order is charged at that
+/// rate too. Without one, the client's price has to match the current
+/// catalog price in `currency` so that a customer is never charged something
+/// different from what they were shown, and the current rate is returned.
+/// Either way items are left at their base currency price.
+pub fn apply_server_prices(
+    items: &mut [Item],
+    quote_token: Option<&str>,
+    currency: Currency,
+) -> Result<Conversion, PricingError> {
+    match quote_token {
+        Some(token) => {
+            let payload = Quote::verify(token)?;
+            if payload.conversion.to != currency {
+                return Err(PricingError::InvalidQuote);
+            }
+
+            let quoted: HashMap<i32, Money> = payload
+                .prices
+                .into_iter()
+                .map(|quoted| (quoted.id, quoted.price))
+                .collect();
+
+            for item in items.iter_mut() {
+                match quoted.get(&item.id) {
+                    Some(price) => item.price = price.clone(),
+                    None => return Err(PricingError::InvalidQuote),
+                }
+            }
+
+            Ok(payload.conversion)
+        }
+        None => {
+            let conversion =
+                Conversion::to_presentment(currency).map_err(PricingError::Exchange)?;
+            let changes = reprice(items, &current_prices(items), &conversion);
+
+            if changes.is_empty() {
+                Ok(conversion)
+            } else {
+                Err(PricingError::PriceChanged(changes))
+            }
+        }
+    }
+}
+
+/// Sets every item to its current catalog price, ignoring the client price
+pub fn apply_catalog_prices(items: &mut [Item]) {
+    reprice(
+        items,
+        &current_prices(items),
+        &Conversion::identity(Currency::base()),
+    );
+}
+
+/// Sets every item to its base currency price in `current` and returns the
+/// items whose price, as shown through `conversion`, was something else
+fn reprice(
+    items: &mut [Item],
+    current: &HashMap<i32, Money>,
+    conversion: &Conversion,
+) -> Vec<PriceChange> {
+    let mut changes: Vec<PriceChange> = vec![];
+
+    for item in items.iter_mut() {
+        let current_price = current.get(&item.id).cloned().unwrap_or_default();
+        let shown_price = conversion.convert(&current_price);
+        if item.price != shown_price {
+            changes.push(PriceChange {
+                id: item.id,
+                expected: item.price.clone(),
+                current: shown_price,
+            });
+        }
+        item.price = current_price;
+    }
+
+    changes
+}
+
+fn current_prices(items: &[Item]) -> HashMap<i32, Money> {
+    use crate::schema::products::dsl::*;
+
+    let conn = &mut POOL.get().unwrap();
+    let item_ids: Vec<i32> = items.iter().map(|item| item.id).collect();
+
+    products
+        .filter(id.eq_any(item_ids))
+        .load::<Product>(conn)
+        .expect("Unable to retrieve current product prices")
+        .into_iter()
+        .map(|product| (product.id, product.price))
+        .collect()
+}
+
+fn quote_mac() -> HmacSha256 {
+    HmacSha256::new_from_slice(&QUOTE_SECRET).expect("HMAC accepts keys of any length")
+}
+
+fn sign(payload: &[u8]) -> Vec<u8> {
+    let mut mac = quote_mac();
+    mac.update(payload);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is set before the unix epoch")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigdecimal::BigDecimal;
+    use std::str::FromStr;
+
+    fn money(amount: &str, currency: Currency) -> Money {
+        Money::new(BigDecimal::from_str(amount).unwrap(), currency)
+    }
+
+    fn to_euros() -> Conversion {
+        Conversion {
+            from: Currency::base(),
+            to: Currency::EUR,
+            rate: BigDecimal::from_str("0.9").unwrap(),
+        }
+    }
+
+    #[test]
+    fn reprice_accepts_prices_shown_in_another_currency() {
+        let catalog = HashMap::from([(7, money("12.50", Currency::base()))]);
+        let mut items = vec![Item {
+            id: 7,
+            qty: 2,
+            price: money("11.25", Currency::EUR),
+        }];
+
+        assert!(reprice(&mut items, &catalog, &to_euros()).is_empty());
+        // The invoice converts from the base price itself
+        assert_eq!(items[0].price, money("12.50", Currency::base()));
+    }
+
+    #[test]
+    fn reprice_reports_changes_in_the_currency_shown() {
+        let catalog = HashMap::from([(7, money("12.50", Currency::base()))]);
+        let mut items = vec![Item {
+            id: 7,
+            qty: 1,
+            price: money("10.00", Currency::EUR),
+        }];
+
+        let changes = reprice(&mut items, &catalog, &to_euros());
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].expected, money("10.00", Currency::EUR));
+        assert_eq!(changes[0].current, money("11.25", Currency::EUR));
+        assert_eq!(items[0].price, money("12.50", Currency::base()));
+    }
+}
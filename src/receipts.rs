@@ -0,0 +1,215 @@
+use handlebars::{no_escape, Handlebars, RenderError};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+use crate::{authorize_net::Address, invoicing::InvoiceRecord, money::Money, settings::SETTINGS};
+
+const RECEIPT_TEMPLATE: &str = "receipt";
+const DEFAULT_HTML_TEMPLATE: &str = include_str!("../templates/receipt.html.hbs");
+const DEFAULT_TEXT_TEMPLATE: &str = include_str!("../templates/receipt.txt.hbs");
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReceiptFormat {
+    #[default]
+    Html,
+    Text,
+}
+
+impl ReceiptFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ReceiptFormat::Html => "text/html; charset=utf-8",
+            ReceiptFormat::Text => "text/plain; charset=utf-8",
+        }
+    }
+
+    fn file_name(&self) -> &'static str {
+        match self {
+            ReceiptFormat::Html => "receipt.html.hbs",
+            ReceiptFormat::Text => "receipt.txt.hbs",
+        }
+    }
+
+    fn default_template(&self) -> &'static str {
+        match self {
+            ReceiptFormat::Html => DEFAULT_HTML_TEMPLATE,
+            ReceiptFormat::Text => DEFAULT_TEXT_TEMPLATE,
+        }
+    }
+}
+
+/// Compiled receipt templates. A `receipt.html.hbs` or `receipt.txt.hbs` in
+/// `RECEIPT_TEMPLATE_DIR` replaces the matching built in template.
+struct ReceiptTemplates {
+    html: Handlebars<'static>,
+    text: Handlebars<'static>,
+}
+
+impl ReceiptTemplates {
+    fn load() -> Self {
+        let mut html = Handlebars::new();
+        register(&mut html, ReceiptFormat::Html);
+
+        // Plain text receipts are never shown in a browser, so values are
+        // written out as they are
+        let mut text = Handlebars::new();
+        text.register_escape_fn(no_escape);
+        register(&mut text, ReceiptFormat::Text);
+
+        ReceiptTemplates { html, text }
+    }
+}
+
+fn register(registry: &mut Handlebars, format: ReceiptFormat) {
+    let custom = SETTINGS
+        .receipt_template_dir
+        .as_ref()
+        .map(|dir| Path::new(dir).join(format.file_name()))
+        .filter(|path| path.exists())
+        .map(|path| {
+            fs::read_to_string(&path)
+                .unwrap_or_else(|error| panic!("Unable to read {}: {}", path.display(), error))
+        });
+
+    registry
+        .register_template_string(
+            RECEIPT_TEMPLATE,
+            custom.as_deref().unwrap_or(format.default_template()),
+        )
+        .unwrap_or_else(|error| panic!("Invalid {} template: {}", format.file_name(), error));
+}
+
+lazy_static! {
+    static ref TEMPLATES: ReceiptTemplates = ReceiptTemplates::load();
+}
+
+#[derive(Serialize)]
+struct ReceiptLine {
+    title: String,
+    qty: i32,
+    unit_price: String,
+    line_total: String,
+    discount: Option<String>,
+    tax: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ReceiptAmount {
+    name: String,
+    amount: String,
+}
+
+/// Everything a receipt template can show, with amounts already formatted
+#[derive(Serialize)]
+struct ReceiptContext {
+    store_name: String,
+    invoice_number: String,
+    order_id: i32,
+    issued_at: String,
+    customer_email: String,
+    currency: String,
+    lines: Vec<ReceiptLine>,
+    subtotal: String,
+    discounts: Vec<ReceiptAmount>,
+    discount_total: Option<String>,
+    shipping_method: String,
+    shipping: String,
+    taxes: String,
+    tax_note: Option<String>,
+    total: String,
+    credit_notes: Vec<ReceiptAmount>,
+    balance: Option<String>,
+    masked_card: Option<String>,
+    billing_address: Option<Address>,
+    shipping_address: Option<Address>,
+}
+
+fn optional_amount(amount: &Money) -> Option<String> {
+    if amount.is_zero() {
+        None
+    } else {
+        Some(amount.to_string())
+    }
+}
+
+impl ReceiptContext {
+    fn from_record(record: &InvoiceRecord) -> Self {
+        let invoice = &record.invoice;
+        let tax_note = match (&invoice.tax_exemption_id, invoice.prices_include_tax) {
+            (Some(exemption_id), _) => Some(format!("Tax exempt, certificate {}", exemption_id)),
+            (None, true) => Some(String::from("Prices include tax")),
+            (None, false) => None,
+        };
+
+        ReceiptContext {
+            store_name: SETTINGS.store_name.clone(),
+            invoice_number: record.invoice_number.clone(),
+            order_id: record.order_id,
+            issued_at: record.issued_at.format("%Y-%m-%d %H:%M UTC").to_string(),
+            customer_email: record.customer_email.clone(),
+            currency: invoice.total.currency.code().to_string(),
+            lines: invoice
+                .lines
+                .iter()
+                .map(|line| ReceiptLine {
+                    title: line.title.clone(),
+                    qty: line.qty,
+                    unit_price: line.unit_price.to_string(),
+                    line_total: line.line_total.to_string(),
+                    discount: optional_amount(&line.discount),
+                    tax: optional_amount(&line.tax.amount),
+                })
+                .collect(),
+            subtotal: invoice.subtotal.to_string(),
+            discounts: invoice
+                .discounts
+                .iter()
+                .map(|discount| ReceiptAmount {
+                    name: discount.name.clone(),
+                    amount: discount.amount.to_string(),
+                })
+                .collect(),
+            discount_total: optional_amount(&invoice.discount_total),
+            shipping_method: invoice.shipping_method.describe().to_string(),
+            shipping: invoice.shipping.to_string(),
+            taxes: invoice.taxes.to_string(),
+            tax_note,
+            total: invoice.total.to_string(),
+            credit_notes: record
+                .credit_notes
+                .iter()
+                .map(|credit_note| ReceiptAmount {
+                    name: format!(
+                        "{} ({})",
+                        credit_note.credit_note_number, credit_note.reason
+                    ),
+                    amount: Money::new(credit_note.amount.clone(), invoice.total.currency)
+                        .to_string(),
+                })
+                .collect(),
+            balance: if record.credit_notes.is_empty() {
+                None
+            } else {
+                Some(record.balance.to_string())
+            },
+            masked_card: record.masked_card.clone(),
+            billing_address: record.billing_address.clone(),
+            shipping_address: record.shipping_address.clone(),
+        }
+    }
+}
+
+/// Renders the receipt for an issued invoice
+pub fn render_receipt(
+    record: &InvoiceRecord,
+    format: ReceiptFormat,
+) -> Result<String, RenderError> {
+    let registry = match format {
+        ReceiptFormat::Html => &TEMPLATES.html,
+        ReceiptFormat::Text => &TEMPLATES.text,
+    };
+
+    registry.render(RECEIPT_TEMPLATE, &ReceiptContext::from_record(record))
+}
@@ -0,0 +1,376 @@
+use bigdecimal::BigDecimal;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{
+    db::{TransactionError, POOL},
+    gateway::{PaymentGateway, PaymentOutcome, TransactionStatus},
+    invoicing::{find_order_invoice, issue_credit_note, CreditReason, InvoiceRecord},
+    models::*,
+    money::{Money, Rounding},
+    orders::{find_order, line_charge, merge_lines, LineQuantity, OrderError, PaymentStatus},
+    payments::{record_payment, PaymentAction},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RefundKind {
+    /// Money sent back for a settled payment
+    Refund,
+    /// The payment was cancelled before it settled
+    Void,
+}
+
+impl RefundKind {
+    pub fn code(&self) -> &'static str {
+        match self {
+            RefundKind::Refund => "refund",
+            RefundKind::Void => "void",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RefundRequest {
+    /// Leave out to refund everything still left on the order
+    pub lines: Option<Vec<LineQuantity>>,
+    /// Put the refunded units back in stock
+    #[serde(default)]
+    pub restock: bool,
+    pub note: Option<String>,
+}
+
+impl Refund {
+    pub fn lines(&self) -> Vec<LineQuantity> {
+        serde_json::from_str(&self.lines).expect("Stored refund lines are not valid")
+    }
+}
+
+pub fn order_refunds(order_id: i32) -> Vec<Refund> {
+    use crate::schema::refunds;
+
+    let conn = &mut POOL.get().unwrap();
+
+    refunds::table
+        .filter(refunds::order_id.eq(order_id))
+        .order(refunds::id)
+        .load::<Refund>(conn)
+        .expect("Unable to load refunds")
+}
+
+/// What a refund request works out to once checked against the order
+struct PlannedRefund {
+    order: PlacedOrder,
+    previous_status: PaymentStatus,
+    /// Whether any money has been taken yet
+    captured: bool,
+    amount: Money,
+    lines: Vec<LineQuantity>,
+    whole_order: bool,
+}
+
+/// Gives money back for some or all of an order. Payments that have not
+/// settled yet can only be voided, which cancels them as a whole; settled
+/// ones are refunded to the card they were paid with. Each refund is
+/// recorded against the order and credited on its invoice.
+pub async fn refund_order(
+    gateway: &dyn PaymentGateway,
+    order_id: i32,
+    request: &RefundRequest,
+) -> Result<Refund, OrderError> {
+    find_order(order_id).ok_or(OrderError::NotFound)?;
+    let record = find_order_invoice(order_id).ok_or(OrderError::InvoiceNotFound)?;
+    let planned = plan_refund(&record, request.lines.as_deref())?;
+
+    let result = send_refund(gateway, &record, &planned).await;
+    let (kind, transaction_id) = match result {
+        Ok(sent) => sent,
+        Err(order_error) => {
+            restore_status(order_id, planned.previous_status);
+            return Err(order_error);
+        }
+    };
+
+    let (reason, credit_amount) = match kind {
+        RefundKind::Void => (CreditReason::Cancellation, None),
+        RefundKind::Refund if planned.whole_order => (CreditReason::Refund, None),
+        RefundKind::Refund => (CreditReason::Refund, Some(&planned.amount)),
+    };
+    // The money has already gone back, so the refund is recorded even if the
+    // invoice cannot be credited
+    let credit_note_number = match issue_credit_note(
+        &record.invoice_number,
+        reason,
+        credit_amount,
+        request.note.as_deref(),
+    ) {
+        Ok(credit_note) => Some(credit_note.credit_note_number),
+        Err(error) => {
+            eprintln!(
+                "Unable to credit refund on order #{}: {}",
+                order_id,
+                error.describe()
+            );
+            None
+        }
+    };
+
+    Ok(record_refund(
+        &planned,
+        kind,
+        &transaction_id,
+        credit_note_number.as_deref(),
+        request,
+    ))
+}
+
+/// Checks the request against what is left on the order and moves the order
+/// to `refunding`, so two refunds can never be sent for the same money
+fn plan_refund(
+    record: &InvoiceRecord,
+    lines: Option<&[LineQuantity]>,
+) -> Result<PlannedRefund, OrderError> {
+    use crate::schema::{orders, refunds};
+
+    let invoice = &record.invoice;
+    let currency = invoice.total.currency;
+    // Lines of the same product are checked against what is left together
+    let lines = lines.map(merge_lines).transpose()?;
+    let lines = lines.as_deref();
+    let conn = &mut POOL.get().unwrap();
+
+    conn.build_transaction()
+        .read_write()
+        .run::<PlannedRefund, TransactionError<OrderError>, _>(|conn| {
+            let order: PlacedOrder = orders::table
+                .find(record.order_id)
+                .for_update()
+                .first(conn)
+                .optional()?
+                .ok_or(OrderError::NotFound)?;
+
+            let previous_status = order.status();
+            let captured = match previous_status {
+                PaymentStatus::Captured | PaymentStatus::PartiallyRefunded => true,
+                PaymentStatus::Authorized | PaymentStatus::UnderReview => false,
+                status => return Err(OrderError::InvalidStatus(status).into()),
+            };
+            if !captured && lines.is_some() {
+                return Err(OrderError::PartialRefundUnavailable.into());
+            }
+
+            let mut refunded: HashMap<i32, i32> = HashMap::new();
+            for refund in refunds::table
+                .filter(refunds::order_id.eq(order.id))
+                .load::<Refund>(conn)?
+            {
+                for line in refund.lines() {
+                    *refunded.entry(line.product_id).or_insert(0) += line.qty;
+                }
+            }
+
+            let balance = Money::new(&order.captured_amount - &order.refunded_amount, currency);
+            let (amount, refund_lines) = match lines {
+                Some(lines) => {
+                    let mut amount = Money::zero(currency);
+                    for refund_line in lines {
+                        let line = invoice
+                            .lines
+                            .iter()
+                            .find(|line| line.product_id == refund_line.product_id)
+                            .ok_or(OrderError::UnknownLine(refund_line.product_id))?;
+                        let remaining =
+                            line.qty - refunded.get(&line.product_id).copied().unwrap_or(0);
+                        if refund_line.qty < 1 || refund_line.qty > remaining {
+                            return Err(OrderError::InvalidQuantity(refund_line.product_id).into());
+                        }
+
+                        let share = BigDecimal::from(refund_line.qty) / BigDecimal::from(line.qty);
+                        amount += line_charge(invoice, line).apply_rate(&share, Rounding::HalfUp);
+                    }
+                    if amount > balance {
+                        return Err(OrderError::RefundExceedsBalance(balance).into());
+                    }
+
+                    (amount, lines.to_vec())
+                }
+                None => {
+                    let remaining_lines = invoice
+                        .lines
+                        .iter()
+                        .map(|line| LineQuantity {
+                            product_id: line.product_id,
+                            qty: line.qty - refunded.get(&line.product_id).copied().unwrap_or(0),
+                        })
+                        .filter(|line| line.qty > 0)
+                        .collect();
+
+                    // An uncaptured payment is released in full
+                    if captured {
+                        (balance.clone(), remaining_lines)
+                    } else {
+                        (Money::new(order.total.clone(), currency), remaining_lines)
+                    }
+                }
+            };
+            if amount.is_zero() {
+                return Err(OrderError::NothingToRefund.into());
+            }
+
+            let order = diesel::update(orders::table.find(order.id))
+                .set(orders::payment_status.eq(PaymentStatus::Refunding.code()))
+                .get_result(conn)?;
+
+            Ok(PlannedRefund {
+                order,
+                previous_status,
+                captured,
+                whole_order: captured && amount == balance,
+                amount,
+                lines: refund_lines,
+            })
+        })
+        .map_err(TransactionError::into_domain)
+}
+
+/// Voids the payment if it has not settled, otherwise refunds it
+async fn send_refund(
+    gateway: &dyn PaymentGateway,
+    record: &InvoiceRecord,
+    planned: &PlannedRefund,
+) -> Result<(RefundKind, String), OrderError> {
+    let transaction_id = planned
+        .order
+        .transaction_id
+        .as_deref()
+        .ok_or_else(|| OrderError::RefundFailed("the order has no payment".to_string()))?;
+
+    let kind = match gateway
+        .transaction_status(transaction_id)
+        .await
+        .map_err(|gateway_error| OrderError::RefundFailed(gateway_error.describe()))?
+    {
+        TransactionStatus::Settled | TransactionStatus::RefundPendingSettlement => {
+            RefundKind::Refund
+        }
+        TransactionStatus::AuthorizedPendingCapture
+        | TransactionStatus::CapturedPendingSettlement
+        | TransactionStatus::UnderReview => {
+            if planned.captured && !planned.whole_order {
+                return Err(OrderError::PartialRefundUnavailable);
+            }
+
+            RefundKind::Void
+        }
+        status => {
+            return Err(OrderError::RefundFailed(format!(
+                "the payment is {:?}",
+                status
+            )))
+        }
+    };
+
+    let (action, result) = match kind {
+        RefundKind::Void => (PaymentAction::Void, gateway.void(transaction_id).await),
+        RefundKind::Refund => {
+            let masked_card = record
+                .masked_card
+                .as_deref()
+                .ok_or_else(|| OrderError::RefundFailed("no card is on file".to_string()))?;
+            (
+                PaymentAction::Refund,
+                gateway
+                    .refund(transaction_id, &planned.amount, masked_card)
+                    .await,
+            )
+        }
+    };
+    record_payment(planned.order.id, action, Some(&planned.amount), &result);
+    let response =
+        result.map_err(|gateway_error| OrderError::RefundFailed(gateway_error.describe()))?;
+
+    match response.outcome() {
+        PaymentOutcome::Approved | PaymentOutcome::HeldForReview(_) => {
+            Ok((kind, response.transaction_id))
+        }
+        PaymentOutcome::Declined(reason) | PaymentOutcome::Error(reason) => {
+            Err(OrderError::RefundFailed(reason))
+        }
+    }
+}
+
+/// Stores the refund, updates the order's refunded total and puts refunded
+/// units back in stock if asked to
+fn record_refund(
+    planned: &PlannedRefund,
+    kind: RefundKind,
+    transaction_id: &str,
+    credit_note_number: Option<&str>,
+    request: &RefundRequest,
+) -> Refund {
+    use crate::schema::{orders, products, refunds};
+
+    let order = &planned.order;
+    let lines = serde_json::to_string(&planned.lines).expect("Unable to serialize refund lines");
+    let refunded_amount = if planned.captured {
+        &order.refunded_amount + &planned.amount.amount
+    } else {
+        order.refunded_amount.clone()
+    };
+    let status = match kind {
+        RefundKind::Void => PaymentStatus::Voided,
+        RefundKind::Refund if refunded_amount >= order.captured_amount => PaymentStatus::Refunded,
+        RefundKind::Refund => PaymentStatus::PartiallyRefunded,
+    };
+
+    let conn = &mut POOL.get().unwrap();
+
+    conn.build_transaction()
+        .read_write()
+        .run::<Refund, diesel::result::Error, _>(|conn| {
+            diesel::update(orders::table.find(order.id))
+                .set((
+                    orders::payment_status.eq(status.code()),
+                    orders::refunded_amount.eq(&refunded_amount),
+                ))
+                .execute(conn)?;
+
+            if request.restock {
+                for line in &planned.lines {
+                    diesel::update(products::table.find(line.product_id))
+                        .set(products::stock.eq(products::stock + line.qty))
+                        .execute(conn)?;
+                }
+            }
+
+            diesel::insert_into(refunds::table)
+                .values(&NewRefund {
+                    order_id: &order.id,
+                    kind: kind.code(),
+                    amount: &planned.amount.amount,
+                    transaction_id: Some(transaction_id),
+                    credit_note_number,
+                    lines: &lines,
+                    restocked: &request.restock,
+                    note: request.note.as_deref(),
+                })
+                .get_result(conn)
+        })
+        .unwrap_or_else(|error| panic!("Unable to record refund on order #{}: {}", order.id, error))
+}
+
+fn restore_status(order_id: i32, status: PaymentStatus) {
+    use crate::schema::orders;
+
+    let conn = &mut POOL.get().unwrap();
+
+    diesel::update(
+        orders::table
+            .find(order_id)
+            .filter(orders::payment_status.eq(PaymentStatus::Refunding.code())),
+    )
+    .set(orders::payment_status.eq(status.code()))
+    .execute(conn)
+    .expect("Unable to update order payment status");
+}
@@ -1,10 +1,294 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    cart_lines (id) {
+        id -> Int4,
+        cart_id -> Varchar,
+        product_id -> Int4,
+        qty -> Int4,
+        reserved_until -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    carts (id) {
+        id -> Varchar,
+        currency -> Varchar,
+        shipping_method -> Varchar,
+        coupon_code -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+        checked_out_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    coupon_redemptions (id) {
+        id -> Int4,
+        coupon_id -> Int4,
+        customer_email -> Varchar,
+        order_id -> Int4,
+        redeemed_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    coupons (id) {
+        id -> Int4,
+        code -> Varchar,
+        discount_rule_id -> Int4,
+        starts_at -> Nullable<Timestamptz>,
+        ends_at -> Nullable<Timestamptz>,
+        max_redemptions -> Nullable<Int4>,
+        max_redemptions_per_customer -> Nullable<Int4>,
+        min_subtotal -> Nullable<Numeric>,
+        redemptions -> Int4,
+        active -> Bool,
+    }
+}
+
+diesel::table! {
+    credit_notes (id) {
+        id -> Int4,
+        credit_note_number -> Varchar,
+        invoice_id -> Int4,
+        reason -> Varchar,
+        note -> Nullable<Varchar>,
+        amount -> Numeric,
+        issued_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    customer_profiles (customer_email) {
+        customer_email -> Varchar,
+        profile_id -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    discount_rules (id) {
+        id -> Int4,
+        name -> Varchar,
+        kind -> Varchar,
+        value -> Numeric,
+        product_id -> Nullable<Int4>,
+        category -> Nullable<Varchar>,
+        buy_qty -> Nullable<Int4>,
+        get_qty -> Nullable<Int4>,
+        min_subtotal -> Nullable<Numeric>,
+        stackable -> Bool,
+        exclusive_group -> Nullable<Varchar>,
+        automatic -> Bool,
+        active -> Bool,
+    }
+}
+
+diesel::table! {
+    discount_tiers (id) {
+        id -> Int4,
+        rule_id -> Int4,
+        min_subtotal -> Numeric,
+        value -> Numeric,
+    }
+}
+
+diesel::table! {
+    document_counters (name) {
+        name -> Varchar,
+        next_number -> Int4,
+    }
+}
+
+diesel::table! {
+    exchange_rates (currency) {
+        currency -> Varchar,
+        rate -> Numeric,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    fraud_screenings (id) {
+        id -> Int4,
+        order_id -> Int4,
+        customer_email -> Varchar,
+        ip_address -> Varchar,
+        card_fingerprint -> Nullable<Varchar>,
+        decision -> Varchar,
+        reasons -> Nullable<Text>,
+        payment_outcome -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    invoices (id) {
+        id -> Int4,
+        invoice_number -> Varchar,
+        order_id -> Int4,
+        customer_email -> Varchar,
+        currency -> Varchar,
+        total -> Numeric,
+        document -> Text,
+        issued_at -> Timestamptz,
+        billing_address -> Nullable<Text>,
+        shipping_address -> Nullable<Text>,
+        masked_card -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    orders (id) {
+        id -> Int4,
+        customer_email -> Varchar,
+        currency -> Varchar,
+        total -> Numeric,
+        transaction_id -> Nullable<Varchar>,
+        payment_status -> Varchar,
+        capture_amount -> Nullable<Numeric>,
+        captured_amount -> Numeric,
+        shipped_lines -> Nullable<Text>,
+        created_at -> Timestamptz,
+        shipped_at -> Nullable<Timestamptz>,
+        captured_at -> Nullable<Timestamptz>,
+        refunded_amount -> Numeric,
+        review_reasons -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    payment_methods (id) {
+        id -> Int4,
+        customer_email -> Varchar,
+        payment_profile_id -> Varchar,
+        card_type -> Nullable<Varchar>,
+        masked_card -> Varchar,
+        expiration_date -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    payments (id) {
+        id -> Int4,
+        order_id -> Int4,
+        action -> Varchar,
+        amount -> Nullable<Numeric>,
+        currency -> Nullable<Varchar>,
+        outcome -> Varchar,
+        message -> Nullable<Text>,
+        transaction_id -> Nullable<Varchar>,
+        response_code -> Nullable<Varchar>,
+        result_code -> Nullable<Varchar>,
+        auth_code -> Nullable<Varchar>,
+        avs_result_code -> Nullable<Varchar>,
+        cvv_result_code -> Nullable<Varchar>,
+        account_type -> Nullable<Varchar>,
+        account_number -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
     products (id) {
         id -> Int4,
         title -> Varchar,
         stock -> Int4,
         price -> Numeric,
+        purchasable -> Bool,
+        max_order_qty -> Int4,
+        category -> Nullable<Varchar>,
+        tax_category -> Varchar,
+        weight_grams -> Int4,
+        length_mm -> Int4,
+        width_mm -> Int4,
+        height_mm -> Int4,
     }
 }
+
+diesel::table! {
+    refunds (id) {
+        id -> Int4,
+        order_id -> Int4,
+        kind -> Varchar,
+        amount -> Numeric,
+        transaction_id -> Nullable<Varchar>,
+        credit_note_number -> Nullable<Varchar>,
+        lines -> Text,
+        restocked -> Bool,
+        note -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    shipping_rates (id) {
+        id -> Int4,
+        zone_id -> Int4,
+        method -> Varchar,
+        min_weight_grams -> Int4,
+        max_weight_grams -> Nullable<Int4>,
+        base_fee -> Numeric,
+        per_kg_fee -> Numeric,
+    }
+}
+
+diesel::table! {
+    shipping_zones (id) {
+        id -> Int4,
+        name -> Varchar,
+        country -> Varchar,
+        state -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    tax_exemptions (id) {
+        id -> Int4,
+        certificate_id -> Varchar,
+        customer_email -> Varchar,
+        country -> Varchar,
+        state -> Nullable<Varchar>,
+        expires_at -> Nullable<Timestamptz>,
+        active -> Bool,
+    }
+}
+
+diesel::table! {
+    tax_rates (id) {
+        id -> Int4,
+        country -> Varchar,
+        state -> Nullable<Varchar>,
+        zip_prefix -> Nullable<Varchar>,
+        tax_category -> Varchar,
+        rate -> Numeric,
+        shipping_taxable -> Bool,
+    }
+}
+
+diesel::joinable!(cart_lines -> carts (cart_id));
+diesel::joinable!(cart_lines -> products (product_id));
+diesel::joinable!(coupon_redemptions -> coupons (coupon_id));
+diesel::joinable!(coupons -> discount_rules (discount_rule_id));
+diesel::joinable!(credit_notes -> invoices (invoice_id));
+diesel::joinable!(discount_rules -> products (product_id));
+diesel::joinable!(payment_methods -> customer_profiles (customer_email));
+diesel::joinable!(discount_tiers -> discount_rules (rule_id));
+diesel::joinable!(refunds -> orders (order_id));
+diesel::joinable!(shipping_rates -> shipping_zones (zone_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    cart_lines,
+    carts,
+    credit_notes,
+    customer_profiles,
+    discount_rules,
+    discount_tiers,
+    invoices,
+    orders,
+    payment_methods,
+    products,
+    refunds,
+);
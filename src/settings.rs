@@ -0,0 +1,171 @@
+use dotenvy::dotenv;
+use lazy_static::lazy_static;
+use std::{env, str::FromStr};
+
+use crate::{
+    fraud::FraudAction,
+    gateway::GatewayKind,
+    money::{Currency, Money},
+};
+
+/// Store-wide options read once from the environment
+pub struct StoreSettings {
+    /// Currency catalog prices, discounts and shipping rates are kept in
+    pub base_currency: Currency,
+    /// Catalog prices already include tax, which the invoice backs out
+    pub prices_include_tax: bool,
+    /// Standard shipping is free once discounted goods reach this amount
+    pub free_shipping_threshold: Option<Money>,
+    /// How long items added to a cart with a reservation are set aside
+    pub cart_reservation_minutes: i64,
+    /// Name shown at the top of receipts
+    pub store_name: String,
+    /// Directory of receipt templates that replace the built in ones
+    pub receipt_template_dir: Option<String>,
+    /// Which payment gateway orders are charged through
+    pub payment_gateway: GatewayKind,
+    /// Only authorize at checkout and capture once the order ships
+    pub capture_on_shipment: bool,
+    /// How long to wait for a connection to the payment gateway
+    pub gateway_connect_timeout_secs: u64,
+    /// How long to wait for the payment gateway to reply
+    pub gateway_timeout_secs: u64,
+    /// How many more times to try a gateway request that never arrived
+    pub gateway_retries: u32,
+    /// How many gateway requests in a row may fail before it is left alone
+    pub gateway_breaker_threshold: u32,
+    /// How long the gateway is left alone before it is tried again
+    pub gateway_breaker_cooldown_secs: u64,
+    /// Accept orders while the gateway is down and charge them once it is
+    /// back, instead of turning them away
+    pub defer_payments: bool,
+    /// What happens to payments whose billing address does not match the
+    /// card's
+    pub fraud_avs_action: FraudAction,
+    /// What happens to payments whose security code does not match the card
+    pub fraud_cvv_action: FraudAction,
+    /// What happens to orders billed in one country and shipped to another
+    pub fraud_country_mismatch_action: FraudAction,
+    /// Orders worth more than this are held for review
+    pub fraud_review_order_value: Option<Money>,
+    /// Orders worth more than this are turned away
+    pub fraud_max_order_value: Option<Money>,
+    /// How far back orders and declined payments are counted
+    pub fraud_velocity_window_minutes: i64,
+    /// How many orders one email, IP address or card may place within the
+    /// window, 0 for no limit
+    pub fraud_max_orders: i64,
+    /// How many declined payments one email, IP address or card may have
+    /// within the window, 0 for no limit
+    pub fraud_max_declined_payments: i64,
+    /// What happens to orders over either velocity limit
+    pub fraud_velocity_action: FraudAction,
+}
+
+impl StoreSettings {
+    fn from_env() -> Self {
+        dotenv().ok();
+
+        let base_currency = match env::var("BASE_CURRENCY") {
+            Ok(code) if !code.trim().is_empty() => {
+                Currency::from_code(&code).expect("BASE_CURRENCY is not a supported currency")
+            }
+            _ => Currency::default(),
+        };
+
+        StoreSettings {
+            base_currency,
+            prices_include_tax: env_flag("PRICES_INCLUDE_TAX", false),
+            free_shipping_threshold: env_money("FREE_SHIPPING_THRESHOLD", base_currency),
+            cart_reservation_minutes: env_number("CART_RESERVATION_MINUTES", 15),
+            store_name: env::var("STORE_NAME")
+                .ok()
+                .filter(|value| !value.trim().is_empty())
+                .unwrap_or_else(|| String::from("Traffic Jam")),
+            receipt_template_dir: env::var("RECEIPT_TEMPLATE_DIR")
+                .ok()
+                .filter(|value| !value.trim().is_empty()),
+            payment_gateway: match env::var("PAYMENT_GATEWAY") {
+                Ok(code) if !code.trim().is_empty() => GatewayKind::from_code(&code)
+                    .expect("PAYMENT_GATEWAY must be authorize_net or mock"),
+                _ => GatewayKind::AuthorizeNet,
+            },
+            capture_on_shipment: env_flag("CAPTURE_ON_SHIPMENT", true),
+            gateway_connect_timeout_secs: env_number("GATEWAY_CONNECT_TIMEOUT_SECS", 5),
+            gateway_timeout_secs: env_number("GATEWAY_TIMEOUT_SECS", 30),
+            gateway_retries: env_number("GATEWAY_RETRIES", 2),
+            gateway_breaker_threshold: env_number("GATEWAY_BREAKER_THRESHOLD", 5),
+            gateway_breaker_cooldown_secs: env_number("GATEWAY_BREAKER_COOLDOWN_SECS", 30),
+            defer_payments: env_flag("DEFER_PAYMENTS", false),
+            fraud_avs_action: env_action("FRAUD_AVS_ACTION", FraudAction::Review),
+            fraud_cvv_action: env_action("FRAUD_CVV_ACTION", FraudAction::Reject),
+            fraud_country_mismatch_action: env_action(
+                "FRAUD_COUNTRY_MISMATCH_ACTION",
+                FraudAction::Review,
+            ),
+            fraud_review_order_value: env_money("FRAUD_REVIEW_ORDER_VALUE", base_currency),
+            fraud_max_order_value: env_money("FRAUD_MAX_ORDER_VALUE", base_currency),
+            fraud_velocity_window_minutes: env_number("FRAUD_VELOCITY_WINDOW_MINUTES", 60),
+            fraud_max_orders: env_number("FRAUD_MAX_ORDERS", 10),
+            fraud_max_declined_payments: env_number("FRAUD_MAX_DECLINED_PAYMENTS", 3),
+            fraud_velocity_action: env_action("FRAUD_VELOCITY_ACTION", FraudAction::Reject),
+        }
+    }
+}
+
+fn env_flag(name: &str, default: bool) -> bool {
+    match env::var(name) {
+        Ok(value) => matches!(value.trim().to_lowercase().as_str(), "1" | "true" | "yes"),
+        Err(_) => default,
+    }
+}
+
+fn env_number<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be a whole number", name)),
+        _ => default,
+    }
+}
+
+/// Shortest secret accepted for signing, in bytes
+const MIN_SECRET_LENGTH: usize = 32;
+
+/// Reads a signing key. An HMAC with an empty or short key can be forged,
+/// so a missing or weak secret stops the store from starting.
+pub fn required_secret(name: &str) -> Vec<u8> {
+    dotenv().ok();
+    let secret = env::var(name).unwrap_or_default();
+    if secret.trim().len() < MIN_SECRET_LENGTH {
+        panic!(
+            "{} must be set to a random value of at least {} bytes",
+            name, MIN_SECRET_LENGTH
+        );
+    }
+
+    secret.into_bytes()
+}
+
+fn env_money(name: &str, currency: Currency) -> Option<Money> {
+    env::var(name)
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+        .map(|value| {
+            Money::parse(&value, currency)
+                .unwrap_or_else(|| panic!("{} must be a decimal amount", name))
+        })
+}
+
+fn env_action(name: &str, default: FraudAction) -> FraudAction {
+    match env::var(name) {
+        Ok(code) if !code.trim().is_empty() => FraudAction::from_code(&code)
+            .unwrap_or_else(|| panic!("{} must be allow, review or reject", name)),
+        _ => default,
+    }
+}
+
+lazy_static! {
+    pub static ref SETTINGS: StoreSettings = StoreSettings::from_env();
+}
@@ -0,0 +1,224 @@
+use bigdecimal::BigDecimal;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{
+    authorize_net::Address,
+    db::POOL,
+    inventory::Item,
+    models::*,
+    money::{Currency, Money, Rounding},
+    settings::SETTINGS,
+};
+
+/// Divisor turning a parcel's volume in cubic millimetres into grams of
+/// volumetric weight, the usual 5000 cm3/kg carrier convention
+const VOLUMETRIC_DIVISOR: i64 = 5000;
+/// Country of a zone covering every address no other zone does
+const ANY_COUNTRY: &str = "*";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShippingMethod {
+    #[default]
+    Standard,
+    Express,
+    Pickup,
+}
+
+impl ShippingMethod {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ShippingMethod::Standard => "standard",
+            ShippingMethod::Express => "express",
+            ShippingMethod::Pickup => "pickup",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        match code.trim().to_lowercase().as_str() {
+            "standard" => Some(ShippingMethod::Standard),
+            "express" => Some(ShippingMethod::Express),
+            "pickup" => Some(ShippingMethod::Pickup),
+            _ => None,
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            ShippingMethod::Standard => "Standard shipping",
+            ShippingMethod::Express => "Express shipping",
+            ShippingMethod::Pickup => "In-store pickup",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ShippingQuote {
+    pub method: ShippingMethod,
+    pub zone: Option<String>,
+    pub billable_weight_grams: i64,
+    pub fee: Money,
+    pub free_shipping_applied: bool,
+}
+
+pub enum ShippingError {
+    NoZone,
+    MethodUnavailable(ShippingMethod),
+}
+
+impl ShippingError {
+    pub fn describe(&self) -> String {
+        match self {
+            ShippingError::NoZone => "We do not ship to this address".to_string(),
+            ShippingError::MethodUnavailable(method) => format!(
+                "{} is not available for this address and order weight",
+                method.describe()
+            ),
+        }
+    }
+}
+
+impl ShippingQuote {
+    /// Placeholder used before the destination is known
+    pub fn unquoted(method: ShippingMethod, currency: Currency) -> Self {
+        ShippingQuote {
+            method,
+            zone: None,
+            billable_weight_grams: 0,
+            fee: Money::zero(currency),
+            free_shipping_applied: false,
+        }
+    }
+}
+
+/// Prices shipping `items` to `address` with the chosen method.
+///
+/// `goods_total` is the value of the goods after discounts, which decides
+/// whether standard shipping is free.
+pub fn quote_shipping(
+    address: &Address,
+    items: &[Item],
+    method: ShippingMethod,
+    goods_total: &Money,
+) -> Result<ShippingQuote, ShippingError> {
+    let zone = find_zone(address).ok_or(ShippingError::NoZone)?;
+    let billable_weight_grams = billable_weight(items);
+    let rate = find_rate(&zone, method, billable_weight_grams)
+        .ok_or(ShippingError::MethodUnavailable(method))?;
+
+    let currency = goods_total.currency;
+    let free_shipping_applied = method == ShippingMethod::Standard
+        && match &SETTINGS.free_shipping_threshold {
+            Some(threshold) => goods_total.amount >= threshold.amount,
+            None => false,
+        };
+
+    let fee = if free_shipping_applied {
+        Money::zero(currency)
+    } else {
+        let kilograms = BigDecimal::from(billable_weight_grams) / BigDecimal::from(1000);
+        Money::new(rate.base_fee, currency)
+            + Money::rounded(&(rate.per_kg_fee * kilograms), currency, Rounding::HalfUp)
+    };
+
+    Ok(ShippingQuote {
+        method,
+        zone: Some(zone.name),
+        billable_weight_grams,
+        fee,
+        free_shipping_applied,
+    })
+}
+
+/// Every method that can currently ship `items` to `address`
+pub fn available_shipping(
+    address: &Address,
+    items: &[Item],
+    goods_total: &Money,
+) -> Vec<ShippingQuote> {
+    [
+        ShippingMethod::Standard,
+        ShippingMethod::Express,
+        ShippingMethod::Pickup,
+    ]
+    .into_iter()
+    .filter_map(|method| quote_shipping(address, items, method, goods_total).ok())
+    .collect()
+}
+
+fn find_zone(address: &Address) -> Option<ShippingZone> {
+    use crate::schema::shipping_zones::dsl::*;
+
+    let conn = &mut POOL.get().unwrap();
+    let address_state = address.state.trim().to_uppercase();
+
+    shipping_zones
+        .filter(
+            country
+                .eq(address.country.trim().to_uppercase())
+                .or(country.eq(ANY_COUNTRY)),
+        )
+        .load::<ShippingZone>(conn)
+        .expect("Unable to load shipping zones")
+        .into_iter()
+        .filter(|zone| match &zone.state {
+            Some(zone_state) => zone_state.to_uppercase() == address_state,
+            None => true,
+        })
+        // A state specific zone is preferred over a country wide one, and
+        // both over the catch-all zone
+        .max_by_key(|zone| (zone.state.is_some(), zone.country != ANY_COUNTRY))
+}
+
+fn find_rate(
+    zone: &ShippingZone,
+    shipping_method: ShippingMethod,
+    weight: i64,
+) -> Option<ShippingRate> {
+    use crate::schema::shipping_rates::dsl::*;
+
+    let conn = &mut POOL.get().unwrap();
+
+    shipping_rates
+        .filter(zone_id.eq(zone.id))
+        .filter(method.eq(shipping_method.code()))
+        .load::<ShippingRate>(conn)
+        .expect("Unable to load shipping rates")
+        .into_iter()
+        .filter(|rate| {
+            weight >= rate.min_weight_grams as i64
+                && match rate.max_weight_grams {
+                    Some(max_weight) => weight <= max_weight as i64,
+                    None => true,
+                }
+        })
+        .max_by_key(|rate| rate.min_weight_grams)
+}
+
+/// Each unit is billed at the greater of its actual and volumetric weight
+fn billable_weight(items: &[Item]) -> i64 {
+    use crate::schema::products::dsl::*;
+
+    let conn = &mut POOL.get().unwrap();
+    let item_ids: Vec<i32> = items.iter().map(|item| item.id).collect();
+    let parcels: HashMap<i32, (i32, i32, i32, i32)> = products
+        .filter(id.eq_any(item_ids))
+        .select((id, (weight_grams, length_mm, width_mm, height_mm)))
+        .load::<(i32, (i32, i32, i32, i32))>(conn)
+        .expect("Unable to load product weights")
+        .into_iter()
+        .collect();
+
+    items
+        .iter()
+        .map(|item| {
+            let (weight, length, width, height) =
+                parcels.get(&item.id).copied().unwrap_or_default();
+            let volumetric = length as i64 * width as i64 * height as i64 / VOLUMETRIC_DIVISOR;
+
+            (weight as i64).max(volumetric) * item.qty as i64
+        })
+        .sum()
+}
@@ -0,0 +1,231 @@
+use bigdecimal::{BigDecimal, One, Zero};
+use chrono::Utc;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{
+    authorize_net::Address,
+    db::POOL,
+    inventory::Item,
+    models::*,
+    money::{Money, Rounding},
+    settings::SETTINGS,
+};
+
+pub const STANDARD_TAX_CATEGORY: &str = "standard";
+/// Country of a rate that applies wherever no other rate does
+const ANY_COUNTRY: &str = "*";
+
+/// Tax on a single order line. `amount` is what the customer pays, while
+/// `exempted` is tax that would have applied without an exemption.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct LineTax {
+    pub product_id: i32,
+    pub tax_category: String,
+    pub taxable_amount: Money,
+    pub rate: BigDecimal,
+    pub amount: Money,
+    pub exempted: Money,
+}
+
+/// The tax rates that apply to one shipping address, along with the tax
+/// category of every product on the order
+#[derive(Default)]
+pub struct TaxRates {
+    rates: HashMap<String, BigDecimal>,
+    shipping_taxable: bool,
+    product_categories: HashMap<i32, String>,
+    /// Amounts passed in already include tax, which is backed out of them
+    pub prices_include_tax: bool,
+    /// Exemption certificate covering this order, if the customer has one
+    pub exemption_id: Option<String>,
+}
+
+impl TaxRates {
+    pub fn lookup(address: &Address, items: &[Item]) -> Self {
+        use crate::schema::tax_rates;
+
+        let conn = &mut POOL.get().unwrap();
+        let candidates = tax_rates::table
+            .filter(
+                tax_rates::country
+                    .eq(address.country.trim().to_uppercase())
+                    .or(tax_rates::country.eq(ANY_COUNTRY)),
+            )
+            .load::<TaxRate>(conn)
+            .expect("Unable to load tax rates");
+
+        let address_state = address.state.trim().to_uppercase();
+        let address_zip = address.zip.trim();
+
+        let mut best: HashMap<String, (usize, TaxRate)> = HashMap::new();
+        for candidate in candidates {
+            let state_matches = match &candidate.state {
+                Some(candidate_state) => candidate_state.to_uppercase() == address_state,
+                None => true,
+            };
+            let zip_matches = match &candidate.zip_prefix {
+                Some(prefix) => address_zip.starts_with(prefix.as_str()),
+                None => true,
+            };
+            if !state_matches || !zip_matches {
+                continue;
+            }
+
+            let specificity = match (&candidate.state, &candidate.zip_prefix) {
+                (_, Some(prefix)) => 3 + prefix.len(),
+                (Some(_), None) => 2,
+                (None, None) if candidate.country != ANY_COUNTRY => 1,
+                (None, None) => 0,
+            };
+            match best.get(&candidate.tax_category) {
+                Some((best_specificity, _)) if *best_specificity >= specificity => {}
+                _ => {
+                    best.insert(candidate.tax_category.clone(), (specificity, candidate));
+                }
+            }
+        }
+
+        let shipping_taxable = best
+            .get(STANDARD_TAX_CATEGORY)
+            .map(|(_, rate)| rate.shipping_taxable)
+            .unwrap_or(false);
+
+        TaxRates {
+            rates: best
+                .into_iter()
+                .map(|(category, (_, rate))| (category, rate.rate))
+                .collect(),
+            shipping_taxable,
+            product_categories: product_tax_categories(items),
+            prices_include_tax: SETTINGS.prices_include_tax,
+            exemption_id: None,
+        }
+    }
+
+    /// Used when no shipping address is known yet, so nothing is taxed
+    pub fn none() -> Self {
+        TaxRates {
+            prices_include_tax: SETTINGS.prices_include_tax,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_exemption(self, exemption_id: Option<String>) -> Self {
+        TaxRates {
+            exemption_id,
+            ..self
+        }
+    }
+
+    pub fn tax_category(&self, product_id: i32) -> String {
+        self.product_categories
+            .get(&product_id)
+            .cloned()
+            .unwrap_or_else(|| STANDARD_TAX_CATEGORY.to_string())
+    }
+
+    pub fn rate_for(&self, tax_category: &str) -> BigDecimal {
+        self.rates
+            .get(tax_category)
+            .cloned()
+            .unwrap_or_else(BigDecimal::zero)
+    }
+
+    pub fn line_tax(&self, product_id: i32, taxable_amount: Money) -> LineTax {
+        let tax_category = self.tax_category(product_id);
+        let rate = self.rate_for(&tax_category);
+        let (amount, exempted) = self.split_tax(&taxable_amount, &rate);
+
+        LineTax {
+            product_id,
+            amount,
+            exempted,
+            tax_category,
+            taxable_amount,
+            rate,
+        }
+    }
+
+    /// Returns the tax charged on shipping and any tax exempted from it
+    pub fn shipping_tax(&self, shipping: &Money) -> (Money, Money) {
+        if self.shipping_taxable {
+            self.split_tax(shipping, &self.rate_for(STANDARD_TAX_CATEGORY))
+        } else {
+            (
+                Money::zero(shipping.currency),
+                Money::zero(shipping.currency),
+            )
+        }
+    }
+
+    /// With tax-inclusive prices the tax is the portion of the amount above
+    /// its net value, i.e. amount * rate / (1 + rate)
+    fn split_tax(&self, amount: &Money, rate: &BigDecimal) -> (Money, Money) {
+        let effective_rate = if self.prices_include_tax {
+            rate / (BigDecimal::one() + rate)
+        } else {
+            rate.clone()
+        };
+        let tax = amount.apply_rate(&effective_rate, Rounding::HalfUp);
+
+        if self.exemption_id.is_some() {
+            (Money::zero(amount.currency), tax)
+        } else {
+            (tax, Money::zero(amount.currency))
+        }
+    }
+}
+
+fn product_tax_categories(items: &[Item]) -> HashMap<i32, String> {
+    use crate::schema::products::dsl::*;
+
+    let conn = &mut POOL.get().unwrap();
+    let item_ids: Vec<i32> = items.iter().map(|item| item.id).collect();
+
+    products
+        .filter(id.eq_any(item_ids))
+        .select((id, tax_category))
+        .load::<(i32, String)>(conn)
+        .expect("Unable to load product tax categories")
+        .into_iter()
+        .collect()
+}
+
+/// Finds an active, unexpired exemption certificate on file for the customer
+/// that covers the shipping address
+pub fn find_exemption(
+    exemption_certificate_id: &str,
+    email: &str,
+    address: &Address,
+) -> Option<TaxExemption> {
+    use crate::schema::tax_exemptions::dsl::*;
+
+    let conn = &mut POOL.get().unwrap();
+    let exemption: TaxExemption = tax_exemptions
+        .filter(certificate_id.eq(exemption_certificate_id.trim()))
+        .filter(active.eq(true))
+        .first(conn)
+        .optional()
+        .expect("Unable to look up tax exemption")?;
+
+    let covers_address = exemption.customer_email.to_lowercase() == email.trim().to_lowercase()
+        && exemption.country.to_uppercase() == address.country.trim().to_uppercase()
+        && match &exemption.state {
+            Some(exempt_state) => {
+                exempt_state.to_uppercase() == address.state.trim().to_uppercase()
+            }
+            None => true,
+        };
+    let unexpired = match exemption.expires_at {
+        Some(expiry) => Utc::now() < expiry,
+        None => true,
+    };
+
+    if covers_address && unexpired {
+        Some(exemption)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,230 @@
+use chrono::Utc;
+use diesel::prelude::*;
+use serde::Serialize;
+use std::{collections::HashMap, net::IpAddr};
+
+use crate::{
+    authorize_net::{Address, Payment},
+    cards::{validate_card, validate_opaque_data},
+    db::POOL,
+    ecommerce::Customer,
+    inventory::{CreateOrderRequest, Item},
+    models::*,
+    payment_methods::saved_payment,
+    tax::find_exemption,
+};
+
+#[derive(Clone, Serialize)]
+pub struct ValidationError {
+    pub field: String,
+    pub message: String,
+}
+
+impl ValidationError {
+    pub fn new(field: &str, message: &str) -> Self {
+        ValidationError {
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Checks an incoming order before any inventory is touched. Duplicate lines
+/// for the same product are merged, card details tidied and saved payment
+/// methods looked up in place, and every problem found is returned at once
+/// rather than stopping at the first one.
+pub fn validate_order_request(req: &mut CreateOrderRequest) -> Result<(), Vec<ValidationError>> {
+    let mut errors = validate_items(&mut req.items);
+    errors.append(&mut validate_customer(&req.customer));
+    errors.append(&mut match &mut req.customer.payment {
+        Payment::CreditCard(card) => {
+            validate_card(card, "customer.creditCard", Utc::now().date_naive())
+        }
+        Payment::OpaqueData(data) => validate_opaque_data(data, "customer.opaqueData"),
+        Payment::SavedMethod(id) => {
+            match saved_payment(*id, &req.customer.email, req.customer_token.as_deref()) {
+                Ok(profile) => {
+                    req.customer.payment = Payment::Profile(profile);
+                    vec![]
+                }
+                Err(method_error) => vec![ValidationError::new(
+                    "customer.paymentMethodId",
+                    &method_error.describe(),
+                )],
+            }
+        }
+        Payment::Profile(_) => vec![],
+    });
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+pub fn validate_items(items: &mut Vec<Item>) -> Vec<ValidationError> {
+    use crate::schema::products::dsl::*;
+
+    let mut errors: Vec<ValidationError> = vec![];
+
+    if items.is_empty() {
+        errors.push(ValidationError::new(
+            "items",
+            "Order must contain at least one item",
+        ));
+        return errors;
+    }
+
+    for (index, item) in items.iter().enumerate() {
+        if item.qty <= 0 {
+            errors.push(ValidationError::new(
+                &format!("items[{}].qty", index),
+                "Quantity must be greater than zero",
+            ));
+        }
+    }
+
+    let mut merged_items: Vec<Item> = vec![];
+    let mut overflowed: Vec<i32> = vec![];
+    for item in items.drain(..) {
+        match merged_items.iter_mut().find(|merged| merged.id == item.id) {
+            Some(merged) => match merged.qty.checked_add(item.qty) {
+                Some(qty) => merged.qty = qty,
+                None => overflowed.push(item.id),
+            },
+            None => merged_items.push(item),
+        }
+    }
+    *items = merged_items;
+
+    let conn = &mut POOL.get().unwrap();
+    let item_ids: Vec<i32> = items.iter().map(|item| item.id).collect();
+    let known_products: HashMap<i32, Product> = products
+        .filter(id.eq_any(item_ids))
+        .load::<Product>(conn)
+        .expect("Unable to retrieve products for order validation")
+        .into_iter()
+        .map(|product| (product.id, product))
+        .collect();
+
+    // Quantities are checked again once merged, since lines of the same
+    // product add up
+    for item in items.iter() {
+        let field = format!("items[id={}]", item.id);
+        match known_products.get(&item.id) {
+            Some(_) if overflowed.contains(&item.id) => {
+                errors.push(ValidationError::new(&field, "Quantity is too large"));
+            }
+            Some(_) if item.qty <= 0 => {
+                errors.push(ValidationError::new(
+                    &field,
+                    "Quantity must be greater than zero",
+                ));
+            }
+            Some(product) => {
+                if !product.purchasable {
+                    errors.push(ValidationError::new(
+                        &field,
+                        &format!("'{}' is not available for purchase", product.title),
+                    ));
+                } else if item.qty > product.max_order_qty {
+                    errors.push(ValidationError::new(
+                        &field,
+                        &format!(
+                            "At most {} of '{}' may be ordered at once",
+                            product.max_order_qty, product.title
+                        ),
+                    ));
+                }
+            }
+            None => {
+                errors.push(ValidationError::new(
+                    &field,
+                    &format!(
+                        "Item with id {} does not exist within the inventory",
+                        item.id
+                    ),
+                ));
+            }
+        }
+    }
+
+    errors
+}
+
+pub fn validate_customer(customer: &Customer) -> Vec<ValidationError> {
+    let mut errors: Vec<ValidationError> = vec![];
+
+    require_text(&mut errors, "customer.firstName", &customer.first_name);
+    require_text(&mut errors, "customer.lastName", &customer.last_name);
+
+    let email = customer.email.trim();
+    match email.split_once('@') {
+        Some((local, domain)) if !local.is_empty() && domain.contains('.') => {}
+        _ => errors.push(ValidationError::new(
+            "customer.email",
+            "A valid email address is required",
+        )),
+    }
+
+    let phone_digits = customer
+        .phone_number
+        .chars()
+        .filter(|c| c.is_ascii_digit())
+        .count();
+    if !(7..=15).contains(&phone_digits) {
+        errors.push(ValidationError::new(
+            "customer.phoneNumber",
+            "Phone number must contain between 7 and 15 digits",
+        ));
+    }
+
+    if customer.ip_address.parse::<IpAddr>().is_err() {
+        errors.push(ValidationError::new(
+            "customer.ipAddress",
+            "A valid IP address is required",
+        ));
+    }
+
+    validate_address(
+        &mut errors,
+        "customer.billingAddress",
+        &customer.billing_address,
+    );
+    validate_address(
+        &mut errors,
+        "customer.shippingAddress",
+        &customer.shipping_address,
+    );
+
+    if let Some(exemption_id) = &customer.tax_exemption_id {
+        if find_exemption(exemption_id, &customer.email, &customer.shipping_address).is_none() {
+            errors.push(ValidationError::new(
+                "customer.taxExemptionId",
+                "No valid tax exemption certificate on file covers this shipping address",
+            ));
+        }
+    }
+
+    errors
+}
+
+fn validate_address(errors: &mut Vec<ValidationError>, prefix: &str, address: &Address) {
+    require_text(
+        errors,
+        &format!("{}.firstName", prefix),
+        &address.first_name,
+    );
+    require_text(errors, &format!("{}.lastName", prefix), &address.last_name);
+    require_text(errors, &format!("{}.address", prefix), &address.address);
+    require_text(errors, &format!("{}.city", prefix), &address.city);
+    require_text(errors, &format!("{}.zip", prefix), &address.zip);
+    require_text(errors, &format!("{}.country", prefix), &address.country);
+}
+
+fn require_text(errors: &mut Vec<ValidationError>, field: &str, value: &str) {
+    if value.trim().is_empty() {
+        errors.push(ValidationError::new(field, "This field is required"));
+    }
+}